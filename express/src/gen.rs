@@ -1,5 +1,5 @@
 use std::fmt::Write;
-use std::collections::{HashSet, HashMap};
+use std::collections::{BTreeSet, HashSet, HashMap};
 use crate::parse::*;
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -24,6 +24,56 @@ enum Type<'a> {
     // Direct Rust type
     Primitive(&'a str),
 }
+
+/// Entities whose structs are larger than this (in bytes, as estimated by
+/// `TypeMap::layout`) are boxed in the generated `Entity` enum, which would
+/// otherwise be as large as its largest variant.  Common geometry and
+/// topology entities fit, so they don't pay for a second allocation.
+///
+/// Boxing (plus trimming spare capacity from parsed lists) is the only size
+/// optimization made here.  Numeric aggregates are not stored compactly:
+/// bounded aggregates are inline `ArrayVec`s of their element type, and
+/// other aggregates are plain `Vec`s.  Strings are not interned either; they
+/// borrow from the input (or own their text when it has escapes).  Run the
+/// `step_memory` example to measure the heap used by a parsed file.
+const MAX_INLINE_ENTITY_SIZE: usize = 56;
+
+// Estimated size and alignment of a generated Rust type, and whether it has
+// a niche (so that wrapping it in `Option` doesn't make it larger)
+#[derive(Copy, Clone, Debug)]
+struct Layout {
+    size: usize,
+    align: usize,
+    niche: bool,
+}
+impl Layout {
+    fn primitive(rtype: &str) -> Self {
+        let (size, niche) = match rtype {
+            "usize" | "i64" | "f64" => (8, false),
+            "bool" | "Logical" => (1, true),
+            "Cow<'a, str>" => (24, true),
+            _ => panic!("Unknown primitive type {}", rtype),
+        };
+        Layout { size, align: size.min(8), niche }
+    }
+    // `Id<T>`, used for entity references
+    const ID: Layout = Layout { size: 8, align: 8, niche: false };
+    const VEC: Layout = Layout { size: 24, align: 8, niche: true };
+
+    fn optional(self) -> Self {
+        if self.niche {
+            self
+        } else {
+            Layout { size: self.size + self.align, niche: true, ..self }
+        }
+    }
+    fn array_vec(self, n: usize) -> Self {
+        // ArrayVec stores its length as a u32 after the items
+        let align = self.align.max(4);
+        Layout { size: round_up(self.size * n + 4, align), align, niche: false }
+    }
+}
+
 struct TypeMap<'a>(HashMap<&'a str, Type<'a>>, &'a HashMap<&'a str, Ref<'a>>);
 impl <'a> TypeMap<'a> {
     fn to_rtype_build(&mut self, s: &'a str) -> String {
@@ -83,6 +133,155 @@ impl <'a> TypeMap<'a> {
         };
         self.0.insert(s, m);
     }
+    /// Returns how values of the given type are written in Part 21, as
+    /// described in the generated `attribute_kinds`
+    fn kind(&self, s: &str) -> String {
+        let t = self.0.get(s).unwrap_or_else(|| panic!("Could not get {:?}", s));
+        self.type_kind(t)
+    }
+    fn type_kind(&self, t: &Type<'a>) -> String {
+        match t {
+            Type::Entity { .. } => "ENTITY".to_owned(),
+            // Selects of only entities are parsed as plain references
+            Type::Select(v) => if v.iter().all(|s| self.is_entity(s)) {
+                "ENTITY".to_owned()
+            } else {
+                "SELECT".to_owned()
+            },
+            Type::Enum(_) => "ENUMERATION".to_owned(),
+            Type::Redeclared(r) => self.kind(r),
+            Type::RedeclaredPrimitive(p) | Type::Primitive(p) =>
+                primitive_kind(p).to_owned(),
+            Type::Aggregation { type_, .. } =>
+                format!("LIST OF {}", self.type_kind(type_)),
+        }
+    }
+    /// Returns the entity types which a reference to the given type may
+    /// point at, expanding selects and defined types (sorted and deduped).
+    /// Selects of only entities are parsed as untyped references, so they
+    /// (and anything containing them) return `None`.
+    fn targets(&self, s: &str) -> Option<Vec<&'a str>> {
+        let t = self.0.get(s).unwrap_or_else(|| panic!("Could not get {:?}", s));
+        let mut out = self.type_targets(s, t)?;
+        out.sort_unstable();
+        out.dedup();
+        Some(out)
+    }
+    /// Estimates the layout of an attribute of the given named type
+    fn layout(&self, s: &str) -> Layout {
+        let t = self.0.get(s).unwrap_or_else(|| panic!("Could not get {:?}", s));
+        match t {
+            // Stored as a reference
+            Type::Entity { .. } => Layout::ID,
+            Type::Select(v) => if v.iter().all(|s| self.is_entity(s)) {
+                Layout::ID
+            } else {
+                // Conservatively assume that the tag takes extra space
+                let members: Vec<Layout> = v.iter().map(|s| self.layout(s)).collect();
+                let align = members.iter().map(|m| m.align).max().unwrap_or(1);
+                let size = members.iter().map(|m| m.size).max().unwrap_or(0);
+                Layout { size: round_up(size, align) + align, align, niche: true }
+            },
+            Type::Enum(_) => Layout { size: 1, align: 1, niche: true },
+            Type::Redeclared(r) => self.layout(r),
+            Type::RedeclaredPrimitive(p) | Type::Primitive(p) => Layout::primitive(p),
+            Type::Aggregation { .. } => Layout::VEC,
+        }
+    }
+    fn type_targets(&self, name: &str, t: &Type<'a>) -> Option<Vec<&'a str>> {
+        match t {
+            Type::Entity { .. } => {
+                let (k, _) = self.0.get_key_value(name).unwrap();
+                Some(vec![*k])
+            },
+            Type::Select(v) => if v.iter().all(|s| self.is_entity(s)) {
+                None
+            } else {
+                v.iter()
+                    .map(|s| self.targets(s))
+                    .collect::<Option<Vec<_>>>()
+                    .map(|v| v.concat())
+            },
+            Type::Redeclared(r) => self.targets(r),
+            Type::Aggregation { type_, .. } => self.type_targets(name, type_),
+            Type::Enum(_) | Type::RedeclaredPrimitive(_) | Type::Primitive(_) => Some(vec![]),
+        }
+    }
+    /// Returns the entity `s` and all of its ancestors
+    fn ancestors(&self, s: &'a str) -> HashSet<&'a str> {
+        let mut out = HashSet::new();
+        let mut todo = vec![s];
+        while let Some(s) = todo.pop() {
+            if out.insert(s) {
+                if let Some(Type::Entity { supertypes, .. }) = self.0.get(s) {
+                    todo.extend(supertypes.iter().cloned());
+                }
+            }
+        }
+        out
+    }
+
+    /// Marks attributes which may be written as `*` because another part of
+    /// a complex instance derives them, e.g. `NAMED_UNIT.dimensions` in the
+    /// `LENGTH_UNIT()` part of `(LENGTH_UNIT() NAMED_UNIT(*) SI_UNIT(...))`.
+    ///
+    /// An attribute is derivable if some unrelated entity (neither an
+    /// ancestor nor a descendant) derives it, unless the two entities are
+    /// kept apart by a `ONEOF` supertype constraint.
+    fn mark_derivable(&mut self) {
+        // Each ONEOF, as a list of the entities named in each of its operands
+        let mut one_ofs = Vec::new();
+        for r in self.1.values() {
+            if let Ref::Entity(e) = r {
+                if let Some(x) = e.0.1.0.as_ref().and_then(|c| c.expression()) {
+                    x.one_ofs(&mut one_ofs);
+                }
+            }
+        }
+
+        let mut derivers: HashMap<(&str, &str), Vec<&str>> = HashMap::new();
+        let mut ancestors = HashMap::new();
+        for (k, t) in self.0.iter() {
+            if let Type::Entity { attrs, .. } = t {
+                for a in attrs.iter().filter(|a| a.derived) {
+                    derivers.entry((a.from.unwrap(), a.name))
+                        .or_default()
+                        .push(*k);
+                }
+                ancestors.insert(*k, self.ancestors(k));
+            }
+        }
+        // Checks whether a and b descend from different operands of a ONEOF
+        let exclusive = |a: &str, b: &str| one_ofs.iter().any(|o: &Vec<HashSet<&str>>| {
+            let i = o.iter().position(|g| !g.is_disjoint(&ancestors[a]));
+            let j = o.iter().position(|g| !g.is_disjoint(&ancestors[b]));
+            matches!((i, j), (Some(i), Some(j)) if i != j)
+        });
+
+        let mut marked = Vec::new();
+        for (k, t) in self.0.iter() {
+            if let Type::Entity { attrs, .. } = t {
+                for (i, a) in attrs.iter().enumerate() {
+                    if a.derived || a.optional {
+                        continue;
+                    }
+                    let from = a.from.unwrap_or(k);
+                    if derivers.get(&(from, a.name)).is_some_and(|ds| ds.iter()
+                        .any(|d| !ancestors[k].contains(d) &&
+                                 !ancestors[d].contains(k) &&
+                                 !exclusive(k, d)))
+                    {
+                        marked.push((*k, i));
+                    }
+                }
+            }
+        }
+        for (k, i) in marked {
+            if let Some(Type::Entity { attrs, .. }) = self.0.get_mut(k) {
+                attrs[i].derivable = true;
+            }
+        }
+    }
     fn attributes(&mut self, s: &'a str) -> Vec<AttributeData<'a>> {
         if !self.0.contains_key(s) {
             self.build(s);
@@ -97,22 +296,42 @@ impl <'a> TypeMap<'a> {
 }
 
 impl<'a> Type<'a> {
-    fn write_enum_variant<W>(&self, name: &str, buf: &mut W) -> std::fmt::Result
+    /// Checks whether this is an entity which is stored boxed in `Entity`
+    fn is_boxed(&self, type_map: &TypeMap) -> bool {
+        if let Type::Entity{attrs, ..} = self {
+            let fields: Vec<Layout> = attrs.iter()
+                .filter(|a| !a.derived)
+                .map(|a| {
+                    let t = a.param.to_layout(type_map);
+                    if a.optional || a.derivable { t.optional() } else { t }
+                })
+                .collect();
+            let align = fields.iter().map(|f| f.align).max().unwrap_or(1);
+            let size: usize = fields.iter().map(|f| f.size).sum();
+            round_up(size, align) > MAX_INLINE_ENTITY_SIZE
+        } else {
+            false
+        }
+    }
+    fn write_enum_variant<W>(&self, name: &str, buf: &mut W, type_map: &TypeMap) -> std::fmt::Result
         where W: std::fmt::Write
     {
         match self {
+            Type::Entity{..} if self.is_boxed(type_map) =>
+                writeln!(buf, "    {0}(Box<{0}_<'a>>),", to_camel(name)),
             Type::Entity{..} => writeln!(buf, "    {0}({0}_<'a>),",
                                          to_camel(name)),
             _ => Ok(()),
         }
     }
-    fn write_enum_match<W>(&self, name: &str, buf: &mut W) -> std::fmt::Result
+    fn write_enum_match<W>(&self, name: &str, buf: &mut W, type_map: &TypeMap) -> std::fmt::Result
         where W: std::fmt::Write
     {
         match self {
             Type::Entity{..} => writeln!(buf,
-                r#"            "{0}" => {1}_::parse_chunks(strs).map(|(s, v)| (s, Entity::{1}(v))),"#,
-                capitalize(name), to_camel(name)),
+                r#"            "{0}" => {1}_::parse_chunks(strs).map(|(s, v)| (s, Entity::{1}({2}))),"#,
+                capitalize(name), to_camel(name),
+                if self.is_boxed(type_map) { "Box::new(v)" } else { "v" }),
             _ => Ok(()),
         }
     }
@@ -138,6 +357,115 @@ impl<'a> Type<'a> {
         }
         Ok(())
     }
+    fn write_attribute_owners<W>(&self, name: &str, buf: &mut W) -> std::fmt::Result
+        where W: std::fmt::Write
+    {
+        if let Type::Entity{attrs, supertypes} = self {
+            // Entities without supertypes own all of their attributes, so
+            // they're handled by the fallback case
+            if !supertypes.is_empty() {
+                write!(buf, r#"        "{}" => &["#, capitalize(name))?;
+                for (i, a) in attrs.iter().enumerate() {
+                    if i > 0 {
+                        write!(buf, ", ")?;
+                    }
+                    write!(buf, r#""{}""#, capitalize(a.from.unwrap_or(name)))?;
+                }
+                writeln!(buf, "],")?;
+            }
+        }
+        Ok(())
+    }
+    fn write_attribute_names<W>(&self, name: &str, buf: &mut W) -> std::fmt::Result
+        where W: std::fmt::Write
+    {
+        if let Type::Entity{attrs, ..} = self {
+            if !attrs.is_empty() {
+                write!(buf, r#"        "{}" => &["#, capitalize(name))?;
+                for (i, a) in attrs.iter().enumerate() {
+                    if i > 0 {
+                        write!(buf, ", ")?;
+                    }
+                    if a.dupe {
+                        write!(buf, r#""{}__{}""#, a.from.unwrap(), a.name)?;
+                    } else {
+                        write!(buf, r#""{}""#, a.name)?;
+                    }
+                }
+                writeln!(buf, "],")?;
+            }
+        }
+        Ok(())
+    }
+    fn write_attribute_kinds<W>(&self, name: &str, buf: &mut W, type_map: &TypeMap) -> std::fmt::Result
+        where W: std::fmt::Write
+    {
+        if let Type::Entity{attrs, ..} = self {
+            if !attrs.is_empty() {
+                write!(buf, r#"        "{}" => &["#, capitalize(name))?;
+                for (i, a) in attrs.iter().enumerate() {
+                    if i > 0 {
+                        write!(buf, ", ")?;
+                    }
+                    if a.derived {
+                        write!(buf, r#""DERIVED""#)?;
+                    } else {
+                        write!(buf, r#""{}""#, a.param.to_kind(type_map))?;
+                    }
+                }
+                writeln!(buf, "],")?;
+            }
+        }
+        Ok(())
+    }
+    fn write_attribute_optional<W>(&self, name: &str, buf: &mut W) -> std::fmt::Result
+        where W: std::fmt::Write
+    {
+        if let Type::Entity{attrs, ..} = self {
+            if attrs.iter().any(|a| a.optional && !a.derived) {
+                write!(buf, r#"        "{}" => &["#, capitalize(name))?;
+                for (i, a) in attrs.iter().enumerate() {
+                    if i > 0 {
+                        write!(buf, ", ")?;
+                    }
+                    write!(buf, "{}", a.optional && !a.derived)?;
+                }
+                writeln!(buf, "],")?;
+            }
+        }
+        Ok(())
+    }
+    fn write_attribute_targets<W>(&self, name: &str, buf: &mut W, type_map: &TypeMap) -> std::fmt::Result
+        where W: std::fmt::Write
+    {
+        if let Type::Entity{attrs, ..} = self {
+            let targets: Vec<Vec<&str>> = attrs.iter()
+                .map(|a| if a.derived {
+                    vec![]
+                } else {
+                    a.param.to_targets(type_map).unwrap_or_default()
+                })
+                .collect();
+            if targets.iter().any(|t| !t.is_empty()) {
+                write!(buf, r#"        "{}" => &["#, capitalize(name))?;
+                for (i, t) in targets.iter().enumerate() {
+                    if i > 0 {
+                        write!(buf, ", ")?;
+                    }
+                    write!(buf, "&[")?;
+                    for (j, t) in t.iter().enumerate() {
+                        if j > 0 {
+                            write!(buf, ", ")?;
+                        }
+                        write!(buf, r#""{}""#, capitalize(t))?;
+                    }
+                    write!(buf, "]")?;
+                }
+                writeln!(buf, "],")?;
+            }
+        }
+        Ok(())
+    }
     fn write_type<W>(&self, name: &str, buf: &mut W, type_map: &TypeMap) -> std::fmt::Result
         where W: std::fmt::Write
     {
@@ -157,6 +485,11 @@ impl<'a> HasId for {0}<'a> {{
         self.0.append_ids(v);
     }}
 }}
+impl<'a> ToParam for {0}<'a> {{
+    fn to_param(&self) -> Parameter<'_> {{
+        self.0.to_param()
+    }}
+}}
 "#,
                 camel_name, type_map.to_rtype(c), to_camel(c))?;
             },
@@ -171,6 +504,11 @@ impl<'a> Parse<'a> for {0}<'a> {{
 impl<'a> HasId for {0}<'a> {{
     fn append_ids(&self, _v: &mut Vec<usize>) {{ /* Nothing to do here */ }}
 }}
+impl<'a> ToParam for {0}<'a> {{
+    fn to_param(&self) -> Parameter<'_> {{
+        self.0.to_param()
+    }}
+}}
 "#,
                     camel_name, c, strip_lifetime(c))?;
             },
@@ -202,7 +540,17 @@ impl<'a> Parse<'a> for {0}<'a> {{
 impl<'a> HasId for {0}<'a> {{
     fn append_ids(&self, _v: &mut Vec<usize>) {{ /* nothing to do here */ }}
 }}
-"#, camel_name)?;
+impl<'a> ToParam for {0}<'a> {{
+    fn to_param(&self) -> Parameter<'_> {{
+        match self {{"#, camel_name)?;
+                for enum_tag in c {
+                    writeln!(buf, r#"            {}::{} => Parameter::Enum("{}"),"#,
+                        camel_name, to_camel(enum_tag), capitalize(enum_tag))?;
+                }
+                writeln!(buf, "            _ => Parameter::Null,
+        }}
+    }}
+}}")?;
             },
 
             Type::Select(c) => {
@@ -240,7 +588,7 @@ impl<'a> Parse<'a> for {}<'a> {{
                 // Same logic as above
                 let to_parse_str = |v| if !type_map.is_entity(v) {
                     format!(
-                        r#"        map(delimited(tag("{}("), <{}>::parse, char(')')), {}::{})"#,
+                        r#"        map(delimited(keyword("{}"), <{}>::parse, sym(')')), {}::{})"#,
                         capitalize(v), type_map.to_rtype(v),
                         camel_name, to_camel(v))
                 } else {
@@ -282,6 +630,22 @@ impl<'a> HasId for {0}<'a> {{
                 writeln!(buf, "            _ => (),
         }}
     }}
+}}
+impl<'a> ToParam for {0}<'a> {{
+    fn to_param(&self) -> Parameter<'_> {{
+        match self {{", camel_name)?;
+                for v in c {
+                    if type_map.is_entity(v) {
+                        writeln!(buf, "            {}::{}(c) => c.to_param(),",
+                            camel_name, to_camel(v))?;
+                    } else {
+                        writeln!(buf, r#"            {}::{}(c) => Parameter::Typed("{}", Box::new(c.to_param())),"#,
+                            camel_name, to_camel(v), capitalize(v))?;
+                    }
+                }
+                writeln!(buf, "            _ => Parameter::Null,
+        }}
+    }}
 }}")?;
             },
 
@@ -301,6 +665,11 @@ impl<'a> HasId for {0}<'a> {{
         }}
     }}
 }}
+impl<'a> ToParam for {0}<'a> {{
+    fn to_param(&self) -> Parameter<'_> {{
+        self.0.to_param()
+    }}
+}}
 "#,
                     camel_name, type_map.to_inner_rtype(self),
                     type_map.to_inner_rtype(&*type_))?;
@@ -322,7 +691,7 @@ pub struct {}_<'a> {{ // entity", camel_name)?;
                     } else {
                         write!(buf, "    pub {}: ", a.name)?;
                     }
-                    if a.optional {
+                    if a.optional || a.derivable {
                         writeln!(buf, "Option<{}>,", a.type_)?;
                     } else {
                         writeln!(buf, "{},", a.type_)?;
@@ -332,23 +701,25 @@ pub struct {}_<'a> {{ // entity", camel_name)?;
 }}
 pub type {0}<'a> = Id<{0}_<'a>>;
 impl<'a> FromEntity<'a> for {0}_<'a> {{
+    const KEYWORD: &'static str = "{1}";
     fn try_from_entity(e: &'a Entity<'a>) -> Option<&'a Self> {{
         match e {{
             Entity::{0}(v) => Some(v),
+            Entity::ComplexEntity(v) => v.iter().find_map(Self::try_from_entity),
             _ => None,
         }}
     }}
 }}
 impl<'a> ParseFromChunks<'a> for {0}_<'a> {{
     fn parse_chunks(strs: &[&'a str]) -> IResult<'a, Self> {{"#,
-                    camel_name)?;
+                    camel_name, capitalize(name))?;
 
                 // If we'll be reading attributes, then we need an index
                 if !attrs.is_empty() {
                     writeln!(buf, "        let mut i = 0;")?;
                 }
                 // Parse the tag
-                writeln!(buf, r#"        let (s, _) = tag("{}(")(strs[0])?;"#,
+                writeln!(buf, r#"        let (s, _) = keyword("{}")(strs[0])?;"#,
                          capitalize(&name))?;
                 // Then, write a series of parsers which build the whole struct
                 for (i,a) in attrs.iter().enumerate() {
@@ -366,6 +737,9 @@ impl<'a> ParseFromChunks<'a> for {0}_<'a> {{
                         if a.optional {
                             write!(buf, " = param_from_chunks::<Option<{}>>",
                                    a.type_)?;
+                        } else if a.derivable {
+                            write!(buf, " = param_from_chunks::<Derivable<{}>>",
+                                   a.type_)?;
                         } else {
                             write!(buf, " = param_from_chunks::<{}>", a.type_)?;
                         }
@@ -375,12 +749,16 @@ impl<'a> ParseFromChunks<'a> for {0}_<'a> {{
                 }
                 writeln!(buf, "        Ok((s, Self {{")?;
                 for a in attrs.iter().filter(|a| !a.derived) {
-                    if a.dupe {
-                        // TODO make this a function on `a`
-                        writeln!(buf, "            {}__{},",
-                                 a.from.unwrap(), a.name)?;
+                    // TODO make this a function on `a`
+                    let field = if a.dupe {
+                        format!("{}__{}", a.from.unwrap(), a.name)
+                    } else {
+                        a.name.to_owned()
+                    };
+                    if a.derivable {
+                        writeln!(buf, "            {0}: {0}.0,", field)?;
                     } else {
-                        writeln!(buf, "            {},", a.name)?;
+                        writeln!(buf, "            {},", field)?;
                     }
                 }
                 writeln!(buf, "            _marker: std::marker::PhantomData}}))
@@ -397,6 +775,30 @@ impl<'a> HasId for {}_<'a> {{
                     }
                 }
                 writeln!(buf, "    }}
+}}
+impl<'a> ToRecord for {}_<'a> {{
+    fn to_record(&self) -> Record<'_> {{
+        Record {{
+            keyword: \"{}\",
+            params: vec![", camel_name, capitalize(name))?;
+                for a in attrs {
+                    if a.derived {
+                        writeln!(buf, "                Parameter::Derived,")?;
+                    } else if a.derivable {
+                        // Derivable attributes are never dupes in AP214
+                        assert!(!a.dupe);
+                        writeln!(buf, "                self.{}.as_ref()
+                    .map_or(Parameter::Derived, |v| v.to_param()),", a.name)?;
+                    } else if a.dupe {
+                        writeln!(buf, "                self.{}__{}.to_param(),",
+                                 a.from.unwrap(), a.name)?;
+                    } else {
+                        writeln!(buf, "                self.{}.to_param(),", a.name)?;
+                    }
+                }
+                writeln!(buf, "            ],
+        }}
+    }}
 }}")?;
             },
             Type::Primitive(_) => (),
@@ -413,6 +815,8 @@ struct AttributeData<'a> {
     optional: bool,
     dupe: bool, // inherited from different parents with the same name
     derived: bool, // marked whether this is a derived attribute
+    derivable: bool, // may be derived by another part of a complex instance
+    param: &'a ParameterType<'a>, // declared type, for attribute kinds
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -445,27 +849,31 @@ pub fn gen(s: &mut Syntax) -> Result<String, std::fmt::Error> {
     type_map.0.insert("bool", Type::Primitive("bool"));
     type_map.0.insert("i64", Type::Primitive("i64"));
     type_map.0.insert("f64", Type::Primitive("f64"));
-    type_map.0.insert("&'a str", Type::Primitive("&'a str"));
+    type_map.0.insert("Cow<'a, str>", Type::Primitive("Cow<'a, str>"));
 
     for k in ref_map.keys() {
         type_map.build(k);
     }
+    type_map.mark_derivable();
 
     // Step four: do codegen on the completed type map (sorted for determinism)
     let mut keys: Vec<&str> = type_map.0.keys().cloned().collect();
     keys.sort_unstable();
     let mut buf = String::new();
     writeln!(&mut buf, "// Autogenerated file, do not hand-edit!
+use std::borrow::Cow;
 use crate::{{
     id::{{Id, HasId}},
-    parse::{{IResult, Logical, Derived, Parse, ParseFromChunks, nom_alt_err,
-            parse_enum_tag, param_from_chunks, parse_complex_mapping}},
+    param::{{Parameter, Record, ToParam, ToRecord}},
+    parse::{{IResult, Logical, Derived, Derivable, Parse, ParseFromChunks, nom_alt_err,
+            parse_enum_tag, param_from_chunks, parse_complex_mapping,
+            keyword, sym, ws}},
     step_file::FromEntity,
 }};
 use nom::{{
     branch::{{alt}},
     bytes::complete::tag,
-    character::complete::{{alpha0, alphanumeric1, char}},
+    character::complete::{{alpha0, alphanumeric1}},
     combinator::{{map, recognize}},
     multi::{{many0}},
     sequence::{{delimited, pair}},
@@ -478,9 +886,15 @@ use arrayvec::ArrayVec;")?;
     writeln!(&mut buf, "#[derive(Debug)]
 pub enum Entity<'a> {{")?;
     for k in &keys {
-        type_map.0[k].write_enum_variant(k, &mut buf)?;
+        type_map.0[k].write_enum_variant(k, &mut buf, &type_map)?;
     }
     writeln!(&mut buf, r#"    ComplexEntity(Vec<Entity<'a>>),
+    /// An instance which is valid Part 21 but doesn't match the schema,
+    /// stored as one record per part (a single record for simple instances).
+    /// Within a `ComplexEntity`, this is also used for parts with no
+    /// attributes of their own whose inherited attributes can't be parsed,
+    /// as a single record with no parameters.
+    Untyped(Vec<Record<'a>>),
     _FailedToParse,
     _EmptySlot,
 }}
@@ -489,10 +903,10 @@ impl<'a> ParseFromChunks<'a> for Entity<'a> {{
         let (_, r) = recognize(pair(
             alt((alpha0, tag("_"))),
             many0(alt((alphanumeric1, tag("_")))),
-        ))(strs[0])?;
+        ))(ws(strs[0]))?;
         match r {{"#)?;
     for k in &keys {
-        type_map.0[k].write_enum_match(k, &mut buf)?;
+        type_map.0[k].write_enum_match(k, &mut buf, &type_map)?;
     }
     writeln!(&mut buf, r#"            "" => parse_complex_mapping(strs[0]),
             _ => nom_alt_err(r),
@@ -508,7 +922,116 @@ pub fn superclasses_of(s: &str) -> &[&str] {{
     writeln!(&mut buf, "        _ => &[],
     }}
 }}
+
+/// Returns the entity which declares each attribute of the given entity, in
+/// the same order as its parameters.  An empty list means that every
+/// attribute is declared by the entity itself.
+pub fn attribute_owners(s: &str) -> &[&str] {{
+    match s {{")?;
+    for k in &keys {
+        type_map.0[k].write_attribute_owners(k, &mut buf)?;
+    }
+    writeln!(&mut buf, "        _ => &[],
+    }}
+}}
+
+/// Returns the names of the given entity's attributes (including inherited
+/// and derived attributes), in the same order as its parameters.  These
+/// match the field names of its generated struct.
+pub fn attribute_names(s: &str) -> &'static [&'static str] {{
+    match s {{")?;
+    for k in &keys {
+        type_map.0[k].write_attribute_names(k, &mut buf)?;
+    }
+    writeln!(&mut buf, "        _ => &[],
+    }}
+}}
+
+/// Returns how each attribute of the given entity is written in Part 21, in
+/// the same order as its parameters.  Each kind is one of `REAL`, `INTEGER`,
+/// `STRING`, `BOOLEAN`, `LOGICAL`, `BINARY`, `ENUMERATION`, `ENTITY` (a
+/// reference), `SELECT` (a reference or a typed value) or `LIST OF` another
+/// kind, or `DERIVED` for attributes which a subtype derives.
+pub fn attribute_kinds(s: &str) -> &'static [&'static str] {{
+    match s {{")?;
+    for k in &keys {
+        type_map.0[k].write_attribute_kinds(k, &mut buf, &type_map)?;
+    }
+    writeln!(&mut buf, "        _ => &[],
+    }}
+}}
+
+/// Returns whether each attribute of the given entity is OPTIONAL (so that
+/// it may be written as `$`), in the same order as its parameters.  An
+/// empty list means that no attribute of the entity is optional.
+pub fn attribute_optional(s: &str) -> &'static [bool] {{
+    match s {{")?;
+    for k in &keys {
+        type_map.0[k].write_attribute_optional(k, &mut buf)?;
+    }
+    writeln!(&mut buf, "        _ => &[],
+    }}
+}}
+
+/// Returns the entity types which each attribute of the given entity may
+/// refer to, in the same order as its parameters.  References to subtypes
+/// of these types are also valid.  Attributes have no types if they can't
+/// hold references, or if their references are untyped (SELECTs of only
+/// entities, which are parsed as plain ids).  An empty list means that no
+/// attribute of the entity has typed references.
+pub fn attribute_targets(s: &str) -> &'static [&'static [&'static str]] {{
+    match s {{")?;
+    for k in &keys {
+        type_map.0[k].write_attribute_targets(k, &mut buf, &type_map)?;
+    }
+    writeln!(&mut buf, "        _ => &[],
+    }}
+}}
+
+/// Returns how values of the given named type (entity or defined type) are
+/// written in Part 21, as described in [`attribute_kinds`]
+pub fn type_kind(s: &str) -> Option<&'static str> {{
+    match s {{")?;
+    for k in keys.iter().filter(|k| !matches!(type_map.0[*k], Type::Primitive(_))) {
+        writeln!(&mut buf, r#"        "{}" => Some("{}"),"#,
+                 capitalize(k), type_map.kind(k))?;
+    }
+    writeln!(&mut buf, "        _ => None,
+    }}
+}}
+
+/// Returns the Part 21 spelling (e.g. `.AHEAD.`) of a value from any
+/// enumeration in the schema, ignoring case.  The value is given without
+/// the surrounding dots.
+pub fn enumeration_literal(s: &str) -> Option<&'static str> {{
+    match s.to_ascii_uppercase().as_str() {{")?;
+    let tags: BTreeSet<String> = type_map.0.values()
+        .filter_map(|t| match t {
+            Type::Enum(c) => Some(c.iter().map(|v| capitalize(v))),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    for t in &tags {
+        writeln!(&mut buf, r#"        "{0}" => Some(".{0}."),"#, t)?;
+    }
+    writeln!(&mut buf, "        _ => None,
+    }}
+}}
 impl<'a> Entity<'a> {{
+    /// Returns the type of a simple entity, as its Part 21 keyword.  Returns
+    /// `None` for complex entities and placeholder variants.
+    pub fn type_name(&self) -> Option<&'static str> {{
+        match self {{")?;
+    for k in keys.iter().filter(|k| type_map.0[*k].is_entity()) {
+        writeln!(&mut buf,
+            r#"            Entity::{}(_) => Some("{}"),"#,
+            to_camel(k), capitalize(k))?;
+    }
+    writeln!(&mut buf, "            _ => None,
+        }}
+    }}
+
     pub fn upstream(&self) -> Vec<usize> {{
         let mut out = Vec::new();
         match self {{
@@ -523,15 +1046,51 @@ impl<'a> Entity<'a> {{
                     out.extend(e.upstream().into_iter());
                 }}
             }},
+            Entity::Untyped(v) => {{
+                for p in v.iter().flat_map(|r| r.params.iter()) {{
+                    p.append_refs(&mut out);
+                }}
+            }},
             _ => (),
         }};
         out
     }}
+
+    /// Converts a simple entity (including an untyped simple instance) into
+    /// a Part 21 record.  Returns `None` for complex entities and
+    /// placeholder variants.
+    pub fn to_record(&self) -> Option<Record<'_>> {{
+        match self {{")?;
+    for k in keys.iter().filter(|k| type_map.0[*k].is_entity()) {
+        writeln!(&mut buf,
+            "            Entity::{}(c) => Some(c.to_record()),",
+            to_camel(k))?;
+    }
+    writeln!(&mut buf, "            Entity::Untyped(v) if v.len() == 1 => Some(v[0].clone()),
+            _ => None,
+        }}
+    }}
 }}")?;
 
     Ok(buf)
 }
 
+fn primitive_kind(rtype: &str) -> &'static str {
+    match rtype {
+        "usize" => "BINARY",
+        "bool" => "BOOLEAN",
+        "i64" => "INTEGER",
+        "f64" => "REAL",
+        "Logical" => "LOGICAL",
+        "Cow<'a, str>" => "STRING",
+        _ => panic!("Unknown primitive type {}", rtype),
+    }
+}
+
+fn round_up(size: usize, align: usize) -> usize {
+    size.div_ceil(align) * align
+}
+
 fn capitalize(s: &str) -> String {
     s.chars().map(|c| c.to_uppercase().next().unwrap()).collect()
 }
@@ -781,6 +1340,50 @@ impl<'a> SelectList<'a> {
         Type::Select(out)
     }
 }
+impl<'a> SupertypeConstraint<'a> {
+    /// Returns the expression constraining this entity's subtypes, if any
+    fn expression(&self) -> Option<&SupertypeExpression<'a>> {
+        match self {
+            SupertypeConstraint::AbstractEntity => None,
+            SupertypeConstraint::AbstractSupertype(a) => a.0.as_ref().map(|c| &c.0),
+            SupertypeConstraint::SupertypeRule(r) => Some(&r.0.0),
+        }
+    }
+}
+impl<'a> SupertypeExpression<'a> {
+    fn terms(&self) -> impl Iterator<Item=&SupertypeTerm<'a>> {
+        std::iter::once(&self.0).chain(self.1.iter()).flat_map(|f| f.0.iter())
+    }
+    /// Collects every ONEOF in this expression, as the entities named in
+    /// each of its operands
+    fn one_ofs(&self, out: &mut Vec<Vec<HashSet<&'a str>>>) {
+        for t in self.terms() {
+            match t {
+                SupertypeTerm::Entity(_) => (),
+                SupertypeTerm::OneOf(o) => {
+                    out.push(o.0.iter().map(|e| {
+                        let mut names = HashSet::new();
+                        e.entities(&mut names);
+                        names
+                    }).collect());
+                    for e in &o.0 {
+                        e.one_ofs(out);
+                    }
+                },
+                SupertypeTerm::Expression(e) => e.one_ofs(out),
+            }
+        }
+    }
+    fn entities(&self, out: &mut HashSet<&'a str>) {
+        for t in self.terms() {
+            match t {
+                SupertypeTerm::Entity(e) => { out.insert(e.0); },
+                SupertypeTerm::OneOf(o) => o.0.iter().for_each(|e| e.entities(out)),
+                SupertypeTerm::Expression(e) => e.entities(out),
+            }
+        }
+    }
+}
 impl<'a> EntityDecl<'a> {
     fn to_type(&'a self, type_map: &mut TypeMap<'a>) -> Type<'a> {
         // Derived values from parents shouldn't be stored in the struct, so
@@ -863,8 +1466,10 @@ impl<'a> EntityDecl<'a> {
                     from: None,
                     dupe: false,
                     derived: false,
+                    derivable: false,
                     type_: attr_type.clone(),
                     optional: attr.optional,
+                    param: &attr.parameter_type,
                 });
             }
         }
@@ -909,7 +1514,70 @@ impl<'a> ParameterType<'a> {
         }
     }
 }
+impl<'a> ParameterType<'a> {
+    fn to_targets(&self, type_map: &TypeMap<'a>) -> Option<Vec<&'a str>> {
+        match self {
+            ParameterType::Generalized(GeneralizedTypes::GeneralAggregation(a)) => {
+                let param_type = match a {
+                    GeneralAggregationTypes::Array(a) => &a.parameter_type,
+                    GeneralAggregationTypes::Bag(a) => &a.1,
+                    GeneralAggregationTypes::List(a) => &a.parameter_type,
+                    GeneralAggregationTypes::Set(a) => &a.parameter_type,
+                };
+                param_type.to_targets(type_map)
+            },
+            ParameterType::Generalized(_) =>
+                panic!("No support for generalized types"),
+            ParameterType::Named(e) => type_map.targets(e.name()),
+            ParameterType::Simple(_) => Some(vec![]),
+        }
+    }
+    fn to_kind(&self, type_map: &TypeMap<'a>) -> String {
+        match self {
+            ParameterType::Generalized(GeneralizedTypes::GeneralAggregation(a)) => {
+                let param_type = match a {
+                    GeneralAggregationTypes::Array(a) => &a.parameter_type,
+                    GeneralAggregationTypes::Bag(a) => &a.1,
+                    GeneralAggregationTypes::List(a) => &a.parameter_type,
+                    GeneralAggregationTypes::Set(a) => &a.parameter_type,
+                };
+                format!("LIST OF {}", param_type.to_kind(type_map))
+            },
+            ParameterType::Generalized(_) =>
+                panic!("No support for generalized types"),
+            ParameterType::Named(e) => type_map.kind(e.name()),
+            ParameterType::Simple(e) =>
+                primitive_kind(e.to_attr_type_str()).to_owned(),
+        }
+    }
+}
+impl<'a> ParameterType<'a> {
+    fn to_layout(&self, type_map: &TypeMap<'a>) -> Layout {
+        match self {
+            ParameterType::Generalized(GeneralizedTypes::GeneralAggregation(a)) =>
+                a.to_layout(type_map),
+            ParameterType::Generalized(_) =>
+                panic!("No support for generalized types"),
+            ParameterType::Named(e) => type_map.layout(e.name()),
+            ParameterType::Simple(e) => Layout::primitive(e.to_attr_type_str()),
+        }
+    }
+}
 impl<'a> GeneralAggregationTypes<'a> {
+    // Mirrors `to_attr_type_str`
+    fn to_layout(&self, type_map: &TypeMap<'a>) -> Layout {
+        let (optional, param_type) = match self {
+            GeneralAggregationTypes::Array(a) => (a.optional, &a.parameter_type),
+            GeneralAggregationTypes::Bag(a) => (false,  &a.1),
+            GeneralAggregationTypes::List(a) => (false, &a.parameter_type),
+            GeneralAggregationTypes::Set(a) => (false, &a.parameter_type),
+        };
+        let t = match self.upper_bound() {
+            Some(b) => param_type.to_layout(type_map).array_vec(b),
+            None => Layout::VEC,
+        };
+        if optional { t.optional() } else { t }
+    }
     fn upper_bound(&self) -> Option<usize> {
         let upper: Option<&Bound2> = match &self {
             GeneralAggregationTypes::Array(a) => Some(&a.bounds.1),
@@ -947,7 +1615,7 @@ impl <'a> SimpleTypes<'a> {
             SimpleTypes::Logical => "Logical",
             SimpleTypes::Number => "f64",
             SimpleTypes::Real(_) => "f64",
-            SimpleTypes::String(_) => "&'a str",
+            SimpleTypes::String(_) => "Cow<'a, str>",
         }
     }
     fn to_type(&self) -> Type {
@@ -296,7 +296,7 @@ fn abstract_supertype(s: &str) -> IResult<()> {
 
 // 166 abstract_supertype_declaration = ABSTRACT SUPERTYPE [ subtype_constraint ] .
 #[derive(Debug)]
-pub struct AbstractSupertypeDeclaration<'a>(Option<SubtypeConstraint<'a>>);
+pub struct AbstractSupertypeDeclaration<'a>(pub Option<SubtypeConstraint<'a>>);
 fn abstract_supertype_declaration(s: &str) -> IResult<AbstractSupertypeDeclaration> {
     map(tuple((
         kw("abstract"),
@@ -1518,7 +1518,7 @@ alias!(NumericExpression<'a>, SimpleExpression);
 
 // 263 one_of = ONEOF ’(’ supertype_expression { ’,’ supertype_expression } ’)’
 #[derive(Debug)]
-pub struct OneOf<'a>(Vec<SupertypeExpression<'a>>);
+pub struct OneOf<'a>(pub Vec<SupertypeExpression<'a>>);
 fn one_of(s: &str) -> IResult<OneOf> {
     map(preceded(
         kw("oneof"),
@@ -2228,7 +2228,7 @@ fn subsuper(s: &str) -> IResult<Subsuper> {
 
 // 313 subtype_constraint = OF ’(’ supertype_expression ’)’ .
 #[derive(Debug)]
-pub struct SubtypeConstraint<'a>(SupertypeExpression<'a>);
+pub struct SubtypeConstraint<'a>(pub SupertypeExpression<'a>);
 fn subtype_constraint(s: &str) -> IResult<SubtypeConstraint> {
     map(preceded(kw("of"), parens(supertype_expression)),
         SubtypeConstraint)(s)
@@ -2314,8 +2314,8 @@ fn supertype_constraint(s: &str) -> IResult<SupertypeConstraint> {
 
 // 320 supertype_expression = supertype_factor { ANDOR supertype_factor } .
 #[derive(Debug)]
-pub struct SupertypeExpression<'a>(SupertypeFactor<'a>,
-                               Vec<SupertypeFactor<'a>>);
+pub struct SupertypeExpression<'a>(pub SupertypeFactor<'a>,
+                                   pub Vec<SupertypeFactor<'a>>);
 fn supertype_expression(s: &str) -> IResult<SupertypeExpression> {
     let (s, a) = supertype_factor(s)?;
     let (s, b) = many0(preceded(kw("andor"), supertype_factor))(s)?;
@@ -2324,7 +2324,7 @@ fn supertype_expression(s: &str) -> IResult<SupertypeExpression> {
 
 // 321 supertype_factor = supertype_term { AND supertype_term } .
 #[derive(Debug)]
-pub struct SupertypeFactor<'a>(Vec<SupertypeTerm<'a>>);
+pub struct SupertypeFactor<'a>(pub Vec<SupertypeTerm<'a>>);
 fn supertype_factor(s: &str) -> IResult<SupertypeFactor> {
     map(separated_list1(kw("and"), supertype_term),
         SupertypeFactor)(s)
@@ -2332,7 +2332,7 @@ fn supertype_factor(s: &str) -> IResult<SupertypeFactor> {
 
 // 322 supertype_rule = SUPERTYPE subtype_constraint .
 #[derive(Debug)]
-pub struct SupertypeRule<'a>(SubtypeConstraint<'a>);
+pub struct SupertypeRule<'a>(pub SubtypeConstraint<'a>);
 fn supertype_rule(s: &str) -> IResult<SupertypeRule> {
     map(preceded(kw("supertype"), subtype_constraint), SupertypeRule)(s)
 }
@@ -85,13 +85,12 @@ fn main() {
     // and triangulated in the background while we wait for a GPU context
     let loader = std::thread::spawn(|| {
         println!("Loading mesh!");
-        use step::step_file::StepFile;
+        use step::owned::OwnedStepFile;
         use triangulate::triangulate::triangulate;
 
         let data = std::fs::read(input).expect("Could not open file");
-        let flat = StepFile::strip_flatten(&data);
-        let step = StepFile::parse(&flat);
-        let (mesh, _stats) = triangulate(&step);
+        let step = OwnedStepFile::load_lazy(data).expect("Could not load file");
+        let (mesh, _stats) = triangulate(step.file());
         mesh
     });
 
@@ -16,9 +16,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let start = SystemTime::now();
 
     let data = std::fs::read(input)?;
-    let flat = StepFile::strip_flatten(&data);
-    let entities = StepFile::parse(&flat);
-    println!("Got {} entities", entities.0.len());
+    let entities = StepFile::parse(&data);
+    println!("Got {} entities", entities.len());
 
     let end = SystemTime::now();
     let since_the_epoch = end.duration_since(start).expect("Time went backwards");
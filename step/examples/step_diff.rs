@@ -0,0 +1,60 @@
+use clap::{Arg, App};
+use step::{diff::{diff, Counts}, step_file::StepFile};
+
+fn print_counts(title: &str, counts: &std::collections::BTreeMap<String, Counts>) {
+    if counts.is_empty() {
+        return;
+    }
+    println!("{}:", title);
+    let width = counts.keys().map(|k| k.len()).max().unwrap_or(0);
+    for (k, c) in counts {
+        println!("  {:width$}  +{} -{} ~{}", k, c.added, c.removed, c.modified,
+                 width = width);
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = App::new("step_diff")
+        .author("Matt Keeter <matt@formlabs.com>")
+        .about("Compares two STEP files by structure, ignoring entity ids")
+        .arg(Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .help("list every changed entity"))
+        .arg(Arg::with_name("old")
+            .takes_value(true)
+            .required(true))
+        .arg(Arg::with_name("new")
+            .takes_value(true)
+            .required(true))
+        .get_matches();
+    let old_path = matches.value_of("old").expect("Could not get old file");
+    let new_path = matches.value_of("new").expect("Could not get new file");
+
+    let old_data = std::fs::read(old_path)?;
+    let new_data = std::fs::read(new_path)?;
+    let old = StepFile::parse(&old_data);
+    let new = StepFile::parse(&new_data);
+    let d = diff(&old, &new);
+
+    println!("{} unchanged, {} added, {} removed, {} modified",
+             d.unchanged.len(), d.added.len(), d.removed.len(), d.modified.len());
+    if d.is_empty() {
+        return Ok(());
+    }
+    print_counts("By type", &d.by_type(&old, &new));
+    print_counts("By product", &d.by_product(&old, &new));
+
+    if matches.is_present("verbose") {
+        for i in d.removed.iter() {
+            println!("- #{} {:?}", i, old[step::id::Id::<()>::new(*i)]);
+        }
+        for i in d.added.iter() {
+            println!("+ #{} {:?}", i, new[step::id::Id::<()>::new(*i)]);
+        }
+        for (i, j) in d.modified.iter() {
+            println!("~ #{} -> #{} {:?}", i, j, new[step::id::Id::<()>::new(*j)]);
+        }
+    }
+    std::process::exit(1);
+}
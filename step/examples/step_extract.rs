@@ -0,0 +1,45 @@
+use clap::{Arg, App};
+use step::step_file::StepFile;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = App::new("step_extract")
+        .author("Matt Keeter <matt@formlabs.com>")
+        .about("Writes a standalone STEP file containing the given entities \
+                and everything that they depend on")
+        .arg(Arg::with_name("output")
+            .short("o")
+            .long("out")
+            .help("STEP file to target")
+            .takes_value(true)
+            .required(true))
+        .arg(Arg::with_name("input")
+            .takes_value(true)
+            .required(true))
+        .arg(Arg::with_name("roots")
+            .help("entity ids to extract, e.g. 123 or #123")
+            .takes_value(true)
+            .multiple(true)
+            .required(true))
+        .get_matches();
+    let input = matches.value_of("input")
+        .expect("Could not get input file");
+    let output = matches.value_of("output")
+        .expect("Could not get output file");
+    let roots = matches.values_of("roots")
+        .expect("Could not get root ids")
+        .map(|s| s.trim_start_matches('#').parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let data = std::fs::read(input)?;
+    let step = StepFile::parse(&data);
+    for r in roots.iter() {
+        if step.get(*r).is_none() {
+            return Err(format!("#{} is not an entity in {}", r, input).into());
+        }
+    }
+
+    let mut out = std::io::BufWriter::new(std::fs::File::create(output)?);
+    step.extract(&roots, &mut out)?;
+    println!("Extracted {} of {} entities", step.closure(&roots).len(), step.len());
+    Ok(())
+}
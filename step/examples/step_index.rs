@@ -0,0 +1,52 @@
+use std::time::SystemTime;
+use clap::{Arg, App};
+use step::{id::Id, step_file::StepFile};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = App::new("step_index")
+        .author("Matt Keeter <matt@formlabs.com>")
+        .about("Writes a binary index cache of a STEP file and times loading it")
+        .arg(Arg::with_name("output")
+            .short("o")
+            .long("out")
+            .help("index cache file to target")
+            .takes_value(true)
+            .required(true))
+        .arg(Arg::with_name("input")
+            .takes_value(true)
+            .required(true))
+        .get_matches();
+    let input = matches.value_of("input")
+        .expect("Could not get input file");
+    let output = matches.value_of("output")
+        .expect("Could not get output file");
+
+    let data = std::fs::read(input)?;
+    let start = SystemTime::now();
+    let step = StepFile::parse(&data);
+    println!("Parsed {} entities in {:?}", step.len(), start.elapsed()?);
+
+    let start = SystemTime::now();
+    let step = StepFile::parse_lazy(&data);
+    println!("Indexed {} entities in {:?}", step.len(), start.elapsed()?);
+
+    let mut out = std::io::BufWriter::new(std::fs::File::create(output)?);
+    step.write_index_cache(&mut out)?;
+    drop(out);
+
+    // Reading the cache is included, since the Part 21 timings above aren't
+    // charged for reading their input
+    let start = SystemTime::now();
+    let cache = std::fs::read(output)?;
+    let step = StepFile::from_index_cache(&cache)?;
+    println!("Loaded index cache of {} entities in {:?}", step.len(), start.elapsed()?);
+
+    // Entities in a cache are still stored as Part 21 text, so decoding them
+    // costs as much as after parse_lazy
+    let start = SystemTime::now();
+    for (i, _) in step.sources() {
+        let _ = &step[Id::<()>::new(i)];
+    }
+    println!("Decoded {} entities from the cache in {:?}", step.len(), start.elapsed()?);
+    Ok(())
+}
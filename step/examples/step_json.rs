@@ -0,0 +1,36 @@
+use clap::{Arg, App};
+use step::{owned::OwnedStepFile, step_file::StepFile};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = App::new("step_json")
+        .author("Matt Keeter <matt@formlabs.com>")
+        .about("Converts a STEP file to JSON, or JSON back to STEP")
+        .arg(Arg::with_name("output")
+            .short("o")
+            .long("out")
+            .help("file to target")
+            .takes_value(true))
+        .arg(Arg::with_name("import")
+            .short("i")
+            .long("import")
+            .help("read JSON and write STEP"))
+        .arg(Arg::with_name("input")
+            .takes_value(true)
+            .required(true))
+        .get_matches();
+    let input = matches.value_of("input")
+        .expect("Could not get input file");
+
+    let data = std::fs::read(input)?;
+    let mut out: Box<dyn std::io::Write> = match matches.value_of("output") {
+        Some(out) => Box::new(std::io::BufWriter::new(std::fs::File::create(out)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    if matches.is_present("import") {
+        let file = OwnedStepFile::from_json(std::str::from_utf8(&data)?)?;
+        file.file().write(&mut out)?;
+    } else {
+        StepFile::parse(&data).write_json(&mut out)?;
+    }
+    Ok(())
+}
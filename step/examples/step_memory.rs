@@ -0,0 +1,63 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use clap::{Arg, App};
+use step::{ap214::Entity, step_file::StepFile};
+
+/// Allocator which keeps track of the number of bytes in use
+struct Counting;
+
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        IN_USE.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: Counting = Counting;
+
+/// Returns the heap used by the value returned from `f`, which is kept alive
+/// until it has been measured
+fn heap_used<T, F: FnOnce() -> T>(f: F) -> usize {
+    let before = IN_USE.load(Ordering::Relaxed);
+    let out = f();
+    let used = IN_USE.load(Ordering::Relaxed).saturating_sub(before);
+    drop(out);
+    used
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = App::new("step_memory")
+        .author("Matt Keeter <matt@formlabs.com>")
+        .about("Measures the heap used by parsed STEP files, and parse time")
+        .arg(Arg::with_name("input")
+            .takes_value(true)
+            .multiple(true)
+            .required(true))
+        .get_matches();
+
+    println!("size_of::<Entity>() = {}", std::mem::size_of::<Entity>());
+    for input in matches.values_of("input").expect("Could not get input files") {
+        let data = std::fs::read(input)?;
+        let eager = heap_used(|| StepFile::parse(&data));
+        let lazy = heap_used(|| StepFile::parse_lazy(&data));
+        println!("{}: {} bytes of input, {} bytes parsed, {} bytes indexed",
+                 input, data.len(), eager, lazy);
+
+        // Small files parse quickly, so average over many runs
+        const RUNS: u32 = 100;
+        let start = Instant::now();
+        for _ in 0..RUNS {
+            drop(StepFile::parse(&data));
+        }
+        println!("    parsed in {:?} on average", start.elapsed() / RUNS);
+    }
+    Ok(())
+}
@@ -0,0 +1,44 @@
+use clap::{Arg, App};
+use step::{query::Query, step_file::StepFile};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = App::new("step_query")
+        .author("Matt Keeter <matt@formlabs.com>")
+        .about("Prints the entities in a STEP file which match a query, e.g. \
+                \"ADVANCED_FACE[face_geometry -> CYLINDRICAL_SURFACE[radius < 2]]\"")
+        .arg(Arg::with_name("count")
+            .short("c")
+            .long("count")
+            .help("only print the number of matches"))
+        .arg(Arg::with_name("input")
+            .takes_value(true)
+            .required(true))
+        .arg(Arg::with_name("query")
+            .takes_value(true)
+            .required(true))
+        .get_matches();
+    let input = matches.value_of("input")
+        .expect("Could not get input file");
+    let text = matches.value_of("query")
+        .expect("Could not get query");
+    let query: Query = match text.parse() {
+        Ok(q) => q,
+        Err(e) => {
+            eprintln!("{}\n{}\n{:>width$}", e, text, "^", width = e.offset + 1);
+            std::process::exit(1);
+        },
+    };
+
+    let data = std::fs::read(input)?;
+    let step = StepFile::parse_lazy(&data);
+    let ids = step.query(&query);
+    if matches.is_present("count") {
+        println!("{}", ids.len());
+    } else {
+        for i in ids {
+            let s = step.source(i).unwrap();
+            println!("{}", String::from_utf8_lossy(s).trim());
+        }
+    }
+    Ok(())
+}
@@ -1,52 +1,93 @@
 use clap::{Arg, App};
-use step::step_file::StepFile;
-
-pub fn to_dot(s: &StepFile) -> String {
-    let mut out = "digraph {\n".to_owned();
-    for (i, e) in s.0.iter().enumerate() {
-        let d = format!("{:?}", e);
-        let name = d.split("(").next().unwrap();
-
-        out += &format!("  e{} [ label = \"#{}: {}\" ];\n", i, i, name);
-        for j in e.upstream() {
-            out += &format!("  e{} -> e{};\n", i, j);
-        }
-    }
-    out += "}";
-    out
-}
+use step::{
+    graph::{GraphFormat, GraphOptions},
+    step_file::StepFile,
+};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let matches = App::new("step_to_dot2")
+    let matches = App::new("step_to_dot")
         .author("Matt Keeter <matt@formlabs.com>")
-        .about("Converts a STEP file to a dot file")
+        .about("Exports the entity graph of a STEP file as DOT, GraphML or JSON")
         .arg(Arg::with_name("output")
             .short("o")
             .long("out")
-            .help("dot file to target")
+            .help("graph file to target")
             .takes_value(true))
+        .arg(Arg::with_name("format")
+            .short("f")
+            .long("format")
+            .help("output format (dot, graphml or json)")
+            .takes_value(true)
+            .default_value("dot"))
+        .arg(Arg::with_name("root")
+            .short("r")
+            .long("root")
+            .help("entity id to start from (may be repeated)")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("depth")
+            .short("d")
+            .long("depth")
+            .help("maximum number of references to follow from the roots")
+            .takes_value(true))
+        .arg(Arg::with_name("include")
+            .long("include")
+            .help("only show entities of this type (may be repeated)")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("exclude")
+            .long("exclude")
+            .help("hide entities of this type (may be repeated)")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("collapse")
+            .short("c")
+            .long("collapse")
+            .help("fold points and directions into the entities using them"))
         .arg(Arg::with_name("input")
             .takes_value(true)
             .required(true))
         .get_matches();
     let input = matches.value_of("input")
         .expect("Could not get input file");
+    let format: GraphFormat = matches.value_of("format").unwrap().parse()?;
+    let list = |name| -> Vec<String> {
+        matches.values_of(name)
+            .map(|v| v.map(|s| s.to_ascii_uppercase()).collect())
+            .unwrap_or_default()
+    };
+    let mut opts = GraphOptions {
+        roots: matches.values_of("root")
+            .map(|v| v.map(|s| s.trim_start_matches('#').parse())
+                      .collect::<Result<Vec<usize>, _>>())
+            .transpose()?
+            .unwrap_or_default(),
+        max_depth: matches.value_of("depth").map(str::parse).transpose()?,
+        include: list("include"),
+        exclude: list("exclude"),
+        collapse: vec![],
+    };
+    if matches.is_present("collapse") {
+        opts = opts.collapse_geometry();
+    }
 
     let start = std::time::SystemTime::now();
     let data = std::fs::read(input)?;
-    let flat = StepFile::strip_flatten(&data);
-    let entities = StepFile::parse(&flat);
+    let entities = StepFile::parse(&data);
     let end = std::time::SystemTime::now();
     let since_the_epoch = end.duration_since(start)
         .expect("Time went backwards");
-    println!("Loaded + parsed in {:?}", since_the_epoch);
+    eprintln!("Loaded + parsed in {:?}", since_the_epoch);
 
-    let dot = to_dot(&entities);
+    let graph = entities.graph(&opts);
     if let Some(out) = matches.value_of("output") {
-        std::fs::write(out, dot)?;
+        let mut f = std::io::BufWriter::new(std::fs::File::create(out)?);
+        graph.write(format, &mut f)?;
     } else {
-        println!("{}", dot);
+        graph.write(format, &mut std::io::stdout().lock())?;
     }
     Ok(())
 }
-
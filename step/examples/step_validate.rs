@@ -0,0 +1,58 @@
+use clap::{Arg, App};
+use step::step_file::StepFile;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = App::new("step_validate")
+        .author("Matt Keeter <matt@formlabs.com>")
+        .about("Checks the references in a STEP file, reporting dangling ids, wrong types and cycles")
+        .arg(Arg::with_name("lenient")
+            .short("l")
+            .long("lenient")
+            .help("repair common exporter mistakes instead of rejecting entities"))
+        .arg(Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .help("only print the summary"))
+        .arg(Arg::with_name("input")
+            .takes_value(true)
+            .required(true))
+        .get_matches();
+    let input = matches.value_of("input")
+        .expect("Could not get input file");
+    let quiet = matches.is_present("quiet");
+
+    let data = std::fs::read(input)?;
+    let (entities, parsed) = if matches.is_present("lenient") {
+        StepFile::parse_lenient(&data)
+    } else {
+        StepFile::parse_with_report(&data)
+    };
+    if !quiet {
+        for f in &parsed.fixups {
+            println!("line {}: #{} repaired {}[{}] ({}): {:?}", f.line, f.id,
+                     f.keyword, f.index, f.attribute.unwrap_or("extra"), f.kind);
+        }
+        for e in &parsed.errors {
+            match e.id {
+                Some(id) => println!("line {}: #{} failed to parse", e.line, id),
+                None => println!("line {}: instance failed to parse", e.line),
+            }
+        }
+    }
+
+    let report = entities.validate();
+    if !quiet {
+        for i in &report.issues {
+            println!("{}", i);
+        }
+    }
+    println!("{} entities: {} repaired, {} failed to parse, {} dangling references, \
+              {} wrong types, {} cycles",
+             parsed.total, parsed.fixed(), parsed.failed(), report.dangling(),
+             report.wrong_type(), report.cycles());
+
+    if !report.is_ok() || !parsed.is_ok() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,388 @@
+use memchr::{memchr, memchr3};
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use crate::parse::{comment_end, skip_ws, string_end};
+
+/// Lexical state at a particular point in the file
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum State {
+    Normal,
+    String,
+    Comment,
+}
+
+/// Scans `data` from `i` (in the given state) up to `end`, recording the
+/// offset of every semicolon which terminates a block.
+///
+/// Returns the state and offset at which scanning stopped.  The offset may
+/// be slightly past `end`, if a token straddles the boundary (e.g. an escaped
+/// quote `''` or the `*/` closing a comment).
+fn scan(data: &[u8], mut i: usize, mut state: State, end: usize,
+        out: &mut Vec<usize>) -> (State, usize)
+{
+    while i < end {
+        match state {
+            State::Normal => {
+                let j = match memchr3(b';', b'\'', b'/', &data[i..end]) {
+                    Some(j) => i + j,
+                    None => return (state, end),
+                };
+                i = j + 1;
+                match data[j] {
+                    b';' => out.push(j),
+                    b'\'' => state = State::String,
+                    _ => if data.get(j + 1) == Some(&b'*') {
+                        state = State::Comment;
+                        i += 1;
+                    },
+                }
+            },
+            State::String => {
+                let j = match memchr(b'\'', &data[i..end]) {
+                    Some(j) => i + j,
+                    None => return (state, end),
+                };
+                if data.get(j + 1) == Some(&b'\'') {
+                    i = j + 2;
+                } else {
+                    state = State::Normal;
+                    i = j + 1;
+                }
+            },
+            State::Comment => {
+                let j = match memchr(b'*', &data[i..end]) {
+                    Some(j) => i + j,
+                    None => return (state, end),
+                };
+                if data.get(j + 1) == Some(&b'/') {
+                    state = State::Normal;
+                    i = j + 2;
+                } else {
+                    i = j + 1;
+                }
+            },
+        }
+    }
+    (state, i)
+}
+
+/// Returns the offsets of every block-terminating semicolon in the file,
+/// i.e. those which aren't within a string or comment.
+///
+/// With the `parallel` feature, the file is split into chunks which are
+/// scanned in parallel, each assuming that it begins outside of any string
+/// or comment.  Chunks where that assumption turns out to be wrong are then
+/// re-scanned (serially) from the correct state; this is rare, since strings
+/// and comments are short compared to the chunk size.
+fn semicolons(data: &[u8]) -> Vec<usize> {
+    #[cfg(feature = "rayon")]
+    const CHUNK_SIZE: usize = 1 << 20;
+
+    #[cfg(feature = "rayon")]
+    if data.len() > CHUNK_SIZE {
+        let chunk_size = std::cmp::max(
+            CHUNK_SIZE, data.len() / (4 * rayon::current_num_threads()));
+        let starts: Vec<usize> = (0..data.len()).step_by(chunk_size).collect();
+        let mut chunks: Vec<_> = starts.par_iter()
+            .map(|&start| {
+                let end = std::cmp::min(start + chunk_size, data.len());
+                let mut out = vec![];
+                let r = scan(data, start, State::Normal, end, &mut out);
+                (out, r)
+            })
+            .collect();
+
+        let mut expected = (State::Normal, 0);
+        for (start, chunk) in starts.iter().zip(chunks.iter_mut()) {
+            let end = std::cmp::min(start + chunk_size, data.len());
+            if expected != (State::Normal, *start) {
+                chunk.0.clear();
+                chunk.1 = if expected.1 >= end {
+                    expected
+                } else {
+                    scan(data, expected.1, expected.0, end, &mut chunk.0)
+                };
+            }
+            expected = chunk.1;
+        }
+        return chunks.into_iter().flat_map(|c| c.0.into_iter()).collect();
+    }
+
+    let mut out = vec![];
+    scan(data, 0, State::Normal, data.len(), &mut out);
+    out
+}
+
+/// Splits a STEP file into individual blocks, each terminated by a
+/// semicolon.  Whitespace and comments between blocks are skipped, so each
+/// block begins with its first token; whitespace and comments within blocks
+/// are left in place, and are skipped by the parser.
+///
+/// Any text after the final semicolon is ignored.
+pub(crate) fn split_blocks(data: &[u8]) -> Vec<&[u8]> {
+    let mut start = 0;
+    semicolons(data).into_iter()
+        .map(|end| {
+            let b = &data[skip_ws(&data[..end], start)..=end];
+            start = end + 1;
+            b
+        })
+        .collect()
+}
+
+/// Checks whether a block is the given keyword followed by a semicolon,
+/// e.g. `DATA;`, allowing for whitespace and comments before the semicolon
+pub(crate) fn block_is(b: &[u8], keyword: &str) -> bool {
+    match b.strip_prefix(keyword.as_bytes()) {
+        Some(rest) if !rest.is_empty() => skip_ws(rest, 0) == rest.len() - 1,
+        _ => false,
+    }
+}
+
+/// Checks whether a block opens the given section, either as `KEYWORD;` or
+/// (for edition 3 DATA sections) with parameters, e.g. `DATA('a',('S'));`
+fn block_opens(b: &[u8], keyword: &str) -> bool {
+    match b.strip_prefix(keyword.as_bytes()) {
+        Some(rest) => matches!(rest.get(skip_ws(rest, 0)), Some(b'(' | b';')),
+        None => false,
+    }
+}
+
+/// Blocks from each section of the exchange structure
+#[derive(Debug, Default)]
+pub(crate) struct Sections<'a> {
+    /// Blocks within the (first) HEADER section
+    pub header: Vec<&'a [u8]>,
+    /// Blocks within every ANCHOR section
+    pub anchor: Vec<&'a [u8]>,
+    /// Blocks within every REFERENCE section
+    pub reference: Vec<&'a [u8]>,
+    /// Blocks within every DATA section, concatenated
+    pub data: Vec<&'a [u8]>,
+    /// Opening block of each DATA section, plus the blocks of `data` which
+    /// it contains
+    pub data_sections: Vec<(&'a [u8], std::ops::Range<usize>)>,
+}
+
+/// Sorts blocks into sections.  Edition 3 files may have any number of
+/// ANCHOR, REFERENCE, and DATA sections; blocks outside of a section are
+/// ignored, as is everything after `END-ISO-10303-21;`.
+pub(crate) fn split_sections<'a>(blocks: &[&'a [u8]]) -> Sections<'a> {
+    #[derive(Copy, Clone, PartialEq)]
+    enum Section { None, Header, Anchor, Reference, Data }
+
+    let mut out = Sections::default();
+    let mut section = Section::None;
+    let mut seen_header = false;
+    for b in blocks {
+        if block_is(b, "ENDSEC") {
+            if section == Section::Data {
+                if let Some((_, r)) = out.data_sections.last_mut() {
+                    r.end = out.data.len();
+                }
+            }
+            section = Section::None;
+            continue;
+        }
+        match section {
+            Section::None => if block_is(b, "HEADER") && !seen_header {
+                seen_header = true;
+                section = Section::Header;
+            } else if block_is(b, "ANCHOR") {
+                section = Section::Anchor;
+            } else if block_is(b, "REFERENCE") {
+                section = Section::Reference;
+            } else if block_opens(b, "DATA") {
+                section = Section::Data;
+                let n = out.data.len();
+                out.data_sections.push((b, n..n));
+            } else if block_is(b, "END-ISO-10303-21") {
+                break;
+            },
+            Section::Header => out.header.push(b),
+            Section::Anchor => out.anchor.push(b),
+            Section::Reference => out.reference.push(b),
+            Section::Data => out.data.push(b),
+        }
+    }
+    // Tolerate a missing ENDSEC at the end of the file
+    if section == Section::Data {
+        if let Some((_, r)) = out.data_sections.last_mut() {
+            r.end = out.data.len();
+        }
+    }
+    out
+}
+
+/// Extracts entity keywords from a `#id=...;` block, without parsing its
+/// parameters.  This is forgiving of malformed input, since it's also used
+/// for error reporting on blocks which failed to parse.
+pub(crate) fn block_keywords(b: &[u8]) -> Vec<&str> {
+    let b = match memchr(b'=', b) {
+        Some(i) => &b[skip_ws(b, i + 1)..],
+        None => return vec![],
+    };
+    let name = |b: &[u8]| -> usize {
+        b.iter()
+            .position(|c| !(c.is_ascii_alphanumeric() || *c == b'_'))
+            .unwrap_or(b.len())
+    };
+    let as_str = |b| std::str::from_utf8(b).unwrap_or("");
+
+    if b.first() != Some(&b'(') {
+        return vec![as_str(&b[..name(b)])];
+    }
+
+    // Complex instance: record every name at depth 1, skipping strings and
+    // comments
+    let mut out = vec![];
+    let mut depth = 0;
+    let mut i = 0;
+    while i < b.len() {
+        match b[i] {
+            b'(' => depth += 1,
+            b')' => depth -= 1,
+            b'\'' => match string_end(&b[i + 1..]) {
+                Some(j) => i += j + 1,
+                None => break,
+            },
+            b'/' if b.get(i + 1) == Some(&b'*') => {
+                i = comment_end(b, i + 2).unwrap_or(b.len());
+                continue;
+            },
+            c if depth == 1 && c.is_ascii_alphabetic() => {
+                let n = name(&b[i..]);
+                out.push(as_str(&b[i..i + n]));
+                i += n;
+                continue;
+            },
+            _ => (),
+        }
+        i += 1;
+    }
+    out
+}
+
+/// Parses the `#id` at the start of a block, without validating the rest
+pub(crate) fn block_id(b: &[u8]) -> Option<usize> {
+    let digits = b.strip_prefix(b"#")?;
+    let n = digits.iter().take_while(|c| c.is_ascii_digit()).count();
+    std::str::from_utf8(&digits[..n]).ok()?.parse().ok()
+}
+
+/// Checks whether a block's keyword matches, or (for complex instances)
+/// whether any of its keywords match
+pub(crate) fn block_has_keyword(b: &[u8], keyword: &str) -> bool {
+    let rest = match memchr(b'=', b) {
+        Some(i) => &b[skip_ws(b, i + 1)..],
+        None => return false,
+    };
+    if rest.first() == Some(&b'(') {
+        block_keywords(b).contains(&keyword)
+    } else {
+        rest.strip_prefix(keyword.as_bytes())
+            .and_then(|r| r.first())
+            .map(|c| !(c.is_ascii_alphanumeric() || *c == b'_'))
+            .unwrap_or(false)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_keywords() {
+        assert_eq!(block_keywords(b"#1=CARTESIAN_POINT('',(0.,1.));"),
+                   vec!["CARTESIAN_POINT"]);
+        assert_eq!(block_keywords(b"#2=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT('(X',.METRE.));"),
+                   vec!["LENGTH_UNIT", "NAMED_UNIT", "SI_UNIT"]);
+        assert_eq!(block_keywords(b"#3 = ( A ( ) /* C( */ B ( 1 ) ) ;"),
+                   vec!["A", "B"]);
+        assert_eq!(block_keywords(b"garbage"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_block_has_keyword() {
+        assert_eq!(block_id(b"#123=A();"), Some(123));
+        assert_eq!(block_id(b"HEADER;"), None);
+        assert!(block_has_keyword(b"#1= CARTESIAN_POINT ('',());", "CARTESIAN_POINT"));
+        assert!(!block_has_keyword(b"#1=CARTESIAN_POINT('',());", "CARTESIAN"));
+        assert!(block_has_keyword(b"#2=(A()B(1));", "B"));
+        assert!(!block_has_keyword(b"#2=(A()B('C('));", "C"));
+    }
+
+    #[test]
+    fn test_split_blocks() {
+        let data = b"ISO-10303-21;\n/* comment; with semicolon */ HEADER ;
+            #1=PRODUCT('a;b','it'';s',/*;*/'');\r\n  #2=X(); trailing";
+        let blocks = split_blocks(data);
+        assert_eq!(blocks, vec![
+            &b"ISO-10303-21;"[..],
+            b"HEADER ;",
+            b"#1=PRODUCT('a;b','it'';s',/*;*/'');",
+            b"#2=X();",
+        ]);
+        assert!(block_is(blocks[1], "HEADER"));
+        assert!(!block_is(blocks[1], "DATA"));
+    }
+
+    #[test]
+    fn test_split_sections() {
+        let blocks = split_blocks(b"ISO-10303-21;
+            HEADER; FILE_SCHEMA(('A')); ENDSEC;
+            ANCHOR; <widget>=#1; ENDSEC;
+            REFERENCE; #9=<other.stp#bolt>; ENDSEC;
+            DATA('first',('A')); #1=X(); #2=Y(); ENDSEC;
+            DATA ; #3=Z(); ENDSEC;
+            DATA_SECTION_LOOKALIKE; #4=W(); ENDSEC;
+            END-ISO-10303-21;");
+        let s = split_sections(&blocks);
+        assert_eq!(s.header, vec![&b"FILE_SCHEMA(('A'));"[..]]);
+        assert_eq!(s.anchor, vec![&b"<widget>=#1;"[..]]);
+        assert_eq!(s.reference, vec![&b"#9=<other.stp#bolt>;"[..]]);
+        assert_eq!(s.data, vec![&b"#1=X();"[..], b"#2=Y();", b"#3=Z();"]);
+        assert_eq!(s.data_sections, vec![
+            (&b"DATA('first',('A'));"[..], 0..2),
+            (&b"DATA ;"[..], 2..3),
+        ]);
+    }
+
+    #[test]
+    fn test_scan_resume() {
+        // Scanning in two pieces must give the same result as scanning the
+        // whole thing, as long as the second piece starts in the right state
+        let data = b"#1=A('x;''y');/* ; */#2=B(';');";
+        let mut whole = vec![];
+        scan(data, 0, State::Normal, data.len(), &mut whole);
+        for split in 0..data.len() {
+            let mut parts = vec![];
+            let (state, i) = scan(data, 0, State::Normal, split, &mut parts);
+            scan(data, i, state, data.len(), &mut parts);
+            assert_eq!(parts, whole, "split at {}", split);
+        }
+        assert_eq!(whole, vec![13, 30]);
+    }
+
+    #[test]
+    fn test_semicolons_chunked() {
+        // Large enough to be split into chunks with the `parallel` feature,
+        // with plenty of strings and comments straddling chunk boundaries
+        let mut data = Vec::new();
+        let mut i = 0;
+        while data.len() < 5 << 20 {
+            data.extend(format!(
+                "#{}=PRODUCT('a;b''c;{}','/*;',/* ';' {} */'*/;');\n",
+                i, "x".repeat(i % 97), i % 13).bytes());
+            i += 1;
+        }
+        let mut expected = vec![];
+        scan(&data, 0, State::Normal, data.len(), &mut expected);
+        assert_eq!(expected.len(), i);
+        assert_eq!(semicolons(&data), expected);
+    }
+}
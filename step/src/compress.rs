@@ -0,0 +1,128 @@
+//! Transparent decompression of gzip- and zip-compressed STEP files
+//! (`.stp.gz`, `.stpZ` and zipped `.stp` files).  Compression is detected
+//! from the data itself, so file extensions don't matter.
+
+use std::io::{self, Read};
+
+use flate2::read::MultiGzDecoder;
+use zip::ZipArchive;
+
+/// Extensions which identify the model in a zip archive with several files
+const STEP_EXTENSIONS: &[&str] = &["stp", "step", "p21", "stpx"];
+
+/// Checks whether the data begins with the gzip magic number
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&[0x1F, 0x8B])
+}
+
+/// Checks whether the data begins with a zip local file header
+pub fn is_zip(data: &[u8]) -> bool {
+    data.starts_with(b"PK\x03\x04")
+}
+
+/// Checks whether the data is gzip- or zip-compressed
+pub fn is_compressed(data: &[u8]) -> bool {
+    is_gzip(data) || is_zip(data)
+}
+
+/// Largest buffer which is reserved up front when decompressing, since the
+/// expected size is only an estimate (or a claim made by the file itself).
+/// Larger outputs still decompress, growing the buffer as they go.
+const MAX_CAPACITY_HINT: usize = 64 << 20;
+
+/// Decompresses gzip or zip data, returning any other data unchanged.
+///
+/// A zip archive may contain other files (e.g. a readme) alongside the
+/// model, which is the first file with a STEP extension (`.stp`, `.step`,
+/// `.p21` or `.stpx`), or the archive's only file.
+pub fn decompress(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    if is_gzip(&data) {
+        let hint = data.len().saturating_mul(4).min(MAX_CAPACITY_HINT);
+        let mut out = Vec::with_capacity(hint);
+        MultiGzDecoder::new(&data[..]).read_to_end(&mut out)?;
+        Ok(out)
+    } else if is_zip(&data) {
+        unzip(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+fn unzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut archive = ZipArchive::new(io::Cursor::new(data))?;
+    let mut files = vec![];
+    for i in 0..archive.len() {
+        let f = archive.by_index(i)?;
+        if f.is_file() {
+            files.push((i, f.name().to_owned()));
+        }
+    }
+    let is_step = |name: &str| name.rsplit_once('.')
+        .is_some_and(|(_, ext)| STEP_EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e)));
+    let index = match files.iter().find(|(_, name)| is_step(name)) {
+        Some((i, _)) => *i,
+        None if files.len() == 1 => files[0].0,
+        None => return Err(io::Error::new(io::ErrorKind::NotFound,
+                                          "No STEP file in zip archive")),
+    };
+    let mut f = archive.by_index(index)?;
+    // The uncompressed size comes from the archive itself, so it isn't trusted
+    let hint = f.size().min(MAX_CAPACITY_HINT as u64) as usize;
+    let mut out = Vec::with_capacity(hint);
+    f.read_to_end(&mut out)?;
+    Ok(out)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::owned::OwnedStepFile;
+
+    const DATA: &[u8] = include_bytes!("../../examples/cube_hole.step");
+
+    fn zipped(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut z = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        for (name, data) in files {
+            z.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            z.write_all(data).unwrap();
+        }
+        z.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_gzip() {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(DATA).unwrap();
+        let gz = gz.finish().unwrap();
+        assert!(is_gzip(&gz));
+        assert_eq!(decompress(gz.clone()).unwrap(), DATA);
+
+        // The usual loader decompresses before parsing
+        let s = OwnedStepFile::load_lazy(gz).unwrap();
+        assert_eq!(s.data(), DATA);
+        assert!(!s.file().is_empty());
+    }
+
+    #[test]
+    fn test_zip() {
+        let z = zipped(&[("README.txt", b"hello"), ("parts/cube.STP", DATA)]);
+        assert!(is_zip(&z));
+        assert_eq!(decompress(z).unwrap(), DATA);
+
+        // A single file is used whatever its name
+        assert_eq!(decompress(zipped(&[("cube.stpz", DATA)])).unwrap(), DATA);
+
+        let err = decompress(zipped(&[("a.txt", b"a"), ("b.txt", b"b")])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(decompress(b"PK\x03\x04 truncated".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_uncompressed() {
+        assert!(!is_compressed(DATA));
+        assert_eq!(decompress(DATA.to_vec()).unwrap(), DATA);
+    }
+}
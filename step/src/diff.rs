@@ -0,0 +1,455 @@
+//! Structural comparison of two STEP files.
+//!
+//! Exporting the same model twice usually renumbers every entity, so files
+//! are compared by structure instead: each entity is hashed bottom-up
+//! (through [`Entity::upstream`](crate::ap214::Entity::upstream)) from its
+//! own attributes and the hashes of the entities it refers to, so two
+//! entities hash the same if their whole subtrees are equal, regardless of
+//! ids.  Entities with matching hashes are unchanged; the rest are paired
+//! top-down, starting from the outermost changed entities and following
+//! references with the same types, and anything left unpaired was added or
+//! removed.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use crate::{
+    ap214::Product_,
+    blocks::block_keywords,
+    id::Id,
+    param::Parameter,
+    step_file::StepFile,
+    write::entity_records,
+};
+
+/// Result of comparing two files.  Ids in `removed` refer to the old file,
+/// and ids in `added` refer to the new file; both are sorted.
+#[derive(Clone, Debug, Default)]
+pub struct Diff {
+    pub added: Vec<usize>,
+    pub removed: Vec<usize>,
+    /// Pairs of (old, new) ids for entities which exist in both files but
+    /// whose attributes or subtrees differ, sorted by old id
+    pub modified: Vec<(usize, usize)>,
+    /// Pairs of (old, new) ids for identical entities, sorted by old id
+    pub unchanged: Vec<(usize, usize)>,
+}
+
+/// Number of changed entities in one group of a [`Diff`] summary
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Counts {
+    pub added: usize,
+    pub removed: usize,
+    pub modified: usize,
+}
+
+/// Real numbers are compared to this many significant digits, so that
+/// round-off from re-exporting a model isn't reported as a change
+const REAL_DIGITS: usize = 10;
+
+/// Per-file state used while diffing
+struct Side<'s, 'a> {
+    file: &'s StepFile<'a>,
+    /// Hash of each entity including its subtree
+    deep: HashMap<usize, u64>,
+    /// Hash of each entity's type and attributes, ignoring what its
+    /// references point to
+    shallow: HashMap<usize, u64>,
+}
+
+impl<'s, 'a> Side<'s, 'a> {
+    fn new(file: &'s StepFile<'a>) -> Self {
+        file.decode_all();
+        let mut out = Side { file, deep: HashMap::new(), shallow: HashMap::new() };
+        out.hash_all();
+        out
+    }
+
+    /// Computes deep and shallow hashes of every entity, using an explicit
+    /// stack (rather than recursion) to survive long chains of references.
+    /// References which form a cycle are hashed as a placeholder.
+    fn hash_all(&mut self) {
+        let mut visiting = HashSet::new();
+        for (root, _) in self.file.entities() {
+            let mut stack = vec![(root, false)];
+            while let Some((i, expanded)) = stack.pop() {
+                if self.deep.contains_key(&i) {
+                    continue;
+                }
+                if !expanded {
+                    if !visiting.insert(i) {
+                        continue;
+                    }
+                    stack.push((i, true));
+                    if let Some(e) = self.file.get(i) {
+                        for j in e.upstream() {
+                            if !self.deep.contains_key(&j) && !visiting.contains(&j) {
+                                stack.push((j, false));
+                            }
+                        }
+                    }
+                } else {
+                    let deep = self.hash_entity(i, true);
+                    let shallow = self.hash_entity(i, false);
+                    self.deep.insert(i, deep);
+                    self.shallow.insert(i, shallow);
+                    visiting.remove(&i);
+                }
+            }
+        }
+    }
+
+    fn hash_entity(&self, i: usize, deep: bool) -> u64 {
+        let mut h = DefaultHasher::new();
+        let e = match self.file.get(i) {
+            Some(e) => e,
+            None => return 0,
+        };
+        let records = entity_records(e);
+        if records.is_empty() {
+            // Entities which failed to parse are compared by their text,
+            // without the leading `#id=` (so references must match exactly)
+            let b = self.file.source(i).unwrap_or(&[]);
+            b[b.iter().position(|c| *c == b'=').map_or(0, |p| p + 1)..].hash(&mut h);
+        }
+        for r in records {
+            r.keyword.hash(&mut h);
+            for p in r.params.iter() {
+                self.hash_param(p, deep, &mut h);
+            }
+        }
+        h.finish()
+    }
+
+    fn hash_param(&self, p: &Parameter, deep: bool, h: &mut DefaultHasher) {
+        std::mem::discriminant(p).hash(h);
+        match p {
+            Parameter::Integer(i) => i.hash(h),
+            Parameter::Real(f) => if *f == 0.0 {
+                0u8.hash(h) // also catches -0.0
+            } else {
+                format!("{:.*e}", REAL_DIGITS - 1, f).hash(h)
+            },
+            Parameter::String(s) => s.hash(h),
+            Parameter::Enum(s) => s.hash(h),
+            Parameter::Ref(j) => if deep {
+                match self.deep.get(j) {
+                    Some(d) => d.hash(h),
+                    // Either a cycle, an external reference, or a dangling id
+                    None => self.file.reference(*j).map(|r| &r.uri).hash(h),
+                }
+            },
+            Parameter::Typed(t, p) => {
+                t.hash(h);
+                self.hash_param(p, deep, h);
+            },
+            Parameter::List(v) => {
+                v.len().hash(h);
+                for p in v {
+                    self.hash_param(p, deep, h);
+                }
+            },
+            Parameter::Null | Parameter::Derived => (),
+        }
+    }
+
+    fn type_name(&self, i: usize) -> String {
+        type_name(self.file, i)
+    }
+}
+
+/// Maps every entity used by a product's shape to that product's name.
+///
+/// A product's shape is found by following `PRODUCT` ←
+/// `PRODUCT_DEFINITION_FORMATION` ← `PRODUCT_DEFINITION` ←
+/// `PRODUCT_DEFINITION_SHAPE` ← `SHAPE_DEFINITION_REPRESENTATION` →
+/// representation, then through any plain
+/// `SHAPE_REPRESENTATION_RELATIONSHIP` to further representations.
+/// Relationships with a transformation place sub-assemblies, which
+/// belong to their own products.  Shared entities (e.g. units) may have
+/// more than one owner.
+fn owners(f: &StepFile) -> HashMap<usize, BTreeSet<String>> {
+    let upstream_of = |i: usize, keyword: &str| -> Option<usize> {
+        f.get(i)?.upstream().into_iter().find(|j| f.instance_of(*j, keyword))
+    };
+    let mut out: HashMap<usize, BTreeSet<String>> = HashMap::new();
+    for (sdr, _) in f.entities_with_keyword("SHAPE_DEFINITION_REPRESENTATION") {
+        let product = upstream_of(sdr, "PRODUCT_DEFINITION_SHAPE")
+            .and_then(|i| upstream_of(i, "PRODUCT_DEFINITION"))
+            .and_then(|i| upstream_of(i, "PRODUCT_DEFINITION_FORMATION"))
+            .and_then(|i| upstream_of(i, "PRODUCT"))
+            .and_then(|i| f.entity(Id::<Product_>::new(i)));
+        let (product, rep) = match (product, upstream_of(sdr, "REPRESENTATION")) {
+            (Some(p), Some(r)) => (p, r),
+            _ => continue,
+        };
+
+        let mut reps = vec![rep];
+        let mut todo = vec![rep];
+        while let Some(r) = todo.pop() {
+            for k in f.referrers(r) {
+                if f.source(*k).map(block_keywords)
+                    != Some(vec!["SHAPE_REPRESENTATION_RELATIONSHIP"])
+                {
+                    continue;
+                }
+                for j in f.get(*k).map(|e| e.upstream()).unwrap_or_default() {
+                    if !reps.contains(&j) {
+                        reps.push(j);
+                        todo.push(j);
+                    }
+                }
+            }
+        }
+        for i in f.closure(&reps) {
+            out.entry(i).or_default().insert(product.name.0.to_string());
+        }
+    }
+    out
+}
+
+/// Returns the type of an entity, e.g. `CARTESIAN_POINT`, or a list of
+/// types in parentheses for a complex entity
+fn type_name(f: &StepFile, i: usize) -> String {
+    let k = f.source(i).map(block_keywords).unwrap_or_default();
+    if k.len() == 1 {
+        k[0].to_owned()
+    } else {
+        format!("({})", k.join(" "))
+    }
+}
+
+/// Pairs up entities from `xs` and `ys` which have equal keys, preserving
+/// order, and removes them from both lists
+fn pair_by<K: Eq + std::hash::Hash, F, G>(xs: &mut Vec<usize>, ys: &mut Vec<usize>,
+                                          kx: F, ky: G) -> Vec<(usize, usize)>
+    where F: Fn(usize) -> K, G: Fn(usize) -> K
+{
+    let mut free: HashMap<K, Vec<usize>> = HashMap::new();
+    for y in ys.iter().rev() {
+        free.entry(ky(*y)).or_default().push(*y);
+    }
+    let mut out = vec![];
+    xs.retain(|x| match free.get_mut(&kx(*x)).and_then(|v| v.pop()) {
+        Some(y) => {
+            out.push((*x, y));
+            false
+        },
+        None => true,
+    });
+    let used: HashSet<usize> = out.iter().map(|(_, y)| *y).collect();
+    ys.retain(|y| !used.contains(y));
+    out
+}
+
+/// Compares two files by structure, ignoring entity ids
+pub fn diff(old: &StepFile, new: &StepFile) -> Diff {
+    let a = Side::new(old);
+    let b = Side::new(new);
+
+    // Entities with identical subtrees are unchanged
+    let mut xs: Vec<usize> = old.entities().map(|(i, _)| i).collect();
+    let mut ys: Vec<usize> = new.entities().map(|(i, _)| i).collect();
+    let mut unchanged = pair_by(&mut xs, &mut ys, |i| a.deep[&i], |i| b.deep[&i]);
+
+    // Pair the remaining entities top-down.  Each round starts from the
+    // outermost unpaired entities, matching them by type and attributes
+    // (then by type alone), and then pairs up their unpaired references
+    // the same way.
+    let mut modified = vec![];
+    let mut unpaired_a: HashSet<usize> = xs.iter().cloned().collect();
+    let mut unpaired_b: HashSet<usize> = ys.iter().cloned().collect();
+    let pair = |xs: &mut Vec<usize>, ys: &mut Vec<usize>| {
+        let mut out = pair_by(xs, ys,
+            |i| (a.type_name(i), a.shallow[&i]),
+            |i| (b.type_name(i), b.shallow[&i]));
+        out.extend(pair_by(xs, ys, |i| a.type_name(i), |i| b.type_name(i)));
+        out
+    };
+    loop {
+        let outermost = |s: &Side, unpaired: &HashSet<usize>| -> Vec<usize> {
+            let mut v: Vec<usize> = unpaired.iter()
+                .filter(|i| !s.file.referrers(**i).iter().any(|r| unpaired.contains(r)))
+                .cloned()
+                .collect();
+            v.sort_unstable();
+            v
+        };
+        let mut todo = pair(&mut outermost(&a, &unpaired_a),
+                            &mut outermost(&b, &unpaired_b));
+        if todo.is_empty() {
+            break;
+        }
+        while let Some((x, y)) = todo.pop() {
+            if !unpaired_a.remove(&x) || !unpaired_b.remove(&y) {
+                continue;
+            }
+            modified.push((x, y));
+            let children = |s: &Side, unpaired: &HashSet<usize>, i: usize| {
+                let mut v = s.file.get(i).map(|e| e.upstream()).unwrap_or_default();
+                v.retain(|j| unpaired.contains(j));
+                v.dedup();
+                v
+            };
+            todo.extend(pair(&mut children(&a, &unpaired_a, x),
+                             &mut children(&b, &unpaired_b, y)));
+        }
+    }
+
+    // Anything left over was added or removed
+    let mut removed: Vec<usize> = unpaired_a.into_iter().collect();
+    let mut added: Vec<usize> = unpaired_b.into_iter().collect();
+    removed.sort_unstable();
+    added.sort_unstable();
+    modified.sort_unstable();
+    unchanged.sort_unstable();
+    Diff { added, removed, modified, unchanged }
+}
+
+impl Diff {
+    /// Checks whether the two files are structurally identical
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+
+    /// Counts changed entities by type.  Modified entities are listed under
+    /// their new type, which may differ from the old one.
+    pub fn by_type(&self, old: &StepFile, new: &StepFile) -> BTreeMap<String, Counts> {
+        let mut out: BTreeMap<String, Counts> = BTreeMap::new();
+        for i in self.added.iter() {
+            out.entry(type_name(new, *i)).or_default().added += 1;
+        }
+        for i in self.removed.iter() {
+            out.entry(type_name(old, *i)).or_default().removed += 1;
+        }
+        for (_, j) in self.modified.iter() {
+            out.entry(type_name(new, *j)).or_default().modified += 1;
+        }
+        out
+    }
+
+    /// Counts changed entities by the name of the product whose shape uses
+    /// them.  Entities which aren't part of any product's shape (e.g.
+    /// product metadata or presentation) aren't counted.
+    pub fn by_product(&self, old: &StepFile, new: &StepFile) -> BTreeMap<String, Counts> {
+        let a = owners(old);
+        let b = owners(new);
+        let mut out: BTreeMap<String, Counts> = BTreeMap::new();
+        for i in self.added.iter() {
+            for p in b.get(i).into_iter().flatten() {
+                out.entry(p.clone()).or_default().added += 1;
+            }
+        }
+        for i in self.removed.iter() {
+            for p in a.get(i).into_iter().flatten() {
+                out.entry(p.clone()).or_default().removed += 1;
+            }
+        }
+        for (i, j) in self.modified.iter() {
+            let owners: BTreeSet<&String> = a.get(i).into_iter().flatten()
+                .chain(b.get(j).into_iter().flatten())
+                .collect();
+            for p in owners {
+                out.entry(p.clone()).or_default().modified += 1;
+            }
+        }
+        out
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &[u8] = b"DATA;
+#1=CARTESIAN_POINT('',(0.,0.,0.));
+#2=CARTESIAN_POINT('',(1.,0.,0.));
+#3=VERTEX_POINT('',#1);
+#4=VERTEX_POINT('',#2);
+#5=LINE('',#1,#6);
+#6=VECTOR('',#7,1.);
+#7=DIRECTION('',(1.,0.,0.));
+#8=EDGE_CURVE('',#3,#4,#5,.T.);
+ENDSEC;";
+
+    #[test]
+    fn test_renumbered() {
+        let a = StepFile::parse(BASE);
+        let b = StepFile::parse(b"DATA;
+#18=EDGE_CURVE('',#13,#14,#15,.T.);
+#17=DIRECTION('',(1.,0.,0.));
+#16=VECTOR('',#17,1.0000000000001);
+#15=LINE('',#11,#16);
+#14=VERTEX_POINT('',#12);
+#13=VERTEX_POINT('',#11);
+#12=CARTESIAN_POINT('',(1.,0.,0.));
+#11=CARTESIAN_POINT('',(0.,-0.,0.));
+ENDSEC;");
+        let d = diff(&a, &b);
+        assert!(d.is_empty(), "{:?}", d);
+        assert_eq!(d.unchanged.len(), 8);
+        assert!(d.unchanged.contains(&(8, 18)));
+        assert!(d.unchanged.contains(&(1, 11)));
+    }
+
+    #[test]
+    fn test_changes() {
+        let a = StepFile::parse(BASE);
+        // Moves the second point and adds an unrelated point
+        let b = StepFile::parse(b"DATA;
+#1=CARTESIAN_POINT('',(0.,0.,0.));
+#2=CARTESIAN_POINT('',(2.,0.,0.));
+#3=VERTEX_POINT('',#1);
+#4=VERTEX_POINT('',#2);
+#5=LINE('',#1,#6);
+#6=VECTOR('',#7,1.);
+#7=DIRECTION('',(1.,0.,0.));
+#8=EDGE_CURVE('',#3,#4,#5,.T.);
+#9=CARTESIAN_POINT('',(5.,5.,5.));
+ENDSEC;");
+        let d = diff(&a, &b);
+        assert_eq!(d.modified, vec![(2, 2), (4, 4), (8, 8)]);
+        assert_eq!(d.added, vec![9]);
+        assert!(d.removed.is_empty());
+        assert_eq!(d.unchanged.len(), 5);
+
+        let t = d.by_type(&a, &b);
+        assert_eq!(t["CARTESIAN_POINT"], Counts { added: 1, removed: 0, modified: 1 });
+        assert_eq!(t["EDGE_CURVE"], Counts { added: 0, removed: 0, modified: 1 });
+        assert!(!t.contains_key("LINE"));
+
+        // Removing the edge leaves its subtree unchanged
+        let text = std::str::from_utf8(BASE).unwrap()
+            .replace("#8=EDGE_CURVE('',#3,#4,#5,.T.);", "");
+        let c = StepFile::parse(text.as_bytes());
+        let d = diff(&a, &c);
+        assert_eq!(d.removed, vec![8]);
+        assert!(d.added.is_empty() && d.modified.is_empty());
+    }
+
+    #[test]
+    fn test_by_product() {
+        let data = include_bytes!("../../examples/cube_hole.step");
+        let a = StepFile::parse(data);
+        let text = std::str::from_utf8(data).unwrap();
+        let line = text.lines()
+            .find(|l| l.contains("=CARTESIAN_POINT('',(0.,0.,0.))"))
+            .expect("no origin in example");
+        let moved = line.replace("(0.,0.,0.)", "(0.,0.,1.)");
+        let text = text.replacen(line, &moved, 1);
+        let b = StepFile::parse(text.as_bytes());
+
+        let d = diff(&a, &b);
+        assert!(!d.is_empty());
+        assert!(d.added.is_empty() && d.removed.is_empty());
+        assert_eq!(d.modified[0], (d.modified[0].0, d.modified[0].0));
+        assert_eq!(d.unchanged.len() + d.modified.len(), a.len());
+
+        let p = d.by_product(&a, &b);
+        assert_eq!(p.len(), 1);
+        assert!(p.values().next().unwrap().modified > 0);
+    }
+}
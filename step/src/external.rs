@@ -0,0 +1,408 @@
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+use nom::{
+    bytes::complete::is_not,
+    character::complete::char,
+    sequence::delimited,
+};
+
+use crate::{
+    ap214::Entity,
+    id::Id,
+    parse::{IResult, Parse, sym, ws},
+    step_file::StepFile,
+};
+
+/// An entry from an ANCHOR section, which exports a value from this file
+/// under a name, e.g. `<widget> = #12;`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Anchor<'a> {
+    /// Anchor name, without the surrounding angle brackets
+    pub name: &'a str,
+    /// Anchor value, as raw text (usually an instance name like `#12`)
+    pub value: &'a str,
+}
+
+/// An entry from a REFERENCE section, which binds an instance name to an
+/// entity in another file (or elsewhere in this one), e.g.
+/// `#12 = <parts.stp#bolt>;`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reference<'a> {
+    pub id: usize,
+    /// URI of the referenced value, without the surrounding angle brackets
+    pub uri: &'a str,
+}
+
+/// Parses a `<...>` name or URI
+fn angle_bracketed(s: &str) -> IResult<'_, &str> {
+    delimited(sym('<'), is_not(">"), char('>'))(s)
+}
+
+impl<'a> Anchor<'a> {
+    pub(crate) fn from_block(b: &'a [u8]) -> Option<Self> {
+        let s = std::str::from_utf8(b).ok()?;
+        let (s, name) = angle_bracketed(s).ok()?;
+        let (s, _) = sym('=')(s).ok()?;
+        let value = ws(s).strip_suffix(';')?.trim_end();
+        Some(Self { name, value })
+    }
+
+    /// Returns the instance name that this anchor exports, if it's an
+    /// entity rather than a literal value
+    pub fn id(&self) -> Option<usize> {
+        Id::<()>::parse(self.value).ok()
+            .filter(|(rest, _)| rest.is_empty())
+            .map(|(_, i)| i.0)
+    }
+}
+
+impl<'a> Reference<'a> {
+    pub(crate) fn from_block(b: &'a [u8]) -> Option<Self> {
+        let s = std::str::from_utf8(b).ok()?;
+        let (s, id) = Id::<()>::parse(s).ok()?;
+        let (s, _) = sym('=')(s).ok()?;
+        let (s, uri) = angle_bracketed(s).ok()?;
+        sym(';')(s).ok()?;
+        Some(Self { id: id.0, uri })
+    }
+
+    /// Returns the file part of the URI, which is empty for references
+    /// within the same file
+    pub fn file(&self) -> &'a str {
+        self.uri.split('#').next().unwrap_or("")
+    }
+
+    /// Returns the anchor name (after the `#`) of the URI, if present
+    pub fn anchor(&self) -> Option<&'a str> {
+        self.uri.split_once('#').map(|(_, a)| a)
+    }
+
+    /// Returns the path to a local file referenced by this URI, relative to
+    /// the referring file if it's not absolute.  Returns `None` for URIs
+    /// with a scheme other than `file:`, and for references within the same
+    /// file.
+    pub fn local_path(&self) -> Option<&'a str> {
+        let f = self.file();
+        let f = f.strip_prefix("file://").unwrap_or(f);
+        // A scheme is at least two characters, which distinguishes it from
+        // a Windows drive letter
+        let scheme = f.find(':').filter(|i| *i > 1 && f[..*i].chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)));
+        if f.is_empty() || scheme.is_some() {
+            None
+        } else {
+            Some(f)
+        }
+    }
+}
+
+impl<'a> StepFile<'a> {
+    /// Looks up an exported value by name in the file's ANCHOR sections
+    pub fn anchor(&self, name: &str) -> Option<&Anchor<'a>> {
+        self.anchors.binary_search_by(|a| a.name.cmp(name))
+            .ok()
+            .map(|i| &self.anchors[i])
+    }
+
+    /// Looks up an instance name in the file's REFERENCE sections.  Such
+    /// instances aren't stored in the file, so [`get`](Self::get) returns
+    /// `None` for them.
+    pub fn reference(&self, id: usize) -> Option<&Reference<'a>> {
+        self.references.binary_search_by_key(&id, |r| r.id)
+            .ok()
+            .map(|i| &self.references[i])
+    }
+
+    /// Returns every local file named by the file's REFERENCE sections,
+    /// without duplicates.  Relative paths are relative to this file.
+    pub fn external_files(&self) -> Vec<&'a str> {
+        let mut out: Vec<&str> = Vec::new();
+        for f in self.references.iter().filter_map(|r| r.local_path()) {
+            if !out.contains(&f) {
+                out.push(f);
+            }
+        }
+        out
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Error returned when an external reference can't be resolved
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResolveError {
+    /// The file isn't part of the set
+    UnknownFile(PathBuf),
+    /// The id is neither an entity nor a reference in the given file
+    Missing(PathBuf, usize),
+    /// The reference points outside of the local filesystem
+    Remote(String),
+    /// The target file doesn't export the named anchor
+    MissingAnchor(PathBuf, String),
+    /// The anchor exists, but doesn't name an entity instance
+    NotAnInstance(PathBuf, String),
+    /// The chain of references loops back on itself
+    Cycle(PathBuf, usize),
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::UnknownFile(p) =>
+                write!(f, "File {} is not loaded", p.display()),
+            ResolveError::Missing(p, i) =>
+                write!(f, "#{} is not defined in {}", i, p.display()),
+            ResolveError::Remote(u) =>
+                write!(f, "Cannot resolve remote reference <{}>", u),
+            ResolveError::MissingAnchor(p, a) =>
+                write!(f, "Anchor <{}> is not defined in {}", a, p.display()),
+            ResolveError::NotAnInstance(p, a) =>
+                write!(f, "Anchor <{}> in {} is not an instance", a, p.display()),
+            ResolveError::Cycle(p, i) =>
+                write!(f, "Reference #{} in {} is circular", i, p.display()),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+/// An entity found by [`FileSet::resolve`]
+#[derive(Debug)]
+pub struct Resolved<'s, 'a> {
+    /// Path of the file which contains the entity
+    pub path: &'s Path,
+    /// Id of the entity within that file
+    pub id: usize,
+    pub entity: &'s Entity<'a>,
+}
+
+/// Lexically normalizes a path, removing `.` and (where possible) `..`
+/// components, so that paths can be compared without touching the
+/// filesystem
+fn normalize(p: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for c in p.components() {
+        match c {
+            Component::CurDir => (),
+            Component::ParentDir => match out.components().next_back() {
+                Some(Component::Normal(_)) => { out.pop(); },
+                _ => out.push(".."),
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// A set of STEP files which refer to each other's entities through their
+/// REFERENCE and ANCHOR sections, e.g. an assembly split across files.
+///
+/// Files are identified by path, which is used to resolve relative URIs;
+/// the files themselves must already be loaded by the caller.
+#[derive(Debug, Default)]
+pub struct FileSet<'a> {
+    files: Vec<(PathBuf, StepFile<'a>)>,
+}
+
+impl<'a> FileSet<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a file to the set, replacing any file with the same path
+    pub fn insert<P: AsRef<Path>>(&mut self, path: P, file: StepFile<'a>) {
+        let path = normalize(path.as_ref());
+        self.files.retain(|(p, _)| *p != path);
+        self.files.push((path, file));
+    }
+
+    /// Looks up a file by path
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<&StepFile<'a>> {
+        self.find(&normalize(path.as_ref())).map(|(_, f)| f)
+    }
+
+    fn find(&self, path: &Path) -> Option<(&Path, &StepFile<'a>)> {
+        self.files.iter()
+            .find(|(p, _)| p == path)
+            .map(|(p, f)| (p.as_path(), f))
+    }
+
+    /// Returns local files which are referenced by files in the set, but
+    /// which haven't been added to it.  Callers can load these and call
+    /// this function again until it returns an empty list.
+    pub fn missing(&self) -> Vec<PathBuf> {
+        let mut out = Vec::new();
+        for (p, f) in self.files.iter() {
+            let dir = p.parent().unwrap_or_else(|| Path::new(""));
+            for e in f.external_files() {
+                let q = normalize(&dir.join(e));
+                if self.find(&q).is_none() && !out.contains(&q) {
+                    out.push(q);
+                }
+            }
+        }
+        out
+    }
+
+    /// Finds the entity with the given id in a particular file, following
+    /// references (possibly through several files) if the id is bound in
+    /// the file's REFERENCE section.
+    pub fn resolve<P: AsRef<Path>>(&self, path: P, id: usize)
+        -> Result<Resolved<'_, 'a>, ResolveError>
+    {
+        let start = normalize(path.as_ref());
+        let (mut path, mut file) = self.find(&start)
+            .ok_or(ResolveError::UnknownFile(start))?;
+        let mut id = id;
+
+        // Every hop must pass through a distinct reference, so a longer
+        // chain than this must contain a cycle.
+        let max_hops: usize = self.files.iter()
+            .map(|(_, f)| f.references.len())
+            .sum();
+        for _ in 0..=max_hops {
+            if let Some(entity) = file.get(id) {
+                return Ok(Resolved { path, id, entity });
+            }
+            let r = file.reference(id)
+                .ok_or_else(|| ResolveError::Missing(path.to_owned(), id))?;
+
+            if !r.file().is_empty() {
+                let rel = r.local_path()
+                    .ok_or_else(|| ResolveError::Remote(r.uri.to_owned()))?;
+                let dir = path.parent().unwrap_or_else(|| Path::new(""));
+                let target = normalize(&dir.join(rel));
+                let (p, f) = self.find(&target)
+                    .ok_or(ResolveError::UnknownFile(target))?;
+                path = p;
+                file = f;
+            }
+            let name = r.anchor().unwrap_or("");
+            let a = file.anchor(name).ok_or_else(||
+                ResolveError::MissingAnchor(path.to_owned(), name.to_owned()))?;
+            id = a.id().ok_or_else(||
+                ResolveError::NotAnInstance(path.to_owned(), name.to_owned()))?;
+        }
+        Err(ResolveError::Cycle(path.to_owned(), id))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sections() {
+        let s = StepFile::parse(b"ISO-10303-21;
+HEADER;
+FILE_SCHEMA(('AUTOMOTIVE_DESIGN'));
+ENDSEC;
+ANCHOR;
+<origin> = #1;
+<scale> = 2.5;
+ENDSEC;
+REFERENCE;
+#10 = <parts/bolt.stp#shape>;
+#11 = <#origin>;
+#12 = <http://example.com/nut.stp#shape>;
+ENDSEC;
+DATA('points', ('AUTOMOTIVE_DESIGN'));
+#1=CARTESIAN_POINT('',(0.,0.,0.));
+ENDSEC;
+DATA;
+#2=VERTEX_POINT('',#10);
+ENDSEC;
+END-ISO-10303-21;");
+        assert_eq!(s.len(), 2);
+        assert_eq!(s.sections.len(), 2);
+        assert_eq!(s.sections[0].name.as_deref(), Some("points"));
+        assert_eq!(s.sections[0].schemas, vec!["AUTOMOTIVE_DESIGN"]);
+        assert!(s.sections[1].name.is_none());
+        assert_eq!(s.section_of(1), Some(0));
+        assert_eq!(s.section_of(2), Some(1));
+        assert_eq!(s.section_of(10), None);
+
+        assert_eq!(s.anchor("origin").unwrap().id(), Some(1));
+        assert_eq!(s.anchor("scale").unwrap().value, "2.5");
+        assert_eq!(s.anchor("scale").unwrap().id(), None);
+        assert!(s.anchor("missing").is_none());
+
+        let r = s.reference(10).unwrap();
+        assert_eq!(r.file(), "parts/bolt.stp");
+        assert_eq!(r.anchor(), Some("shape"));
+        assert_eq!(s.reference(11).unwrap().local_path(), None);
+        assert_eq!(s.reference(12).unwrap().local_path(), None);
+        assert!(s.get(10).is_none());
+        assert_eq!(s.external_files(), vec!["parts/bolt.stp"]);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let assembly = StepFile::parse(b"ISO-10303-21;
+REFERENCE;
+#10 = <parts/bolt.stp#shape>;
+#11 = <parts/../parts/./bolt.stp#point>;
+#12 = <parts/nut.stp#shape>;
+#13 = <http://example.com/nut.stp#shape>;
+#14 = <#here>;
+#15 = <loop.stp#a>;
+ENDSEC;
+ANCHOR;
+<here> = #1;
+ENDSEC;
+DATA;
+#1=CARTESIAN_POINT('',(0.,0.,0.));
+ENDSEC;");
+        let bolt = StepFile::parse(b"ISO-10303-21;
+ANCHOR;
+<shape> = #3;
+<point> = #4;
+ENDSEC;
+REFERENCE;
+#4 = <../assembly.stp#here>;
+ENDSEC;
+DATA;
+#3=DIRECTION('',(0.,0.,1.));
+ENDSEC;");
+        let looped = StepFile::parse(b"ISO-10303-21;
+ANCHOR;
+<a> = #1;
+ENDSEC;
+REFERENCE;
+#1 = <#a>;
+ENDSEC;");
+
+        let mut set = FileSet::new();
+        set.insert("assembly.stp", assembly);
+        set.insert("parts/bolt.stp", bolt);
+        set.insert("./loop.stp", looped);
+        assert_eq!(set.missing(), vec![PathBuf::from("parts/nut.stp")]);
+
+        let r = set.resolve("assembly.stp", 10).unwrap();
+        assert_eq!(r.path, Path::new("parts/bolt.stp"));
+        assert_eq!(r.id, 3);
+        assert!(matches!(r.entity, Entity::Direction(_)));
+
+        // Follows a chain of references back into the original file
+        let r = set.resolve("assembly.stp", 11).unwrap();
+        assert_eq!(r.path, Path::new("assembly.stp"));
+        assert_eq!(r.id, 1);
+
+        let r = set.resolve("assembly.stp", 14).unwrap();
+        assert_eq!(r.id, 1);
+
+        assert_eq!(set.resolve("assembly.stp", 12).unwrap_err(),
+                   ResolveError::UnknownFile(PathBuf::from("parts/nut.stp")));
+        assert!(matches!(set.resolve("assembly.stp", 13),
+                         Err(ResolveError::Remote(_))));
+        assert!(matches!(set.resolve("assembly.stp", 15),
+                         Err(ResolveError::Cycle(..))));
+        assert!(matches!(set.resolve("assembly.stp", 99),
+                         Err(ResolveError::Missing(_, 99))));
+        assert!(matches!(set.resolve("other.stp", 1),
+                         Err(ResolveError::UnknownFile(_))));
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+use crate::{
+    blocks::block_keywords,
+    reflect::is_a,
+    step_file::StepFile,
+};
+
+impl<'a> StepFile<'a> {
+    /// Checks whether the entity with the given id is an instance of the
+    /// given type (or one of its subtypes), without decoding it
+    pub(crate) fn instance_of(&self, id: usize, ancestor: &str) -> bool {
+        self.source(id)
+            .map(|b| block_keywords(b).iter().any(|k| is_a(k, ancestor)))
+            .unwrap_or(false)
+    }
+
+    /// Returns the ids of the given entities plus everything that they
+    /// refer to, directly or indirectly (through
+    /// [`Entity::upstream`](crate::ap214::Entity::upstream)), in increasing
+    /// order.
+    ///
+    /// Ids which aren't in the file are skipped, unless they're bound to an
+    /// external reference.
+    pub fn closure(&self, roots: &[usize]) -> Vec<usize> {
+        let mut seen = HashSet::new();
+        let mut todo: Vec<usize> = roots.to_vec();
+        while let Some(i) = todo.pop() {
+            if seen.contains(&i) {
+                continue;
+            }
+            if let Some(e) = self.get(i) {
+                todo.extend(e.upstream().into_iter().filter(|j| *j != 0));
+            } else if self.reference(i).is_none() {
+                continue;
+            }
+            seen.insert(i);
+        }
+        let mut out: Vec<usize> = seen.into_iter().collect();
+        out.sort_unstable();
+        out
+    }
+
+    /// Writes a standalone Part 21 file containing the given entities and
+    /// everything that they depend on, e.g. a single part pulled out of an
+    /// assembly.
+    ///
+    /// Besides the [`closure`](Self::closure) of the roots, this includes
+    /// the representation contexts (and their units) of any representation
+    /// which contains an entity from the closure, so that e.g. a lone
+    /// `MANIFOLD_SOLID_BREP` keeps its units.  Representations themselves are
+    /// only included if they're reachable from the roots, so pass a
+    /// representation's id to extract it along with all of its items.
+    pub fn extract<W: Write>(&self, roots: &[usize], out: &mut W) -> io::Result<()> {
+        let closure = self.closure(roots);
+        let mut contexts = vec![];
+        for i in closure.iter() {
+            for r in self.referrers(*i) {
+                if !self.instance_of(*r, "REPRESENTATION") {
+                    continue;
+                }
+                if let Some(e) = self.get(*r) {
+                    contexts.extend(e.upstream()
+                        .into_iter()
+                        .filter(|c| self.instance_of(*c, "REPRESENTATION_CONTEXT")));
+                }
+            }
+        }
+        contexts.extend(closure);
+        self.write_subset(&self.closure(&contexts), out)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ap214::Entity;
+
+    #[test]
+    fn test_extract() {
+        let s = StepFile::parse(include_bytes!("../../examples/cube_hole.step"));
+        let brep = s.entities()
+            .find(|(_, e)| matches!(e, Entity::ManifoldSolidBrep(_)))
+            .unwrap()
+            .0;
+        let closure = s.closure(&[brep]);
+        assert!(closure.contains(&brep));
+        assert!(closure.len() < s.len());
+
+        let mut out = Vec::new();
+        s.extract(&[brep], &mut out).unwrap();
+        let t = StepFile::parse(&out);
+
+        // The extracted file is self-contained: every reference resolves
+        let ids: HashSet<usize> = t.entities().map(|(i, _)| i).collect();
+        for (_, e) in t.entities() {
+            assert!(!matches!(e, Entity::_FailedToParse));
+            for j in e.upstream() {
+                assert!(ids.contains(&j), "#{} is missing", j);
+            }
+        }
+        let count = |k| t.entities_with_keyword(k).count();
+        assert_eq!(count("MANIFOLD_SOLID_BREP"), 1);
+        assert_eq!(count("GEOMETRIC_REPRESENTATION_CONTEXT"), 1);
+        assert!(count("SI_UNIT") > 0);
+        assert_eq!(count("ADVANCED_BREP_SHAPE_REPRESENTATION"), 0);
+        assert!(t.len() > closure.len());
+    }
+}
@@ -0,0 +1,355 @@
+//! Export of the entity graph (entities as nodes, references as edges) for
+//! graph tools, as DOT, GraphML or JSON.
+//!
+//! Whole files are usually too large to look at, so a [`Graph`] is built
+//! from [`GraphOptions`], which select the part of the file to export:
+//! everything reachable from a set of roots (up to some depth), filtered
+//! by type, with small geometric leaves folded into the nodes which use
+//! them.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::{json::json_string, param::Parameter, step_file::StepFile};
+
+/// Leaf entities which [`GraphOptions::collapse_geometry`] folds into the
+/// nodes that refer to them
+pub const GEOMETRY_LEAVES: &[&str] = &["CARTESIAN_POINT", "DIRECTION"];
+
+/// Selects which part of a file is exported.  Type names are matched
+/// including subtypes, e.g. `REPRESENTATION_ITEM` matches every
+/// `CARTESIAN_POINT`.
+#[derive(Clone, Debug, Default)]
+pub struct GraphOptions {
+    /// Ids to start from.  If empty, every entity in the file is included.
+    pub roots: Vec<usize>,
+    /// Maximum number of references to follow from the roots
+    pub max_depth: Option<usize>,
+    /// If not empty, only entities of these types are shown
+    pub include: Vec<String>,
+    /// Entities of these types are never shown
+    pub exclude: Vec<String>,
+    /// Entities of these types aren't shown as nodes; instead, each node
+    /// counts the entities of these types which it refers to
+    pub collapse: Vec<String>,
+}
+
+impl GraphOptions {
+    /// Adds [`GEOMETRY_LEAVES`] to the collapsed types
+    pub fn collapse_geometry(mut self) -> Self {
+        self.collapse.extend(GEOMETRY_LEAVES.iter().map(|s| s.to_string()));
+        self
+    }
+}
+
+/// An entity in the graph
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    pub id: usize,
+    /// Entity type, or a space-separated list for a complex entity
+    pub type_name: String,
+    /// The entity's `name` attribute, if it has a non-empty one
+    pub name: Option<String>,
+    /// Number of references to collapsed entities, by type
+    pub collapsed: BTreeMap<String, usize>,
+}
+
+/// A reference from one entity to another, through the named attribute
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub attribute: &'static str,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GraphFormat {
+    Dot,
+    GraphMl,
+    Json,
+}
+
+impl FromStr for GraphFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dot" | "gv" => Ok(GraphFormat::Dot),
+            "graphml" => Ok(GraphFormat::GraphMl),
+            "json" => Ok(GraphFormat::Json),
+            _ => Err(format!("Unknown graph format '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for GraphFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            GraphFormat::Dot => "dot",
+            GraphFormat::GraphMl => "graphml",
+            GraphFormat::Json => "json",
+        })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl<'a> StepFile<'a> {
+    /// Builds a graph of the part of the file selected by `opts`.  Nodes
+    /// are sorted by id, and edges by source id then attribute order.
+    ///
+    /// References are followed through entities which are hidden by the
+    /// type filters, but edges to and from hidden entities are dropped.
+    pub fn graph(&self, opts: &GraphOptions) -> Graph {
+        let is_any = |i: usize, types: &[String]| types.iter().any(|t| self.instance_of(i, t));
+
+        // Breadth-first search from the roots, recording depth
+        let mut depth: HashMap<usize, usize> = HashMap::new();
+        let mut todo: VecDeque<usize> = if opts.roots.is_empty() {
+            self.sources().map(|(i, _)| i).collect()
+        } else {
+            opts.roots.iter().cloned().collect()
+        };
+        for i in todo.iter() {
+            depth.insert(*i, 0);
+        }
+        while let Some(i) = todo.pop_front() {
+            let d = depth[&i];
+            if opts.max_depth.is_some_and(|m| d >= m) || is_any(i, &opts.collapse) {
+                continue;
+            }
+            for j in self.get(i).map(|e| e.upstream()).unwrap_or_default() {
+                if j != 0 && self.source(j).is_some() && !depth.contains_key(&j) {
+                    depth.insert(j, d + 1);
+                    todo.push_back(j);
+                }
+            }
+        }
+
+        let shown = |i: usize| {
+            !is_any(i, &opts.collapse)
+                && (opts.include.is_empty() || is_any(i, &opts.include))
+                && !is_any(i, &opts.exclude)
+        };
+        let mut ids: Vec<usize> = depth.keys().cloned().filter(|i| shown(*i)).collect();
+        ids.sort_unstable();
+        let id_set: HashSet<usize> = ids.iter().cloned().collect();
+
+        let mut out = Graph::default();
+        for i in ids {
+            let e = match self.get(i) {
+                Some(e) => e,
+                None => continue,
+            };
+            let mut node = Node {
+                id: i,
+                type_name: e.keywords().join(" "),
+                name: match e.attribute("name") {
+                    Some(Parameter::String(s)) if !s.is_empty() => Some(s.into_owned()),
+                    _ => None,
+                },
+                collapsed: BTreeMap::new(),
+            };
+            let mut seen = HashSet::new();
+            for (attribute, p) in e.attributes() {
+                let mut refs = vec![];
+                p.append_refs(&mut refs);
+                for j in refs {
+                    if id_set.contains(&j) {
+                        if seen.insert((j, attribute)) {
+                            out.edges.push(Edge { from: i, to: j, attribute });
+                        }
+                    } else if depth.contains_key(&j) && is_any(j, &opts.collapse) {
+                        let t = self.get(j).map(|e| e.keywords().join(" "))
+                            .unwrap_or_default();
+                        *node.collapsed.entry(t).or_default() += 1;
+                    }
+                }
+            }
+            out.nodes.push(node);
+        }
+        out
+    }
+}
+
+/// Escapes a string for use in XML text or attributes
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+impl Node {
+    /// Returns a one-line description, e.g. `#12 PRODUCT 'Bolt'`
+    pub fn label(&self) -> String {
+        let mut out = format!("#{} {}", self.id, self.type_name);
+        if let Some(n) = &self.name {
+            out += &format!(" '{}'", n);
+        }
+        for (t, n) in self.collapsed.iter() {
+            out += &format!(" +{} {}", n, t);
+        }
+        out
+    }
+}
+
+impl Graph {
+    pub fn write<W: Write>(&self, format: GraphFormat, out: &mut W) -> io::Result<()> {
+        match format {
+            GraphFormat::Dot => self.write_dot(out),
+            GraphFormat::GraphMl => self.write_graphml(out),
+            GraphFormat::Json => self.write_json(out),
+        }
+    }
+
+    pub fn write_dot<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "digraph {{")?;
+        for n in self.nodes.iter() {
+            writeln!(out, "  e{} [ label = {} ];", n.id, json_string(&n.label()))?;
+        }
+        for e in self.edges.iter() {
+            writeln!(out, "  e{} -> e{} [ label = \"{}\" ];", e.from, e.to, e.attribute)?;
+        }
+        writeln!(out, "}}")
+    }
+
+    pub fn write_graphml<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>
+<graphml xmlns="http://graphml.graphdrawing.org/xmlns">
+  <key id="type" for="node" attr.name="type" attr.type="string"/>
+  <key id="name" for="node" attr.name="name" attr.type="string"/>
+  <key id="label" for="node" attr.name="label" attr.type="string"/>
+  <key id="attribute" for="edge" attr.name="attribute" attr.type="string"/>
+  <graph id="G" edgedefault="directed">"#)?;
+        for n in self.nodes.iter() {
+            writeln!(out, r#"    <node id="e{}">"#, n.id)?;
+            writeln!(out, r#"      <data key="type">{}</data>"#, xml_escape(&n.type_name))?;
+            if let Some(name) = &n.name {
+                writeln!(out, r#"      <data key="name">{}</data>"#, xml_escape(name))?;
+            }
+            writeln!(out, r#"      <data key="label">{}</data>"#, xml_escape(&n.label()))?;
+            writeln!(out, "    </node>")?;
+        }
+        for e in self.edges.iter() {
+            writeln!(out, r#"    <edge source="e{}" target="e{}"><data key="attribute">{}</data></edge>"#,
+                     e.from, e.to, e.attribute)?;
+        }
+        writeln!(out, "  </graph>\n</graphml>")
+    }
+
+    pub fn write_json<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "{{\n  \"nodes\": [")?;
+        for (i, n) in self.nodes.iter().enumerate() {
+            write!(out, "    {{\"id\": {}, \"type\": {}", n.id, json_string(&n.type_name))?;
+            if let Some(name) = &n.name {
+                write!(out, ", \"name\": {}", json_string(name))?;
+            }
+            if !n.collapsed.is_empty() {
+                let c: Vec<String> = n.collapsed.iter()
+                    .map(|(t, n)| format!("{}: {}", json_string(t), n))
+                    .collect();
+                write!(out, ", \"collapsed\": {{{}}}", c.join(", "))?;
+            }
+            writeln!(out, "}}{}", if i + 1 < self.nodes.len() { "," } else { "" })?;
+        }
+        writeln!(out, "  ],\n  \"edges\": [")?;
+        for (i, e) in self.edges.iter().enumerate() {
+            writeln!(out, "    {{\"from\": {}, \"to\": {}, \"attribute\": {}}}{}",
+                     e.from, e.to, json_string(e.attribute),
+                     if i + 1 < self.edges.len() { "," } else { "" })?;
+        }
+        writeln!(out, "  ]\n}}")
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"DATA;
+#1=CARTESIAN_POINT('',(0.,0.,0.));
+#2=DIRECTION('',(0.,0.,1.));
+#3=DIRECTION('',(1.,0.,0.));
+#4=AXIS2_PLACEMENT_3D('',#1,#2,#3);
+#5=PLANE('floor',#4);
+#6=VERTEX_POINT('',#1);
+#7=ADVANCED_FACE('',(),#5,.T.);
+ENDSEC;";
+
+    #[test]
+    fn test_graph() {
+        let s = StepFile::parse(DATA);
+        let g = s.graph(&GraphOptions::default());
+        assert_eq!(g.nodes.len(), 7);
+        assert_eq!(g.edges.len(), 6);
+        assert!(g.edges.contains(&Edge { from: 4, to: 3, attribute: "ref_direction" }));
+
+        // Roots and depth
+        let g = s.graph(&GraphOptions { roots: vec![7], max_depth: Some(1),
+                                        ..Default::default() });
+        assert_eq!(g.nodes.iter().map(|n| n.id).collect::<Vec<_>>(), vec![5, 7]);
+        assert_eq!(g.nodes[0].name.as_deref(), Some("floor"));
+        assert_eq!(g.nodes[0].label(), "#5 PLANE 'floor'");
+
+        // Collapsed leaves are counted on the nodes which use them
+        let g = s.graph(&GraphOptions { roots: vec![7], ..Default::default() }
+                        .collapse_geometry());
+        assert_eq!(g.nodes.iter().map(|n| n.id).collect::<Vec<_>>(), vec![4, 5, 7]);
+        assert_eq!(g.nodes[0].label(), "#4 AXIS2_PLACEMENT_3D +1 CARTESIAN_POINT +2 DIRECTION");
+        assert_eq!(g.edges.len(), 2);
+
+        // Type filters, including subtypes
+        let g = s.graph(&GraphOptions {
+            include: vec!["REPRESENTATION_ITEM".to_owned()],
+            exclude: vec!["PLACEMENT".to_owned(), "POINT".to_owned()],
+            ..Default::default()
+        });
+        assert_eq!(g.nodes.iter().map(|n| n.id).collect::<Vec<_>>(), vec![2, 3, 5, 6, 7]);
+        assert_eq!(g.edges, vec![Edge { from: 7, to: 5, attribute: "face_geometry" }]);
+    }
+
+    #[test]
+    fn test_graph_formats() {
+        let s = StepFile::parse(DATA);
+        let g = s.graph(&GraphOptions { roots: vec![5], ..Default::default() }
+                        .collapse_geometry());
+        let write = |f: GraphFormat| {
+            let mut out = vec![];
+            g.write(f, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(write(GraphFormat::Dot), r##"digraph {
+  e4 [ label = "#4 AXIS2_PLACEMENT_3D +1 CARTESIAN_POINT +2 DIRECTION" ];
+  e5 [ label = "#5 PLANE 'floor'" ];
+  e5 -> e4 [ label = "position" ];
+}
+"##);
+        assert_eq!(write(GraphFormat::Json), r#"{
+  "nodes": [
+    {"id": 4, "type": "AXIS2_PLACEMENT_3D", "collapsed": {"CARTESIAN_POINT": 1, "DIRECTION": 2}},
+    {"id": 5, "type": "PLANE", "name": "floor"}
+  ],
+  "edges": [
+    {"from": 5, "to": 4, "attribute": "position"}
+  ]
+}
+"#);
+        let xml = write(GraphFormat::GraphMl);
+        assert!(xml.contains(r#"<edge source="e5" target="e4"><data key="attribute">position</data></edge>"#));
+        assert!(xml.contains(r##"<data key="label">#5 PLANE &apos;floor&apos;</data>"##));
+
+        assert_eq!("GraphML".parse(), Ok(GraphFormat::GraphMl));
+        assert!("svg".parse::<GraphFormat>().is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use std::borrow::Cow;
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    combinator::map,
+    sequence::{delimited, pair, tuple},
+};
+
+use crate::parse::{IResult, Parse, keyword, sym, ws};
+
+/// `FILE_DESCRIPTION` entry from the HEADER section
+#[derive(Debug, Default)]
+pub struct FileDescription<'a> {
+    pub description: Vec<Cow<'a, str>>,
+    pub implementation_level: Cow<'a, str>,
+}
+impl<'a> Parse<'a> for FileDescription<'a> {
+    fn parse(s: &'a str) -> IResult<'a, Self> {
+        map(delimited(
+                keyword("FILE_DESCRIPTION"),
+                tuple((Vec::<Cow<str>>::parse, sym(','), <Cow<str>>::parse)),
+                pair(sym(')'), sym(';'))),
+            |(description, _, implementation_level)| Self {
+                description, implementation_level,
+            })(s)
+    }
+}
+
+/// `FILE_NAME` entry from the HEADER section
+#[derive(Debug, Default)]
+pub struct FileName<'a> {
+    pub name: Cow<'a, str>,
+    pub time_stamp: Cow<'a, str>,
+    pub author: Vec<Cow<'a, str>>,
+    pub organization: Vec<Cow<'a, str>>,
+    pub preprocessor_version: Cow<'a, str>,
+    pub originating_system: Cow<'a, str>,
+    pub authorization: Cow<'a, str>,
+}
+impl<'a> Parse<'a> for FileName<'a> {
+    fn parse(s: &'a str) -> IResult<'a, Self> {
+        map(delimited(
+                keyword("FILE_NAME"),
+                tuple((
+                    <Cow<str>>::parse, sym(','),
+                    <Cow<str>>::parse, sym(','),
+                    Vec::<Cow<str>>::parse, sym(','),
+                    Vec::<Cow<str>>::parse, sym(','),
+                    <Cow<str>>::parse, sym(','),
+                    <Cow<str>>::parse, sym(','),
+                    <Cow<str>>::parse)),
+                pair(sym(')'), sym(';'))),
+            |(name, _, time_stamp, _, author, _, organization, _,
+              preprocessor_version, _, originating_system, _, authorization)|
+            Self {
+                name, time_stamp, author, organization,
+                preprocessor_version, originating_system, authorization,
+            })(s)
+    }
+}
+
+/// `FILE_SCHEMA` entry from the HEADER section
+#[derive(Debug, Default)]
+pub struct FileSchema<'a> {
+    pub schema_identifiers: Vec<Cow<'a, str>>,
+}
+impl<'a> Parse<'a> for FileSchema<'a> {
+    fn parse(s: &'a str) -> IResult<'a, Self> {
+        map(delimited(keyword("FILE_SCHEMA"), Vec::<Cow<str>>::parse, pair(sym(')'), sym(';'))),
+            |schema_identifiers| Self { schema_identifiers })(s)
+    }
+}
+
+/// Opening of a DATA section.  Edition 3 files may have several DATA
+/// sections, each with a name and its own list of schemas, e.g.
+/// `DATA('geometry',('AP214'));`.  Earlier editions only have a single
+/// unnamed section (`DATA;`), which uses the schemas from `FILE_SCHEMA`.
+#[derive(Debug, Default)]
+pub struct DataSection<'a> {
+    pub name: Option<Cow<'a, str>>,
+    pub schemas: Vec<Cow<'a, str>>,
+
+    /// Address range which contains the source text of every entity in the
+    /// section, used to check which section a particular entity belongs to
+    pub(crate) span: std::ops::Range<usize>,
+}
+impl<'a> Parse<'a> for DataSection<'a> {
+    fn parse(s: &'a str) -> IResult<'a, Self> {
+        alt((
+            map(delimited(
+                    keyword("DATA"),
+                    tuple((<Cow<str>>::parse, sym(','), Vec::<Cow<str>>::parse)),
+                    pair(sym(')'), sym(';'))),
+                |(name, _, schemas)| Self {
+                    name: Some(name), schemas, span: 0..0,
+                }),
+            map(pair(tag("DATA"), sym(';')), |_| Self::default()),
+        ))(ws(s))
+    }
+}
+
+/// Typed representation of a STEP file's HEADER section.
+///
+/// Any of the three mandatory entries which are missing or malformed are
+/// left as `None`; other header entities are ignored.
+#[derive(Debug, Default)]
+pub struct StepHeader<'a> {
+    pub description: Option<FileDescription<'a>>,
+    pub name: Option<FileName<'a>>,
+    pub schema: Option<FileSchema<'a>>,
+}
+
+impl<'a> StepHeader<'a> {
+    /// Builds a header from the blocks between `HEADER;` and `ENDSEC;`
+    pub(crate) fn from_blocks(blocks: &[&'a [u8]]) -> Self {
+        let mut out = Self::default();
+        for b in blocks {
+            let s = match std::str::from_utf8(b) {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            if let Ok((_, d)) = FileDescription::parse(s) {
+                out.description = Some(d);
+            } else if let Ok((_, n)) = FileName::parse(s) {
+                out.name = Some(n);
+            } else if let Ok((_, f)) = FileSchema::parse(s) {
+                out.schema = Some(f);
+            }
+        }
+        out
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_header() {
+        let h = StepHeader::from_blocks(&[
+            b"FILE_DESCRIPTION(('STEP AP214'),'2;1');",
+            b"FILE_NAME('part','2021-04-02T18:07:35+00:00',('Jos\\X2\\00E9\\X0\\'),(''),'ST-DEVELOPER v18.1','SolidWorks',$);",
+            b"FILE_SCHEMA (
+                /* comment */ ( 'AUTOMOTIVE_DESIGN { 1 0 10303 214 3 1 1 }' ) ) ;",
+        ]);
+        let d = h.description.unwrap();
+        assert_eq!(d.description, vec!["STEP AP214"]);
+        assert_eq!(d.implementation_level, "2;1");
+
+        let n = h.name.unwrap();
+        assert_eq!(n.name, "part");
+        assert_eq!(n.author, vec!["Jos\u{e9}"]);
+        assert_eq!(n.originating_system, "SolidWorks");
+        assert_eq!(n.authorization, "");
+
+        let s = h.schema.unwrap();
+        assert_eq!(s.schema_identifiers, vec!["AUTOMOTIVE_DESIGN { 1 0 10303 214 3 1 1 }"]);
+    }
+
+    #[test]
+    fn test_parse_data_section() {
+        let (_, d) = DataSection::parse("DATA ( 'parts' , ('AP214','AP242') ) ;").unwrap();
+        assert_eq!(d.name.unwrap(), "parts");
+        assert_eq!(d.schemas, vec!["AP214", "AP242"]);
+
+        let (_, d) = DataSection::parse("DATA;").unwrap();
+        assert!(d.name.is_none());
+        assert!(d.schemas.is_empty());
+    }
+}
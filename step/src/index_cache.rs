@@ -0,0 +1,318 @@
+//! Binary index caches of a [`StepFile`], which store the result of splitting
+//! a Part 21 file into instances.
+//!
+//! An index cache is *not* a snapshot of parsed entities: it stores the
+//! Part 21 source text of every entity back-to-back, along with a table of
+//! ids and offsets, and entities are decoded from that text on first access.
+//! Loading one replaces [`StepFile::parse_lazy`]'s scan of the file with a
+//! table lookup, but that scan is already cheap, so loading a cache takes
+//! about as long as `parse_lazy` and decoding costs the same afterwards.  It
+//! does not make reopening a fully-parsed file faster; that would need the
+//! decoded entities themselves to be stored, which this format doesn't do.
+//! The `step_index` example measures each step on a given file.
+//!
+//! All integers are little-endian and read byte-wise, so a cache can be used
+//! directly from a memory-mapped file.
+//!
+//! The layout is
+//! ```text
+//! magic           b"STEPIDX1"
+//! version         u32
+//! section count   u32
+//! meta length     u64
+//! text length     u64
+//! entity count    u64
+//! sections        [u64; section count]     number of entities in each
+//! table           [(u64, u64); entity count]  id and end offset in text
+//! meta            [u8; meta length]        HEADER, ANCHOR and REFERENCE
+//!                                          sections and DATA openers, as
+//!                                          Part 21 text
+//! text            [u8; text length]        entities, in table order
+//! ```
+//! Entities in the table are grouped by DATA section, then sorted by id.
+
+use std::convert::TryInto;
+use std::io::{self, Write};
+
+use crate::{
+    blocks::{split_blocks, split_sections},
+    step_file::{Entry, StepFile},
+    write::header_records,
+};
+
+const MAGIC: &[u8; 8] = b"STEPIDX1";
+const VERSION: u32 = 1;
+
+/// Checks whether a buffer begins with the index cache magic number
+pub fn is_index_cache(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid index cache: {}", msg))
+}
+
+/// Cursor for reading little-endian integers from an index cache
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let end = self.pos.checked_add(n)
+            .filter(|e| *e <= self.data.len())
+            .ok_or_else(|| invalid("unexpected end of data"))?;
+        let out = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(out)
+    }
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+    fn usize(&mut self) -> io::Result<usize> {
+        self.u64()?.try_into().map_err(|_| invalid("value out of range"))
+    }
+}
+
+impl<'a> StepFile<'a> {
+    /// Writes a binary index cache of the file, which can be loaded with
+    /// [`from_index_cache`](Self::from_index_cache).
+    ///
+    /// Unlike [`write`](Self::write), entities keep their original ids and
+    /// source text, so entities don't need to be decoded first.
+    pub fn write_index_cache<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut meta = Vec::new();
+        writeln!(meta, "ISO-10303-21;\nHEADER;")?;
+        for r in header_records(&self.header) {
+            writeln!(meta, "{};", r)?;
+        }
+        writeln!(meta, "ENDSEC;")?;
+        if !self.anchors.is_empty() {
+            writeln!(meta, "ANCHOR;")?;
+            for a in self.anchors.iter() {
+                writeln!(meta, "<{}>={};", a.name, a.value)?;
+            }
+            writeln!(meta, "ENDSEC;")?;
+        }
+        if !self.references.is_empty() {
+            writeln!(meta, "REFERENCE;")?;
+            for r in self.references.iter() {
+                writeln!(meta, "#{}=<{}>;", r.id, r.uri)?;
+            }
+            writeln!(meta, "ENDSEC;")?;
+        }
+
+        // Every entity comes from a DATA section, but files without any
+        // sections are still written with a single (empty) one
+        let sections = std::cmp::max(self.sections.len(), 1);
+        let mut entities: Vec<(usize, usize, &[u8])> = self.sources()
+            .map(|(i, b)| (self.section_of(i).unwrap_or(0), i, b))
+            .collect();
+        entities.sort_by_key(|(s, i, _)| (*s, *i));
+
+        let mut counts = vec![0u64; sections];
+        for s in 0..sections {
+            self.write_data_section(Some(s), &mut meta)?;
+            writeln!(meta, "ENDSEC;")?;
+        }
+        writeln!(meta, "END-ISO-10303-21;")?;
+
+        let mut end = 0u64;
+        let mut table = Vec::with_capacity(entities.len() * 16);
+        for (s, i, b) in entities.iter() {
+            counts[*s] += 1;
+            end += b.len() as u64;
+            table.extend((*i as u64).to_le_bytes());
+            table.extend(end.to_le_bytes());
+        }
+
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&(sections as u32).to_le_bytes())?;
+        out.write_all(&(meta.len() as u64).to_le_bytes())?;
+        out.write_all(&end.to_le_bytes())?;
+        out.write_all(&(entities.len() as u64).to_le_bytes())?;
+        for c in counts {
+            out.write_all(&c.to_le_bytes())?;
+        }
+        out.write_all(&table)?;
+        out.write_all(&meta)?;
+        for (_, _, b) in entities.iter() {
+            out.write_all(b)?;
+        }
+        Ok(())
+    }
+
+    /// Loads an index cache written by
+    /// [`write_index_cache`](Self::write_index_cache), borrowing from `data`
+    /// (which may be memory-mapped).  Entities are decoded from their source
+    /// text on first access.
+    pub fn from_index_cache(data: &'a [u8]) -> io::Result<Self> {
+        let mut r = Reader { data, pos: 0 };
+        if r.bytes(MAGIC.len())? != MAGIC {
+            return Err(invalid("bad magic number"));
+        }
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+        let section_count = r.u32()? as usize;
+        let meta_len = r.usize()?;
+        let text_len = r.usize()?;
+        let entity_count = r.usize()?;
+        if entity_count > data.len() / 16 || section_count > data.len() / 8 {
+            return Err(invalid("too many entities"));
+        }
+
+        let counts = (0..section_count)
+            .map(|_| r.usize())
+            .collect::<io::Result<Vec<usize>>>()?;
+        let table = r.bytes(entity_count * 16)?;
+        let meta = r.bytes(meta_len)?;
+        let text = r.bytes(text_len)?;
+        if counts.iter().try_fold(0usize, |a, b| a.checked_add(*b))
+            != Some(entity_count)
+        {
+            return Err(invalid("section sizes don't match entity count"));
+        }
+
+        let mut t = Reader { data: table, pos: 0 };
+        let mut start = 0;
+        let mut entries: Vec<Entry<()>> = Vec::with_capacity(entity_count);
+        for _ in 0..entity_count {
+            let id = t.usize()?;
+            let end = t.usize()?;
+            if end < start || end > text.len() {
+                return Err(invalid("bad entity offset"));
+            }
+            entries.push((id, &text[start..end], ()));
+            start = end;
+        }
+
+        let blocks = split_blocks(meta);
+        let sections = split_sections(&blocks);
+        if sections.data_sections.len() != section_count {
+            return Err(invalid("section count doesn't match metadata"));
+        }
+        let mut out = Self::from_entries(&sections, entries);
+
+        // Entities in each section are contiguous in the text, so the
+        // section spans can be recovered from the table
+        let addr = text.as_ptr() as usize;
+        let mut offset = 0;
+        let mut i = 0;
+        for (s, n) in out.sections.iter_mut().zip(counts) {
+            let end = if n == 0 {
+                offset
+            } else {
+                i += n;
+                let j = (i - 1) * 16 + 8;
+                u64::from_le_bytes(table[j..j + 8].try_into().unwrap()) as usize
+            };
+            s.span = (addr + offset)..(addr + end);
+            offset = end;
+        }
+        Ok(out)
+    }
+
+    /// Loads a file lazily, either from an index cache or (if the data
+    /// doesn't begin with the index cache magic number) from Part 21 text
+    pub fn load_lazy(data: &'a [u8]) -> io::Result<Self> {
+        if is_index_cache(data) {
+            Self::from_index_cache(data)
+        } else {
+            Ok(Self::parse_lazy(data))
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(s: &StepFile) -> String {
+        let mut out = Vec::new();
+        s.write(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_index_cache_round_trip() {
+        let data = include_bytes!("../../examples/cube_hole.step");
+        let a = StepFile::parse(data);
+        let mut cache = Vec::new();
+        a.write_index_cache(&mut cache).unwrap();
+        assert!(is_index_cache(&cache));
+
+        let b = StepFile::load_lazy(&cache).unwrap();
+        assert_eq!(a.len(), b.len());
+        assert_eq!(a.sources().collect::<Vec<_>>(), b.sources().collect::<Vec<_>>());
+        assert_eq!(written(&a), written(&b));
+    }
+
+    #[test]
+    fn test_index_cache_sections() {
+        let a = StepFile::parse(b"ISO-10303-21;
+HEADER;
+FILE_SCHEMA(('AUTOMOTIVE_DESIGN'));
+ENDSEC;
+ANCHOR;
+<origin>=#20;
+ENDSEC;
+REFERENCE;
+#5=<other.stp#bolt>;
+ENDSEC;
+DATA('points',('AP214'));
+#20=CARTESIAN_POINT('',(0.,0.,0.));
+ENDSEC;
+DATA('empty',());
+ENDSEC;
+DATA;
+#3=VERTEX_POINT('',#5);
+#4=CARTESIAN_POINT('',(oops));
+ENDSEC;
+END-ISO-10303-21;");
+        let mut cache = Vec::new();
+        a.write_index_cache(&mut cache).unwrap();
+        let b = StepFile::from_index_cache(&cache).unwrap();
+
+        assert_eq!(b.header.schema.as_ref().unwrap().schema_identifiers,
+                   vec!["AUTOMOTIVE_DESIGN"]);
+        assert_eq!(b.anchor("origin").unwrap().id(), Some(20));
+        assert_eq!(b.reference(5).unwrap().uri, "other.stp#bolt");
+        assert_eq!(b.sections.len(), 3);
+        assert_eq!(b.sections[1].name.as_deref(), Some("empty"));
+        assert_eq!(b.section_of(20), Some(0));
+        assert_eq!(b.section_of(3), Some(2));
+        assert_eq!(b.section_of(4), Some(2));
+        assert!(b.source(4).is_some());
+        assert_eq!(written(&a), written(&b));
+    }
+
+    #[test]
+    fn test_index_cache_errors() {
+        let a = StepFile::parse(include_bytes!("../../examples/cuboid.step"));
+        let mut cache = Vec::new();
+        a.write_index_cache(&mut cache).unwrap();
+
+        // Every truncation must be rejected, rather than panicking
+        for n in (0..cache.len()).step_by(97) {
+            assert!(StepFile::from_index_cache(&cache[..n]).is_err(), "{}", n);
+        }
+        let mut bad = cache.clone();
+        bad[8] = 2;
+        assert!(StepFile::from_index_cache(&bad).is_err());
+
+        // Corrupt the first entity's end offset
+        let mut bad = cache.clone();
+        let table = 40 + 8 * a.sections.len();
+        bad[table + 15] = 0xFF;
+        assert!(StepFile::from_index_cache(&bad).is_err());
+    }
+}
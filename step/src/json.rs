@@ -0,0 +1,690 @@
+//! Lossless JSON representation of a [`StepFile`], for tools which don't
+//! want to parse Part 21.
+//!
+//! The layout is
+//! ```text
+//! {
+//! "header": [{"type": "FILE_DESCRIPTION", "params": [...]}, ...],
+//! "anchors": [{"name": "origin", "value": "#20"}, ...],
+//! "references": [{"id": 5, "uri": "other.stp#bolt"}, ...],
+//! "sections": [{"name": null, "schemas": [], "entities": [
+//!   {"id": 1, "type": "CARTESIAN_POINT", "attributes": {"name": "", "coordinates": [0.0, 0.0, 0.0]}},
+//!   {"id": 2, "parts": [{"type": "LENGTH_UNIT", "attributes": {}}, ...]},
+//!   {"id": 3, "raw": "SOMETHING_UNKNOWN(1,2)"}
+//! ]}]
+//! }
+//! ```
+//! Simple entities list their attributes by name (see
+//! [`Entity::attributes`](crate::ap214::Entity::attributes)), complex
+//! entities list their parts as in Part 21 (each with only the attributes
+//! which that part declares), and entities which couldn't be decoded keep
+//! their Part 21 text.
+//!
+//! Attribute values are encoded as
+//! - integers as JSON numbers without a decimal point or exponent
+//! - reals as JSON numbers with a decimal point or exponent
+//! - strings as JSON strings
+//! - lists as arrays
+//! - `$` as `null`
+//! - references as `{"ref": 12}`
+//! - enumerations (including booleans) as `{"enum": "T"}`
+//! - typed values as `{"type": "LENGTH_MEASURE", "value": 1.0}`
+//! - `*` as `{"derived": true}`
+//!
+//! Entities keep their ids.  Importing goes through Part 21 text, so an
+//! imported file is checked by the same parser as any other.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::{
+    ap214::{Entity, attribute_names},
+    owned::OwnedStepFile,
+    param::{Parameter, Record},
+    reflect::own_attribute_names,
+    step_file::StepFile,
+    write::{entity_records, header_records},
+};
+
+/// Error from reading JSON, either because it's malformed or because it
+/// doesn't describe a valid file
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct JsonError(pub String);
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid JSON: {}", self.0)
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+fn err<T>(msg: String) -> Result<T, JsonError> {
+    Err(JsonError(msg))
+}
+
+/// Writes a string as a JSON string literal
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Export
+
+fn write_param<W: Write>(p: &Parameter, out: &mut W) -> io::Result<()> {
+    match p {
+        Parameter::Integer(i) => write!(out, "{}", i),
+        Parameter::Real(f) if f.is_finite() => write!(out, "{:?}", f),
+        Parameter::Real(_) => write!(out, "null"),
+        Parameter::String(s) => write!(out, "{}", json_string(s)),
+        Parameter::Enum(e) => write!(out, "{{\"enum\": {}}}", json_string(e)),
+        Parameter::Ref(i) => write!(out, "{{\"ref\": {}}}", i),
+        Parameter::Typed(t, p) => {
+            write!(out, "{{\"type\": {}, \"value\": ", json_string(t))?;
+            write_param(p, out)?;
+            write!(out, "}}")
+        },
+        Parameter::List(v) => {
+            write!(out, "[")?;
+            for (i, p) in v.iter().enumerate() {
+                if i > 0 {
+                    write!(out, ", ")?;
+                }
+                write_param(p, out)?;
+            }
+            write!(out, "]")
+        },
+        Parameter::Null => write!(out, "null"),
+        Parameter::Derived => write!(out, "{{\"derived\": true}}"),
+    }
+}
+
+fn write_attributes<W: Write>(r: &Record, names: &[&str], out: &mut W) -> io::Result<()> {
+    write!(out, "\"type\": {}, \"attributes\": {{", json_string(r.keyword))?;
+    for (i, (n, p)) in names.iter().zip(r.params.iter()).enumerate() {
+        if i > 0 {
+            write!(out, ", ")?;
+        }
+        write!(out, "{}: ", json_string(n))?;
+        write_param(p, out)?;
+    }
+    write!(out, "}}")
+}
+
+impl<'a> StepFile<'a> {
+    /// Writes the file as JSON, in the format described in the
+    /// [module documentation](crate::json).  Unlike [`write`](Self::write),
+    /// entities keep their original ids.
+    pub fn write_json<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "{{\n\"header\": [")?;
+        let header = header_records(&self.header);
+        for (i, r) in header.iter().enumerate() {
+            write!(out, "  {{\"type\": {}, \"params\": ", json_string(r.keyword))?;
+            write_param(&Parameter::List(r.params.clone()), out)?;
+            writeln!(out, "}}{}", if i + 1 < header.len() { "," } else { "" })?;
+        }
+
+        writeln!(out, "],\n\"anchors\": [")?;
+        for (i, a) in self.anchors.iter().enumerate() {
+            writeln!(out, "  {{\"name\": {}, \"value\": {}}}{}",
+                     json_string(a.name), json_string(a.value),
+                     if i + 1 < self.anchors.len() { "," } else { "" })?;
+        }
+        writeln!(out, "],\n\"references\": [")?;
+        for (i, r) in self.references.iter().enumerate() {
+            writeln!(out, "  {{\"id\": {}, \"uri\": {}}}{}", r.id, json_string(r.uri),
+                     if i + 1 < self.references.len() { "," } else { "" })?;
+        }
+
+        // As in index caches, files without any sections get an empty one
+        let sections = std::cmp::max(self.sections.len(), 1);
+        let mut entities: Vec<Vec<usize>> = vec![vec![]; sections];
+        for (i, _) in self.sources() {
+            entities[self.section_of(i).unwrap_or(0)].push(i);
+        }
+        writeln!(out, "],\n\"sections\": [")?;
+        for (s, ids) in entities.iter_mut().enumerate() {
+            ids.sort_unstable();
+            let (name, schemas) = match self.sections.get(s) {
+                Some(d) => (d.name.as_deref().map(json_string),
+                            d.schemas.iter().map(|s| json_string(s)).collect()),
+                None => (None, vec![]),
+            };
+            writeln!(out, "  {{\"name\": {}, \"schemas\": [{}], \"entities\": [",
+                     name.as_deref().unwrap_or("null"), schemas.join(", "))?;
+            for (j, i) in ids.iter().enumerate() {
+                write!(out, "    {{\"id\": {}, ", i)?;
+                let records = self.get(*i).map(entity_records).unwrap_or_default();
+                // Untyped entities don't have attribute names, so they're
+                // written as raw text
+                if records.is_empty() || matches!(self.get(*i), Some(Entity::Untyped(_))) {
+                    let b = self.source(*i).unwrap_or(&[]);
+                    let b = &b[b.iter().position(|c| *c == b'=').map_or(0, |p| p + 1)..];
+                    let raw = String::from_utf8_lossy(b);
+                    write!(out, "\"raw\": {}", json_string(raw.trim().trim_end_matches(';').trim_end()))?;
+                } else if matches!(self.get(*i), Some(Entity::ComplexEntity(_))) {
+                    write!(out, "\"parts\": [")?;
+                    for (k, r) in records.iter().enumerate() {
+                        write!(out, "{}{{", if k > 0 { ", " } else { "" })?;
+                        write_attributes(r, &own_attribute_names(r.keyword), out)?;
+                        write!(out, "}}")?;
+                    }
+                    write!(out, "]")?;
+                } else {
+                    write_attributes(&records[0], attribute_names(records[0].keyword), out)?;
+                }
+                writeln!(out, "}}{}", if j + 1 < ids.len() { "," } else { "" })?;
+            }
+            writeln!(out, "  ]}}{}", if s + 1 < sections { "," } else { "" })?;
+        }
+        writeln!(out, "]\n}}")
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Reading JSON
+
+/// A parsed JSON value.  Numbers keep their text, so that integers and
+/// reals can be told apart.
+#[derive(Clone, Debug, PartialEq)]
+enum Json<'a> {
+    Null,
+    Bool(bool),
+    Number(&'a str),
+    String(Cow<'a, str>),
+    Array(Vec<Json<'a>>),
+    Object(Vec<(Cow<'a, str>, Json<'a>)>),
+}
+
+struct Reader<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error<T>(&self, msg: &str) -> Result<T, JsonError> {
+        err(format!("{} at offset {}", msg, self.pos))
+    }
+    fn ws(&mut self) {
+        let b = self.s.as_bytes();
+        while self.pos < b.len() && b[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+    fn peek(&mut self) -> Option<u8> {
+        self.ws();
+        self.s.as_bytes().get(self.pos).copied()
+    }
+    fn expect(&mut self, c: u8) -> Result<(), JsonError> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            self.error(&format!("Expected '{}'", c as char))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json<'a>, JsonError> {
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                let mut out = vec![];
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(out));
+                }
+                loop {
+                    if self.peek() != Some(b'"') {
+                        return self.error("Expected key");
+                    }
+                    let k = self.string()?;
+                    self.expect(b':')?;
+                    out.push((k, self.value()?));
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => { self.pos += 1; return Ok(Json::Object(out)); },
+                        _ => return self.error("Expected ',' or '}'"),
+                    }
+                }
+            },
+            Some(b'[') => {
+                self.pos += 1;
+                let mut out = vec![];
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(out));
+                }
+                loop {
+                    out.push(self.value()?);
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => { self.pos += 1; return Ok(Json::Array(out)); },
+                        _ => return self.error("Expected ',' or ']'"),
+                    }
+                }
+            },
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            _ => {
+                for (word, v) in [("null", Json::Null), ("true", Json::Bool(true)),
+                                  ("false", Json::Bool(false))] {
+                    if self.s[self.pos..].starts_with(word) {
+                        self.pos += word.len();
+                        return Ok(v);
+                    }
+                }
+                self.error("Unexpected input")
+            },
+        }
+    }
+
+    fn number(&mut self) -> Result<Json<'a>, JsonError> {
+        let b = self.s.as_bytes();
+        let start = self.pos;
+        let digits = |pos: &mut usize| {
+            let s = *pos;
+            while *pos < b.len() && b[*pos].is_ascii_digit() {
+                *pos += 1;
+            }
+            *pos > s
+        };
+        let mut pos = self.pos;
+        if b[pos] == b'-' {
+            pos += 1;
+        }
+        let mut ok = digits(&mut pos);
+        if pos < b.len() && b[pos] == b'.' {
+            pos += 1;
+            ok &= digits(&mut pos);
+        }
+        if pos < b.len() && (b[pos] == b'e' || b[pos] == b'E') {
+            pos += 1;
+            if pos < b.len() && (b[pos] == b'+' || b[pos] == b'-') {
+                pos += 1;
+            }
+            ok &= digits(&mut pos);
+        }
+        if !ok {
+            return self.error("Invalid number");
+        }
+        self.pos = pos;
+        Ok(Json::Number(&self.s[start..pos]))
+    }
+
+    fn string(&mut self) -> Result<Cow<'a, str>, JsonError> {
+        self.expect(b'"')?;
+        let start = self.pos;
+        let b = self.s.as_bytes();
+        // Fast path for strings without escapes
+        while self.pos < b.len() && b[self.pos] != b'"' && b[self.pos] != b'\\' {
+            self.pos += 1;
+        }
+        if self.pos < b.len() && b[self.pos] == b'"' {
+            self.pos += 1;
+            return Ok(Cow::Borrowed(&self.s[start..self.pos - 1]));
+        }
+        let mut out = self.s[start..self.pos].to_owned();
+        let mut chars = self.s[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(Cow::Owned(out));
+                },
+                '\\' => {
+                    let c = match chars.next().map(|(_, c)| c) {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            let hex = |chars: &mut std::str::CharIndices| {
+                                let h: String = chars.take(4).map(|(_, c)| c).collect();
+                                u32::from_str_radix(&h, 16).ok().filter(|_| h.len() == 4)
+                            };
+                            let hi = hex(&mut chars);
+                            let code = match hi {
+                                Some(hi @ 0xD800..=0xDBFF) => {
+                                    let lo = match (chars.next(), chars.next()) {
+                                        (Some((_, '\\')), Some((_, 'u'))) => hex(&mut chars),
+                                        _ => None,
+                                    };
+                                    lo.filter(|lo| (0xDC00..=0xDFFF).contains(lo))
+                                        .map(|lo| 0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00))
+                                },
+                                c => c,
+                            };
+                            match code.and_then(char::from_u32) {
+                                Some(c) => c,
+                                None => return self.error("Invalid unicode escape"),
+                            }
+                        },
+                        _ => return self.error("Invalid escape"),
+                    };
+                    out.push(c);
+                },
+                c => out.push(c),
+            }
+        }
+        self.error("Unterminated string")
+    }
+}
+
+fn parse_json(s: &str) -> Result<Json<'_>, JsonError> {
+    let mut r = Reader { s, pos: 0 };
+    let v = r.value()?;
+    match r.peek() {
+        None => Ok(v),
+        Some(_) => r.error("Trailing characters"),
+    }
+}
+
+impl<'a> Json<'a> {
+    fn get(&self, key: &str) -> Option<&Json<'a>> {
+        match self {
+            Json::Object(v) => v.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+    fn field(&self, key: &str, context: &str) -> Result<&Json<'a>, JsonError> {
+        self.get(key).map_or_else(|| err(format!("Missing \"{}\" in {}", key, context)), Ok)
+    }
+    fn as_array(&self, context: &str) -> Result<&[Json<'a>], JsonError> {
+        match self {
+            Json::Array(v) => Ok(v),
+            _ => err(format!("Expected an array for {}", context)),
+        }
+    }
+    fn as_str(&self, context: &str) -> Result<&str, JsonError> {
+        match self {
+            Json::String(s) => Ok(s),
+            _ => err(format!("Expected a string for {}", context)),
+        }
+    }
+    fn as_id(&self, context: &str) -> Result<usize, JsonError> {
+        match self {
+            Json::Number(n) => n.parse().or_else(|_| err(format!("Invalid id {} in {}", n, context))),
+            _ => err(format!("Expected an id for {}", context)),
+        }
+    }
+
+    fn to_param(&self) -> Result<Parameter<'_>, JsonError> {
+        Ok(match self {
+            Json::Null => Parameter::Null,
+            Json::Bool(b) => Parameter::Enum(if *b { "T" } else { "F" }),
+            Json::Number(n) => if n.contains(['.', 'e', 'E']) {
+                Parameter::Real(n.parse().or_else(|_| err(format!("Invalid number {}", n)))?)
+            } else {
+                match n.parse() {
+                    Ok(i) => Parameter::Integer(i),
+                    Err(_) => Parameter::Real(n.parse().or_else(|_| err(format!("Invalid number {}", n)))?),
+                }
+            },
+            Json::String(s) => Parameter::String(Cow::Borrowed(s)),
+            Json::Array(v) => Parameter::List(v.iter().map(Json::to_param).collect::<Result<_, _>>()?),
+            Json::Object(v) => match v.as_slice() {
+                [(k, r)] if k == "ref" => Parameter::Ref(r.as_id("reference")?),
+                [(k, e)] if k == "enum" => Parameter::Enum(e.as_str("enumeration")?),
+                [(k, Json::Bool(true))] if k == "derived" => Parameter::Derived,
+                _ => match (self.get("type"), self.get("value")) {
+                    (Some(t), Some(p)) if v.len() == 2 =>
+                        Parameter::Typed(t.as_str("typed value")?, Box::new(p.to_param()?)),
+                    _ => return err(format!("Unknown value {:?}", self)),
+                },
+            },
+        })
+    }
+
+    /// Builds a record for an entity (or part) with named attributes
+    fn to_record(&self, names: impl Fn(&str) -> Vec<&'static str>) -> Result<Record<'_>, JsonError> {
+        let keyword = self.field("type", "entity")?.as_str("entity type")?;
+        let attributes = match self.field("attributes", keyword)? {
+            Json::Object(v) => v,
+            _ => return err(format!("Expected an object for attributes of {}", keyword)),
+        };
+        let names = names(keyword);
+        if let Some((k, _)) = attributes.iter().find(|(k, _)| !names.contains(&k.as_ref())) {
+            return err(format!("Unknown attribute \"{}\" for {}", k, keyword));
+        }
+        let params = names.iter()
+            .map(|n| match attributes.iter().find(|(k, _)| k == n) {
+                Some((_, v)) => v.to_param(),
+                None => err(format!("Missing attribute \"{}\" for {}", n, keyword)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Record { keyword, params })
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Import
+
+/// Converts JSON (in the format written by [`StepFile::write_json`]) back
+/// into Part 21 text
+pub fn json_to_step(json: &str) -> Result<Vec<u8>, JsonError> {
+    let root = parse_json(json)?;
+    let mut out = Vec::new();
+    let w = |out: &mut Vec<u8>, s: &str| out.extend_from_slice(s.as_bytes());
+
+    w(&mut out, "ISO-10303-21;\nHEADER;\n");
+    for h in root.field("header", "file")?.as_array("header")? {
+        let keyword = h.field("type", "header")?.as_str("header type")?;
+        let params = match h.field("params", keyword)?.to_param()? {
+            Parameter::List(v) => v,
+            _ => return err(format!("Expected an array for params of {}", keyword)),
+        };
+        w(&mut out, &format!("{};\n", Record { keyword, params }));
+    }
+    w(&mut out, "ENDSEC;\n");
+
+    let anchors = root.get("anchors").map(|a| a.as_array("anchors")).transpose()?;
+    if let Some(anchors) = anchors.filter(|a| !a.is_empty()) {
+        w(&mut out, "ANCHOR;\n");
+        for a in anchors {
+            w(&mut out, &format!("<{}>={};\n",
+                                 a.field("name", "anchor")?.as_str("anchor name")?,
+                                 a.field("value", "anchor")?.as_str("anchor value")?));
+        }
+        w(&mut out, "ENDSEC;\n");
+    }
+    let references = root.get("references").map(|a| a.as_array("references")).transpose()?;
+    if let Some(references) = references.filter(|a| !a.is_empty()) {
+        w(&mut out, "REFERENCE;\n");
+        for r in references {
+            w(&mut out, &format!("#{}=<{}>;\n",
+                                 r.field("id", "reference")?.as_id("reference")?,
+                                 r.field("uri", "reference")?.as_str("reference uri")?));
+        }
+        w(&mut out, "ENDSEC;\n");
+    }
+
+    for s in root.field("sections", "file")?.as_array("sections")? {
+        match s.get("name") {
+            Some(Json::String(name)) => {
+                let schemas = s.field("schemas", "section")?.to_param()?;
+                w(&mut out, &format!("DATA({},{});\n",
+                                     Parameter::String(name.clone()), schemas));
+            },
+            _ => w(&mut out, "DATA;\n"),
+        }
+        for e in s.field("entities", "section")?.as_array("entities")? {
+            let id = e.field("id", "entity")?.as_id("entity")?;
+            let text = if let Some(raw) = e.get("raw") {
+                raw.as_str("raw entity")?.to_owned()
+            } else if let Some(parts) = e.get("parts") {
+                let mut records = parts.as_array("parts")?.iter()
+                    .map(|p| p.to_record(own_attribute_names))
+                    .collect::<Result<Vec<_>, _>>()?;
+                records.sort_by_key(|r| r.keyword);
+                let parts: Vec<String> = records.iter().map(|r| r.to_string()).collect();
+                format!("({})", parts.join(""))
+            } else {
+                e.to_record(|k| attribute_names(k).to_vec())?.to_string()
+            };
+            w(&mut out, &format!("#{}={};\n", id, text));
+        }
+        w(&mut out, "ENDSEC;\n");
+    }
+    w(&mut out, "END-ISO-10303-21;\n");
+    Ok(out)
+}
+
+impl OwnedStepFile {
+    /// Loads a file from JSON, in the format written by
+    /// [`StepFile::write_json`]
+    pub fn from_json(json: &str) -> Result<Self, JsonError> {
+        Ok(Self::parse(json_to_step(json)?))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ap214::Entity;
+
+    fn to_json(s: &StepFile) -> String {
+        let mut out = Vec::new();
+        s.write_json(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_parse_json() {
+        assert_eq!(parse_json(r#" {"a": [1, -2.5e3, "x\"é😀", null, true], "b": {}} "#),
+                   Ok(Json::Object(vec![
+                       ("a".into(), Json::Array(vec![
+                           Json::Number("1"), Json::Number("-2.5e3"),
+                           Json::String("x\"é😀".into()), Json::Null, Json::Bool(true)])),
+                       ("b".into(), Json::Object(vec![])),
+                   ])));
+        assert!(parse_json("[1, 2").is_err());
+        assert!(parse_json("[1.]").is_err());
+        assert!(parse_json("\"abc").is_err());
+        assert!(parse_json("{} x").is_err());
+        assert_eq!(json_string("a\"b\\\n\u{1}"), r#""a\"b\\\n\u0001""#);
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        for data in [&include_bytes!("../../examples/cube_hole.step")[..],
+                     &include_bytes!("../../examples/abstract_pca.step")[..]] {
+            let a = StepFile::parse(data);
+            let json = to_json(&a);
+            let b = OwnedStepFile::from_json(&json).unwrap();
+            assert_eq!(json, to_json(b.file()));
+            assert_eq!(a.len(), b.file().len());
+
+            let (mut wa, mut wb) = (vec![], vec![]);
+            a.write(&mut wa).unwrap();
+            b.file().write(&mut wb).unwrap();
+            assert_eq!(wa, wb);
+        }
+    }
+
+    #[test]
+    fn test_json_sections() {
+        let a = StepFile::parse(b"ISO-10303-21;
+HEADER;
+FILE_SCHEMA(('AUTOMOTIVE_DESIGN'));
+ENDSEC;
+ANCHOR;
+<origin>=#20;
+ENDSEC;
+REFERENCE;
+#5=<other.stp#bolt>;
+ENDSEC;
+DATA('points',('AP214'));
+#20=CARTESIAN_POINT('it''s',(0.,1.5E-9,-2.));
+ENDSEC;
+DATA;
+#3=VERTEX_POINT('',#5);
+#4=CARTESIAN_POINT('',(oops));
+#6=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));
+#7=MEASURE_REPRESENTATION_ITEM('',LENGTH_MEASURE(2.),#6);
+ENDSEC;
+END-ISO-10303-21;");
+        let json = to_json(&a);
+        assert!(json.contains(r#"{"id": 20, "type": "CARTESIAN_POINT", "attributes": {"name": "it's", "coordinates": [0.0, 1.5e-9, -2.0]}}"#), "{}", json);
+        assert!(json.contains(r#"{"id": 4, "raw": "CARTESIAN_POINT('',(oops))"}"#));
+        assert!(json.contains(r#""value_component": {"type": "LENGTH_MEASURE", "value": 2.0}"#));
+        assert!(json.contains(r#"{"name": "points", "schemas": ["AP214"], "entities": ["#));
+
+        let b = OwnedStepFile::from_json(&json).unwrap();
+        let b = b.file();
+        assert_eq!(json, to_json(b));
+        assert_eq!(b.anchor("origin").unwrap().id(), Some(20));
+        assert_eq!(b.reference(5).unwrap().uri, "other.stp#bolt");
+        assert_eq!(b.section_of(20), Some(0));
+        assert_eq!(b.section_of(7), Some(1));
+        assert!(matches!(b.get(4), Some(Entity::_FailedToParse)));
+    }
+
+    #[test]
+    fn test_json_complex_units() {
+        let a = StepFile::parse(b"DATA;
+#1=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));
+#2=(NAMED_UNIT(*)PLANE_ANGLE_UNIT()SI_UNIT($,.RADIAN.));
+ENDSEC;");
+        let json = to_json(&a);
+        assert!(json.contains(r#"{"id": 1, "parts": [{"type": "LENGTH_UNIT", "attributes": {}}, "#), "{}", json);
+        assert!(json.contains(r#"{"type": "PLANE_ANGLE_UNIT", "attributes": {}}"#), "{}", json);
+
+        let b = OwnedStepFile::from_json(&json).unwrap();
+        let b = b.file();
+        assert_eq!(json, to_json(b));
+        assert!(b.get(1).unwrap().is_a("LENGTH_UNIT"));
+        assert!(b.get(2).unwrap().is_a("PLANE_ANGLE_UNIT"));
+
+        let (mut wa, mut wb) = (vec![], vec![]);
+        a.write(&mut wa).unwrap();
+        b.write(&mut wb).unwrap();
+        assert_eq!(wa, wb);
+        let out = std::str::from_utf8(&wb).unwrap();
+        assert!(out.contains("#1=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));"), "{}", out);
+        assert!(out.contains("#2=(NAMED_UNIT(*)PLANE_ANGLE_UNIT()SI_UNIT($,.RADIAN.));"), "{}", out);
+    }
+
+    #[test]
+    fn test_json_import() {
+        // Attributes may be given in any order, and booleans are accepted
+        // as logicals
+        let b = OwnedStepFile::from_json(r#"{"header": [], "sections": [{"entities": [
+            {"id": 1, "type": "DIRECTION", "attributes": {"direction_ratios": [1, 0, 0], "name": "x"}},
+            {"id": 2, "type": "ORIENTED_EDGE", "attributes": {"name": "", "edge_start": {"derived": true},
+             "edge_end": {"derived": true}, "edge_element": {"ref": 3}, "orientation": false}}
+        ]}]}"#).unwrap();
+        assert!(matches!(b.file().get(1), Some(Entity::Direction(_))));
+        assert!(matches!(b.file().get(2), Some(Entity::OrientedEdge(_))));
+
+        let bad = |s: &str| json_to_step(&format!(
+            r#"{{"header": [], "sections": [{{"entities": [{}]}}]}}"#, s)).unwrap_err().0;
+        assert_eq!(bad(r#"{"id": 1, "type": "DIRECTION", "attributes": {"name": ""}}"#),
+                   "Missing attribute \"direction_ratios\" for DIRECTION");
+        assert_eq!(bad(r#"{"id": 1, "type": "DIRECTION", "attributes": {"nmae": ""}}"#),
+                   "Unknown attribute \"nmae\" for DIRECTION");
+        assert!(bad(r#"{"id": 1, "type": "DIRECTION", "attributes": {"name": {"x": 1}, "direction_ratios": []}}"#)
+                .starts_with("Unknown value"));
+        assert_eq!(bad(r#"{"type": "DIRECTION"}"#), "Missing \"id\" in entity");
+    }
+}
@@ -1,4 +1,22 @@
 pub mod parse;
 pub mod step_file;
+pub mod header;
 pub mod ap214; // autogenerated!
 pub mod id;
+pub mod param;
+pub mod write;
+pub mod report;
+pub mod referrers;
+pub mod external;
+pub mod index_cache;
+pub mod owned;
+pub mod extract;
+pub mod diff;
+pub mod reflect;
+pub mod query;
+pub mod graph;
+pub mod json;
+pub mod xml;
+pub mod compress;
+pub mod validate;
+mod blocks;
@@ -0,0 +1,152 @@
+use std::io;
+use std::mem::ManuallyDrop;
+
+use crate::{
+    compress::decompress,
+    step_file::StepFile,
+    xml::{is_xml, xml_to_step},
+};
+
+/// A [`StepFile`] which owns its source data, so that it can be stored in a
+/// struct or sent between threads without keeping a buffer alive alongside
+/// it.  Use [`file`](Self::file) to access the parsed file.
+pub struct OwnedStepFile {
+    /// Parsed file, which borrows from `data` (rather than living for
+    /// `'static`, as the type suggests)
+    file: ManuallyDrop<StepFile<'static>>,
+
+    /// Source data, from `Box::into_raw`.  This is never modified or freed
+    /// until `file` has been dropped.
+    data: *mut [u8],
+}
+
+// SAFETY: `data` is immutable and uniquely owned, and the `StepFile` is
+// itself `Send + Sync`, as checked below.
+unsafe impl Send for OwnedStepFile {}
+unsafe impl Sync for OwnedStepFile {}
+const _: fn() = || {
+    fn check<T: Send + Sync>() {}
+    check::<StepFile<'static>>();
+};
+
+impl OwnedStepFile {
+    fn build<E, F>(data: Vec<u8>, f: F) -> Result<Self, E>
+        where F: FnOnce(&'static [u8]) -> Result<StepFile<'static>, E>
+    {
+        let data = Box::into_raw(data.into_boxed_slice());
+        // SAFETY: the allocation stays valid (and unmodified) until `drop`,
+        // and the borrow never escapes except through `file`, which
+        // shortens it to the lifetime of `self`.
+        let slice: &'static [u8] = unsafe { &*data };
+        match f(slice) {
+            Ok(file) => Ok(Self { file: ManuallyDrop::new(file), data }),
+            Err(e) => {
+                // SAFETY: nothing borrows from the data anymore
+                drop(unsafe { Box::from_raw(data) });
+                Err(e)
+            }
+        }
+    }
+
+    /// Parses a STEP file, taking ownership of the data.  See
+    /// [`StepFile::parse`].
+    pub fn parse(data: Vec<u8>) -> Self {
+        Self::build(data, |d| Ok::<_, ()>(StepFile::parse(d))).unwrap()
+    }
+
+    /// Indexes a STEP file without decoding any entities, taking ownership
+    /// of the data.  See [`StepFile::parse_lazy`].
+    pub fn parse_lazy(data: Vec<u8>) -> Self {
+        Self::build(data, |d| Ok::<_, ()>(StepFile::parse_lazy(d))).unwrap()
+    }
+
+    /// Loads a binary index cache, taking ownership of the data.  See
+    /// [`StepFile::from_index_cache`].
+    pub fn from_index_cache(data: Vec<u8>) -> io::Result<Self> {
+        Self::build(data, StepFile::from_index_cache)
+    }
+
+    /// Loads an index cache, Part 21 file or STEP-XML file, lazily.  See
+    /// [`StepFile::load_lazy`]; gzip and zip data are decompressed first (see
+    /// [`decompress`]), and STEP-XML is converted to Part 21 (see
+    /// [`xml_to_step`]).
+    pub fn load_lazy(data: Vec<u8>) -> io::Result<Self> {
+        let data = decompress(data)?;
+        if is_xml(&data) {
+            Ok(Self::parse_lazy(xml_to_step(&data)?))
+        } else {
+            Self::build(data, StepFile::load_lazy)
+        }
+    }
+
+    /// Returns the parsed file
+    pub fn file(&self) -> &StepFile<'_> {
+        // SAFETY: `StepFile<'static>` and `StepFile<'_>` have the same
+        // layout, and the data lives as long as `self`.  Shortening the
+        // lifetime behind a shared reference is only sound because no `&self`
+        // method of `StepFile` stores a borrow of its caller's: `StepFile` is
+        // invariant in `'a` (through the `OnceLock<Entity<'a>>` caches), so
+        // such a method could otherwise leave a shorter-lived borrow in a
+        // file which we still treat as `'static`.  The caches are only ever
+        // filled by decoding the file's own `blocks`, and the referrer index
+        // holds no borrows at all; any new interior mutability in `StepFile`
+        // must keep to this.
+        let p: *const StepFile<'static> = &*self.file;
+        unsafe { &*p.cast::<StepFile<'_>>() }
+    }
+
+    /// Returns the source data
+    pub fn data(&self) -> &[u8] {
+        // SAFETY: see `build`
+        unsafe { &*self.data }
+    }
+}
+
+impl Drop for OwnedStepFile {
+    fn drop(&mut self) {
+        // SAFETY: the file borrows from the data, so it's dropped first
+        unsafe {
+            ManuallyDrop::drop(&mut self.file);
+            drop(Box::from_raw(self.data));
+        }
+    }
+}
+
+impl std::fmt::Debug for OwnedStepFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.file().fmt(f)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ap214::Entity, id::Id};
+
+    #[test]
+    fn test_owned() {
+        let data = b"DATA;
+            #1=DIRECTION('',(1.,0.,0.));
+            #2=CARTESIAN_POINT('',(0.,1.,2.));
+            ENDSEC;".to_vec();
+        let owned = OwnedStepFile::parse_lazy(data);
+
+        // Decode in another thread, then keep using the file in this one
+        let owned = std::thread::spawn(move || {
+            assert!(matches!(owned.file()[Id::<()>::new(1)], Entity::Direction(_)));
+            owned
+        }).join().unwrap();
+        assert_eq!(owned.file().len(), 2);
+        assert!(owned.data().starts_with(b"DATA;"));
+
+        let mut cache = Vec::new();
+        owned.file().write_index_cache(&mut cache).unwrap();
+        drop(owned);
+        let owned = OwnedStepFile::load_lazy(cache).unwrap();
+        assert!(matches!(owned.file().get(2), Some(Entity::CartesianPoint(_))));
+
+        assert!(OwnedStepFile::from_index_cache(b"STEPIDX1".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use std::borrow::Cow;
+use std::fmt;
+use arrayvec::ArrayVec;
+
+use crate::{id::Id, parse::Logical};
+
+/// A single parameter of a Part 21 entity instance, independent of schema
+#[derive(Clone, Debug, PartialEq)]
+pub enum Parameter<'a> {
+    Integer(i64),
+    Real(f64),
+    /// Decoded string, which is re-encoded when written
+    String(Cow<'a, str>),
+    /// Enumeration tag, without the surrounding dots
+    Enum(&'a str),
+    /// Reference to another entity instance
+    Ref(usize),
+    /// Typed parameter, e.g. `LENGTH_MEASURE(1.0)`
+    Typed(&'a str, Box<Parameter<'a>>),
+    List(Vec<Parameter<'a>>),
+    /// Unset value, written as `$`
+    Null,
+    /// Value derived by a subtype, written as `*`
+    Derived,
+}
+
+/// A keyword plus its parameter list, i.e. a simple entity instance or one
+/// part of a complex entity instance
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record<'a> {
+    pub keyword: &'a str,
+    pub params: Vec<Parameter<'a>>,
+}
+
+pub trait ToParam {
+    fn to_param(&self) -> Parameter<'_>;
+}
+
+pub trait ToRecord {
+    fn to_record(&self) -> Record<'_>;
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl Parameter<'_> {
+    /// Applies `f` to every reference in this parameter (recursively)
+    pub fn map_refs<F: Fn(usize) -> Option<usize> + Copy>(&mut self, f: F) {
+        match self {
+            Parameter::Ref(i) => match f(*i) {
+                Some(j) => *i = j,
+                None => *self = Parameter::Null,
+            },
+            Parameter::Typed(_, p) => p.map_refs(f),
+            Parameter::List(v) => for p in v {
+                p.map_refs(f);
+            },
+            _ => (),
+        }
+    }
+
+    /// Appends every reference in this parameter (recursively) to `out`
+    pub fn append_refs(&self, out: &mut Vec<usize>) {
+        match self {
+            Parameter::Ref(i) => out.push(*i),
+            Parameter::Typed(_, p) => p.append_refs(out),
+            Parameter::List(v) => for p in v {
+                p.append_refs(out);
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Writes a real number in Part 21 syntax, which requires a decimal point
+fn write_real(f: &mut fmt::Formatter<'_>, x: f64) -> fmt::Result {
+    let s = format!("{:?}", x);
+    let (mantissa, exponent) = match s.find('e') {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (&s[..], None),
+    };
+    write!(f, "{}", mantissa)?;
+    if !mantissa.contains('.') {
+        write!(f, ".")?;
+    }
+    if let Some(e) = exponent {
+        write!(f, "E{}", e)?;
+    }
+    Ok(())
+}
+
+/// Writes a string literal in Part 21 syntax, escaping quotes and
+/// backslashes and encoding anything outside of printable ASCII with `\X2\`
+/// (or `\X4\`, for characters outside of the Basic Multilingual Plane)
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "'")?;
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => write!(f, "''")?,
+            '\\' => write!(f, "\\\\")?,
+            ' '..='~' => write!(f, "{}", c)?,
+            c => {
+                // Group runs of encoded characters into a single directive
+                let mut run = vec![c];
+                while let Some(c) = chars.next_if(|c| !(' '..='~').contains(c)) {
+                    run.push(c);
+                }
+                if run.iter().all(|c| (*c as u32) < 0x10000) {
+                    write!(f, "\\X2\\")?;
+                    for c in run {
+                        write!(f, "{:04X}", c as u32)?;
+                    }
+                } else {
+                    write!(f, "\\X4\\")?;
+                    for c in run {
+                        write!(f, "{:08X}", c as u32)?;
+                    }
+                }
+                write!(f, "\\X0\\")?;
+            },
+        }
+    }
+    write!(f, "'")
+}
+
+impl fmt::Display for Parameter<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Parameter::Integer(i) => write!(f, "{}", i),
+            Parameter::Real(x) => write_real(f, *x),
+            Parameter::String(s) => write_string(f, s),
+            Parameter::Enum(s) => write!(f, ".{}.", s),
+            Parameter::Ref(i) => write!(f, "#{}", i),
+            Parameter::Typed(t, p) => write!(f, "{}({})", t, p),
+            Parameter::List(v) => {
+                write!(f, "(")?;
+                for (i, p) in v.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", p)?;
+                }
+                write!(f, ")")
+            },
+            Parameter::Null => write!(f, "$"),
+            Parameter::Derived => write!(f, "*"),
+        }
+    }
+}
+
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.keyword)?;
+        for (i, p) in self.params.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", p)?;
+        }
+        write!(f, ")")
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+impl ToParam for f64 {
+    fn to_param(&self) -> Parameter<'_> {
+        Parameter::Real(*self)
+    }
+}
+impl ToParam for i64 {
+    fn to_param(&self) -> Parameter<'_> {
+        Parameter::Integer(*self)
+    }
+}
+impl ToParam for Cow<'_, str> {
+    fn to_param(&self) -> Parameter<'_> {
+        Parameter::String(Cow::Borrowed(self))
+    }
+}
+impl ToParam for bool {
+    fn to_param(&self) -> Parameter<'_> {
+        Parameter::Enum(if *self { "T" } else { "F" })
+    }
+}
+impl ToParam for Logical {
+    fn to_param(&self) -> Parameter<'_> {
+        Parameter::Enum(match self.0 {
+            Some(true) => "T",
+            Some(false) => "F",
+            None => "UNKNOWN",
+        })
+    }
+}
+impl<T> ToParam for Id<T> {
+    fn to_param(&self) -> Parameter<'_> {
+        // NUL ids are deserialized to 0, so we write them back as `$`
+        if self.0 == 0 {
+            Parameter::Null
+        } else {
+            Parameter::Ref(self.0)
+        }
+    }
+}
+impl<T: ToParam> ToParam for Option<T> {
+    fn to_param(&self) -> Parameter<'_> {
+        match self {
+            Some(t) => t.to_param(),
+            None => Parameter::Null,
+        }
+    }
+}
+impl<T: ToParam> ToParam for Vec<T> {
+    fn to_param(&self) -> Parameter<'_> {
+        Parameter::List(self.iter().map(ToParam::to_param).collect())
+    }
+}
+impl<T: ToParam, const CAP: usize> ToParam for ArrayVec<T, CAP> {
+    fn to_param(&self) -> Parameter<'_> {
+        Parameter::List(self.iter().map(ToParam::to_param).collect())
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_write_real() {
+        for (x, s) in [(1.0, "1.0"), (-0.5, "-0.5"), (1e-7, "1.E-7"),
+                       (2.5e20, "2.5E20"), (0.0, "0.0")]
+        {
+            assert_eq!(Parameter::Real(x).to_string(), s);
+        }
+    }
+    #[test]
+    fn test_write_string() {
+        for (x, s) in [("", "''"), ("it's", "'it''s'"), ("a\\b", "'a\\\\b'"),
+                       ("Gr\u{fc}\u{df}e", "'Gr\\X2\\00FC00DF\\X0\\e'"),
+                       ("\u{1F600}", "'\\X4\\0001F600\\X0\\'")]
+        {
+            assert_eq!(Parameter::String(Cow::Borrowed(x)).to_string(), s);
+        }
+    }
+    #[test]
+    fn test_write_record() {
+        let r = Record {
+            keyword: "SI_UNIT",
+            params: vec![Parameter::Derived, Parameter::Enum("MILLI"),
+                         Parameter::List(vec![Parameter::Ref(3),
+                                              Parameter::Null])],
+        };
+        assert_eq!(r.to_string(), "SI_UNIT(*,.MILLI.,(#3,$))");
+    }
+}
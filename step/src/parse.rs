@@ -1,17 +1,26 @@
+use std::borrow::Cow;
 use std::collections::{HashSet, HashMap};
 use nom::{
     branch::{alt},
-    bytes::complete::{is_not, tag},
+    bytes::complete::tag,
     character::complete::{char, digit1},
     combinator::{map, map_res, opt},
     error::*,
     sequence::{delimited, preceded, tuple},
     multi::{separated_list0},
 };
-use memchr::{memchr, memchr3};
+use memchr::memchr;
 use arrayvec::ArrayVec;
 
-use crate::{id::{Id, HasId}, ap214::{Entity, superclasses_of}};
+use crate::{
+    id::{Id, HasId},
+    ap214::{
+        Entity, attribute_kinds, attribute_names, attribute_optional,
+        attribute_owners, enumeration_literal, superclasses_of, type_kind,
+    },
+    param::{Parameter, Record},
+    report::{Fixup, FixupKind},
+};
 
 ////////////////////////////////////////////////////////////////////////////////
 
@@ -27,6 +36,53 @@ pub fn nom_alt_err<'a, U>(s: &'a str) -> IResult<'a, U> {
     nom_err(s, ErrorKind::Alt)
 }
 
+/// Skips whitespace and comments
+pub(crate) fn ws(s: &str) -> &str {
+    &s[skip_ws(s.as_bytes(), 0)..]
+}
+
+/// Returns the index of the first byte at or after `i` which isn't part of
+/// whitespace or a comment.  An unterminated comment runs to the end of the
+/// input.
+pub(crate) fn skip_ws(s: &[u8], mut i: usize) -> usize {
+    loop {
+        match s.get(i) {
+            Some(c) if c.is_ascii_whitespace() => i += 1,
+            Some(b'/') if s.get(i + 1) == Some(&b'*') => {
+                i = comment_end(s, i + 2).unwrap_or(s.len());
+            },
+            _ => return i,
+        }
+    }
+}
+
+/// Given an offset within the body of a comment, returns the offset just
+/// past its closing `*/`
+pub(crate) fn comment_end(s: &[u8], mut i: usize) -> Option<usize> {
+    loop {
+        i += memchr(b'*', &s[i..])?;
+        if s.get(i + 1) == Some(&b'/') {
+            return Some(i + 2);
+        }
+        i += 1;
+    }
+}
+
+/// Parses a single character, skipping any preceding whitespace
+pub(crate) fn sym<'a>(c: char) -> impl Fn(&'a str) -> IResult<'a, char> {
+    move |s| char(c)(ws(s))
+}
+
+/// Parses an entity or type keyword and its opening parenthesis, allowing
+/// for whitespace before and after the keyword
+pub(crate) fn keyword<'a>(k: &'static str) -> impl Fn(&'a str) -> IResult<'a, &'a str> {
+    move |s| {
+        let (s, k) = tag(k)(ws(s))?;
+        let (s, _) = sym('(')(s)?;
+        Ok((s, k))
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct Logical(pub Option<bool>);
 
@@ -42,6 +98,7 @@ pub(crate) trait Parse<'a> {
 
 impl Parse<'_> for f64 {
     fn parse(s: &str) -> IResult<Self> {
+        let s = ws(s);
         match fast_float::parse_partial::<f64, _>(s) {
             Err(_) => nom_err(s, ErrorKind::Float),
             Ok((x, n)) => Ok((&s[n..], x)),
@@ -59,27 +116,36 @@ impl Parse<'_> for i64 {
                 } else {
                     Ok(num)
                 }
-            })(s)
+            })(ws(s))
     }
 }
-impl<'a> Parse<'a> for &'a str {
-    fn parse(s: &'a str) -> IResult<'a, &'a str> {
-        alt((
-            map(delimited(char('\''), opt(is_not("'")), char('\'')),
-                |r| r.unwrap_or("")),
+impl<'a> Parse<'a> for Cow<'a, str> {
+    fn parse(s: &'a str) -> IResult<'a, Cow<'a, str>> {
+        let s = ws(s);
+        if let Some(rest) = s.strip_prefix('\'') {
+            match string_end(rest.as_bytes()) {
+                Some(i) => Ok((&rest[i + 1..], decode_string(&rest[..i]))),
+                None => nom_err(s, ErrorKind::Char),
+            }
+        } else {
             // NUL REF
-            map(char('$'), |_| "")))(s)
+            map(char('$'), |_| Cow::Borrowed(""))(s)
+        }
     }
 }
 
 impl<'a, T: Parse<'a>> Parse<'a> for Vec<T> {
     fn parse(s: &'a str) -> IResult<'a, Vec<T>> {
-        delimited(char('('), separated_list0(char(','), T::parse), char(')'))(s)
+        // Lists aren't modified after parsing, so drop any spare capacity.
+        // On examples/, this saves about 3% of the parsed heap without a
+        // measurable change in parse time (measured with `step_memory`).
+        map(delimited(sym('('), separated_list0(sym(','), T::parse), sym(')')),
+            |mut v| { v.shrink_to_fit(); v })(s)
     }
 }
 impl<'a, T: Parse<'a>, const CAP: usize> Parse<'a> for ArrayVec<T, CAP> {
     fn parse(s: &'a str) -> IResult<'a, ArrayVec<T, CAP>> {
-        let (mut s, _) = char('(')(s)?;
+        let (mut s, _) = sym('(')(s)?;
         let mut out = ArrayVec::new();
         // Based on nom's separated_list0
         let (s_, o) = match T::parse(s) {
@@ -90,7 +156,7 @@ impl<'a, T: Parse<'a>, const CAP: usize> Parse<'a> for ArrayVec<T, CAP> {
         out.push(o);
 
         loop {
-            let (s_, _) = match char(',')(s) {
+            let (s_, _) = match sym(',')(s) {
                 Err(nom::Err::Error(_)) => break,
                 e => e?,
             };
@@ -102,14 +168,14 @@ impl<'a, T: Parse<'a>, const CAP: usize> Parse<'a> for ArrayVec<T, CAP> {
             s = s_;
             out.push(o);
         }
-        let (s, _) = char(')')(s)?;
+        let (s, _) = sym(')')(s)?;
         Ok((s, out))
     }
 }
 impl<'a, T: Parse<'a>> Parse<'a> for Option<T> {
     fn parse(s: &'a str) -> IResult<'a, Self> {
         alt((
-            map(char('$'), |_| None),
+            map(sym('$'), |_| None),
             map(T::parse, |v| Some(v))))(s)
     }
 }
@@ -119,7 +185,7 @@ impl<'a> Parse<'a> for Logical {
             map(tag(".T."), |_| Logical(Some(true))),
             map(tag(".F."), |_| Logical(Some(false))),
             map(tag(".UNKNOWN."), |_| Logical(None)),
-        ))(s)
+        ))(ws(s))
     }
 }
 impl<'a> Parse<'a> for bool {
@@ -127,7 +193,7 @@ impl<'a> Parse<'a> for bool {
         alt((
             map(tag(".T."), |_| true),
             map(tag(".F."), |_| false),
-        ))(s)
+        ))(ws(s))
     }
 }
 impl<'a, T> Parse<'a> for Id<T> {
@@ -138,10 +204,121 @@ impl<'a, T> Parse<'a> for Id<T> {
                 |s: &str| s.parse().map(|i| Id::new(i))),
             // NUL id deserializes to 0
             map(char('$'), |_| Id::empty())))
-            (s)
+            (ws(s))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Given the text following an opening quote, returns the offset of the
+/// closing quote.  A pair of quotes (`''`) is an escaped quote within the
+/// string, rather than its end.
+pub(crate) fn string_end(s: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    loop {
+        i += memchr(b'\'', &s[i..])?;
+        if s.get(i + 1) == Some(&b'\'') {
+            i += 2;
+        } else {
+            return Some(i);
+        }
     }
 }
 
+/// Parses a run of hex digits into a list of values, `width` digits each.
+/// Returns `None` if the text isn't valid hex or isn't a multiple of `width`.
+fn parse_hex_run(s: &str, width: usize) -> Option<Vec<u32>> {
+    if !s.len().is_multiple_of(width) {
+        return None;
+    }
+    (0..s.len()).step_by(width)
+        .map(|i| u32::from_str_radix(s.get(i..i + width)?, 16).ok())
+        .collect()
+}
+
+/// Decodes the body of a Part 21 string literal (without the surrounding
+/// quotes), handling `''`, `\\`, `\S\`, `\X\`, `\X2\`, and `\X4\`
+/// escapes and dropping `\N\` / `\F\` print control directives.
+///
+/// `\S\` and `\X\` are decoded as ISO 8859-1; other code pages selected
+/// with `\P?\` are not supported, and are also decoded as ISO 8859-1.
+/// Malformed escape sequences are passed through unchanged.  Line breaks
+/// are removed, since they aren't significant in Part 21.
+///
+/// This only allocates if the string contains escape sequences or line
+/// breaks.
+pub fn decode_string(s: &str) -> Cow<'_, str> {
+    let special = |s: &str| s.bytes()
+        .position(|c| matches!(c, b'\\' | b'\'' | b'\n' | b'\r'));
+    if special(s).is_none() {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = special(rest) {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let b = rest.as_bytes();
+        let n = if b[0] == b'\n' || b[0] == b'\r' {
+            1
+        } else if b[0] == b'\'' {
+            // The only quotes in a string body are escaped pairs
+            out.push('\'');
+            if b.get(1) == Some(&b'\'') { 2 } else { 1 }
+        } else if rest.starts_with("\\\\") {
+            out.push('\\');
+            2
+        } else if rest.starts_with("\\N\\") || rest.starts_with("\\F\\") {
+            3
+        } else if b.len() >= 4 && b[1] == b'P' && b[3] == b'\\' {
+            4
+        } else if rest.starts_with("\\S\\") && b.len() >= 4 && b[3].is_ascii() {
+            out.push(char::from(b[3] + 128));
+            4
+        } else if rest.starts_with("\\X\\") {
+            let hex = rest.get(3..5)
+                .filter(|h| h.bytes().all(|c| c.is_ascii_hexdigit()));
+            match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                Some(c) => {
+                    out.push(char::from(c));
+                    5
+                },
+                None => 0,
+            }
+        } else if rest.starts_with("\\X2\\") || rest.starts_with("\\X4\\") {
+            let wide = b[2] == b'4';
+            let end = rest[4..].find("\\X0\\");
+            let decoded = end.and_then(|e|
+                parse_hex_run(&rest[4..4 + e], if wide { 8 } else { 4 }));
+            match (end, decoded) {
+                (Some(e), Some(v)) if wide => {
+                    out.extend(v.into_iter().map(|c|
+                        char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER)));
+                    e + 8
+                },
+                (Some(e), Some(v)) => {
+                    let v: Vec<u16> = v.into_iter().map(|c| c as u16).collect();
+                    out.push_str(&String::from_utf16_lossy(&v));
+                    e + 8
+                },
+                _ => 0,
+            }
+        } else {
+            0
+        };
+        // Malformed escapes are copied through literally
+        let n = if n == 0 {
+            out.push('\\');
+            1
+        } else {
+            n
+        };
+        rest = &rest[n..];
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 pub(crate) trait ParseFromChunks<'a> {
@@ -159,7 +336,18 @@ impl<'a, T: ParseFromChunks<'a>> Parse<'a> for T {
 pub struct Derived;
 impl<'a> Parse<'a> for Derived {
     fn parse(s: &str) -> IResult<Self> {
-        map(char('*'), |_| Derived)(s)
+        map(sym('*'), |_| Derived)(s)
+    }
+}
+
+/// An attribute which another part of a complex instance may derive, in which
+/// case it is written as `*` and parsed as `None` (e.g. `NAMED_UNIT.dimensions`
+/// in `LENGTH_UNIT()` next to `SI_UNIT`)
+pub struct Derivable<T>(pub Option<T>);
+impl<'a, T: Parse<'a>> Parse<'a> for Derivable<T> {
+    fn parse(s: &'a str) -> IResult<'a, Self> {
+        alt((map(sym('*'), |_| Derivable(None)),
+             map(T::parse, |v| Derivable(Some(v)))))(s)
     }
 }
 
@@ -172,12 +360,12 @@ impl<'a> Parse<'a> for Derived {
 /// for cases where we're splicing together multiple sets of arguments to build
 /// a complete Entity.
 fn check_str<'a>(s: &'a str, i: &mut usize, strs: &[&'a str]) -> &'a str {
-    if s.is_empty() {
+    let mut s = ws(s);
+    while s.is_empty() && *i + 1 < strs.len() {
         *i += 1;
-        strs.get(*i).unwrap_or(&"")
-    } else {
-        s
+        s = ws(strs[*i]);
     }
+    s
 }
 pub(crate) fn param_from_chunks<'a, T: Parse<'a>>(
     last: bool, s: &'a str,
@@ -186,7 +374,7 @@ pub(crate) fn param_from_chunks<'a, T: Parse<'a>>(
     let s = check_str(s, i, strs);
     let (s, out) = T::parse(s)?;
     let s = check_str(s, i, strs);
-    let (s, _) = char(if last { ')'} else { ',' })(s)?;
+    let (s, _) = sym(if last { ')'} else { ',' })(s)?;
     Ok((check_str(s, i, strs), out))
 }
 
@@ -196,7 +384,7 @@ pub(crate) fn parse_enum_tag(s: &str) -> IResult<&str> {
                   |c: char| c == '_' ||
                             c.is_ascii_uppercase() ||
                             c.is_ascii_digit()),
-              char('.'))(s)
+              char('.'))(ws(s))
 }
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -206,19 +394,130 @@ pub(crate) fn parse_entity_decl(s: &[u8]) -> IResult<(usize, Entity)> {
         Ok(s) => s,
         Err(_) => return nom_err("", ErrorKind::Escaped), // TODO correct code?
     };
-    map(tuple((Id::<()>::parse, char('='), Entity::parse)),
+    map(tuple((Id::<()>::parse, sym('='), Entity::parse)),
         |(i, _, e)| (i.0, e))(s)
 }
 
+/// Parses an entity instance which failed to parse with the schema,
+/// keeping its parameters as [`Entity::Untyped`].  Instances which aren't
+/// valid Part 21 are stored as `Entity::_FailedToParse`; this only fails if
+/// the instance's id can't be read.
 pub(crate) fn parse_entity_fallback(s: &[u8]) -> IResult<(usize, Entity)> {
     let s = match std::str::from_utf8(s) {
         Ok(s) => s,
         Err(_) => return nom_err("", ErrorKind::Escaped),
     };
-    map(Id::<()>::parse, |i| (i.0, Entity::_FailedToParse))(s)
+    let (s, i) = Id::<()>::parse(s)?;
+    match preceded(sym('='), parse_untyped)(s) {
+        Ok((s, e)) => Ok((s, (i.0, e))),
+        Err(_) => Ok((s, (i.0, Entity::_FailedToParse))),
+    }
+}
+
+/// Parses the body of an entity instance (after the `=`) without the
+/// schema, as either a single record or a parenthesized list of records
+fn parse_untyped(s: &str) -> IResult<'_, Entity<'_>> {
+    let (s, records) = if let Ok((mut s, _)) = sym('(')(s) {
+        let mut records = vec![];
+        while let Ok((s_, r)) = Record::parse(s) {
+            records.push(r);
+            s = s_;
+        }
+        if records.is_empty() {
+            return nom_alt_err(s);
+        }
+        let (s, _) = sym(')')(s)?;
+        (s, records)
+    } else {
+        let (s, r) = Record::parse(s)?;
+        (s, vec![r])
+    };
+    let (s, _) = opt(sym(';'))(s)?;
+    let s = ws(s);
+    if !s.is_empty() {
+        return nom_err(s, ErrorKind::Eof);
+    }
+    Ok((s, Entity::Untyped(records)))
+}
+
+/// Parses an entity keyword, e.g. `CARTESIAN_POINT`
+fn parse_keyword(s: &str) -> IResult<'_, &str> {
+    let s = ws(s);
+    let n = s.bytes()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == b'_')
+        .count();
+    if s.as_bytes().first().is_some_and(|c| c.is_ascii_alphabetic() || *c == b'_') {
+        Ok((&s[n..], &s[..n]))
+    } else {
+        nom_err(s, ErrorKind::Alpha)
+    }
+}
+
+impl<'a> Parse<'a> for Record<'a> {
+    fn parse(s: &'a str) -> IResult<'a, Self> {
+        let (s, keyword) = parse_keyword(s)?;
+        let (s, params) = Vec::<Parameter>::parse(s)?;
+        Ok((s, Record { keyword, params }))
+    }
+}
+
+impl<'a> Parse<'a> for Parameter<'a> {
+    fn parse(s: &'a str) -> IResult<'a, Self> {
+        let s = ws(s);
+        match s.as_bytes().first() {
+            Some(b'\'') => map(Cow::parse, Parameter::String)(s),
+            Some(b'#') => map(Id::<()>::parse, |i| Parameter::Ref(i.0))(s),
+            Some(b'$') => Ok((&s[1..], Parameter::Null)),
+            Some(b'*') => Ok((&s[1..], Parameter::Derived)),
+            Some(b'(') => map(Vec::parse, Parameter::List)(s),
+            // Enumeration tags are kept as written, in any case
+            Some(b'.') => map(
+                delimited(char('.'),
+                          nom::bytes::complete::take_while1(
+                              |c: char| c == '_' || c.is_ascii_alphanumeric()),
+                          char('.')),
+                Parameter::Enum)(s),
+            Some(c) if c.is_ascii_digit() || *c == b'-' || *c == b'+' => {
+                let n = s.bytes()
+                    .take_while(|c| c.is_ascii_digit() || b"+-.eE".contains(c))
+                    .count();
+                if s[..n].contains('.') {
+                    map(f64::parse, Parameter::Real)(s)
+                } else {
+                    map(i64::parse, Parameter::Integer)(s.strip_prefix('+').unwrap_or(s))
+                }
+            },
+            _ => {
+                let (s, k) = parse_keyword(s)?;
+                let (s, p) = delimited(sym('('), Parameter::parse, sym(')'))(s)?;
+                Ok((s, Parameter::Typed(k, Box::new(p))))
+            },
+        }
+    }
+}
+
+/// Returns every supertype of the given entity, following all paths through
+/// the supertype graph, plus the entity itself
+fn ancestors_of(name: &str) -> HashSet<&str> {
+    let mut out = HashSet::new();
+    let mut todo = vec![name];
+    while let Some(t) = todo.pop() {
+        if out.insert(t) {
+            todo.extend(superclasses_of(t).iter());
+        }
+    }
+    out
 }
 
 pub(crate) fn parse_complex_mapping(s: &str) -> IResult<Entity> {
+    parse_complex(s, None)
+}
+
+/// Parses a complex entity instance.  If `fixes` is provided, each part's
+/// parameter list is repaired as in [`parse_entity_lenient`].
+fn parse_complex<'a>(s: &'a str, mut fixes: Option<&mut Vec<Fixup<'a>>>)
+    -> IResult<'a, Entity<'a>>
+{
     // We'll maintain a map from sub-entity name to its argument string, then
     // use this map to figure out the tree and construct it.
     let mut subentities: HashMap<&str, &str> = HashMap::new();
@@ -232,88 +531,141 @@ pub(crate) fn parse_complex_mapping(s: &str) -> IResult<Entity> {
     let mut args_start = 0;
     let mut name: &str = "";
     loop {
-        let next = match memchr3(b'(', b')', b'\'', &bstr[index..]) {
-            Some(i) => i,
+        let next = match bstr[index..].iter()
+            .position(|c| matches!(c, b'(' | b')' | b'\'' | b'/'))
+        {
+            Some(i) => index + i,
             None => return nom_err(s, ErrorKind::Alt),
         };
-        match bstr[index + next] {
+        match bstr[next] {
             b'(' => {
                 if depth == 1 {
-                    let name_slice = &bstr[index..(index + next)];
-                    name = std::str::from_utf8(name_slice)
-                        .expect("Could not convert back to name");
-                    args_start = index + next + 1;
-                    let name_tag_slice = &bstr[index..(index + next + 1)];
-                    let name_tag = std::str::from_utf8(name_tag_slice)
-                        .expect("Could not convert tag back to name");
-                    name_tags.insert(name, name_tag);
+                    let start = skip_ws(bstr, index);
+                    let len = bstr[start..next].iter()
+                        .take_while(|c| c.is_ascii_alphanumeric() || **c == b'_')
+                        .count();
+                    name = &s[start..start + len];
+                    args_start = next + 1;
+                    name_tags.insert(name, &s[start..=next]);
                 }
                 depth += 1;
             },
             b')' => {
                 depth -= 1;
                 if depth == 1 {
-                    let arg_slice = &bstr[args_start..(index + next)];
-                    let args = std::str::from_utf8(arg_slice)
-                        .expect("Could not convert args");
-                    subentities.insert(name, args);
+                    subentities.insert(name, &s[args_start..next]);
                 } else if depth == 0 {
                     break;
                 }
             },
             b'\'' => {
-                // TODO: handle escaped quotes
-                let j = match memchr(b'\'', &bstr[(index + next + 1)..]) {
+                let j = match string_end(&bstr[(next + 1)..]) {
                     Some(j) => j,
                     None => return nom_err(s, ErrorKind::Char),
                 };
-                index += j + 1;
-            }
-            c => panic!("Invalid char: {}", c),
+                index = next + j + 2;
+                continue;
+            },
+            b'/' if bstr.get(next + 1) == Some(&b'*') => {
+                index = comment_end(bstr, next + 2).unwrap_or(bstr.len());
+                continue;
+            },
+            _ => (),
         }
-        index += next + 1;
+        index = next + 1;
     }
-    // Filter out the list of subclasses to those which aren't a parent of
-    // another item in the set; these are our potential leafs.
+    // Filter out the list of subclasses to those which aren't an ancestor of
+    // another item in the set; these are our potential leafs.  Supertype
+    // graphs may be diamond-shaped (with AND / ANDOR), so we walk every
+    // path up to the roots.
+    let ancestors: HashMap<&str, HashSet<&str>> = subentities.keys()
+        .map(|k| (*k, ancestors_of(k)))
+        .collect();
     let mut potential_leafs: HashSet<&str> = subentities.keys()
-        .map(|i| *i)
+        .copied()
         .collect();
-    for k in subentities.keys() {
-        for sup in superclasses_of(k) {
+    for (k, a) in ancestors.iter() {
+        for sup in a.iter().filter(|sup| *sup != k) {
             potential_leafs.remove(sup);
         }
     }
-    // Eliminate any leaf with no arguments, since they're just addding
-    // bonus constraints (which we don't handle anyways)
-    potential_leafs.retain(|k| subentities[k] != "");
 
     // Sort potential leafs so that ComplexEntity is deterministic and we can
     // match against it later
     let mut potential_leafs: Vec<&str> = potential_leafs.into_iter().collect();
-    potential_leafs.sort();
+    potential_leafs.sort_unstable();
+
+    // Leafs with no arguments of their own usually just add constraints (which
+    // we don't handle anyways), but they're still part of the instance's type
+    // so they're all kept.  Their inherited attributes are often written as
+    // `*` because another leaf derives them, e.g. `LENGTH_UNIT()` alongside
+    // `NAMED_UNIT(*)SI_UNIT(...)`; the schema marks those attributes as
+    // derivable, so the leaf still parses as its own type.  If it doesn't
+    // parse anyways, it's kept as a bare keyword (an untyped record with no
+    // parameters).
+    let has_args = |k: &str| subentities.get(k).is_some_and(|a| !ws(a).is_empty());
+    let leafs = potential_leafs;
 
     // At this point, we'll build up argument strings by splicing together bits
     // of arguments from the existing string (to make lifetimes happy), then
-    // parse into leaf entities.
-    let mut leaf_entities = Vec::with_capacity(potential_leafs.len());
-    for leaf in potential_leafs.into_iter() {
-        let mut chain = vec![leaf];
-        loop {
-            let sup = superclasses_of(chain.last().unwrap());
-            match sup.len() {
-                0 => break,
-                1 => chain.push(sup[0]),
-                _ => return nom_err(s, ErrorKind::LengthValue), // TODO: error
+    // parse into leaf entities.  The order of the arguments comes from
+    // `attribute_owners`, which matches the order of fields in the leaf.
+    let mut leaf_entities = Vec::with_capacity(leafs.len());
+    for leaf in leafs.into_iter() {
+        let mut owners: Vec<&str> = Vec::new();
+        for o in attribute_owners(leaf) {
+            if owners.last() != Some(o) {
+                if owners.contains(o) {
+                    // Attributes from a single owner must be contiguous
+                    return nom_err(s, ErrorKind::LengthValue);
+                }
+                owners.push(o);
+            }
+        }
+        if owners.is_empty() {
+            owners.push(leaf);
+        }
+        let bare = || Entity::Untyped(vec![Record { keyword: leaf, params: vec![] }]);
+
+        if let Some(fixes) = fixes.as_deref_mut().filter(|_| has_args(leaf)) {
+            let mut parts = Vec::with_capacity(owners.len());
+            for o in owners {
+                let args = match subentities.get(o) {
+                    Some(a) => *a,
+                    None => return nom_err(s, ErrorKind::Verify),
+                };
+                let params = match split_params(args) {
+                    Some((p, _)) => p,
+                    None => return nom_err(s, ErrorKind::Char),
+                };
+                let mut part = LenientPart::new(leaf, Some(o), params);
+                part.repair(fixes);
+                parts.push(part);
             }
+            leaf_entities.push(parse_repaired(name_tags[leaf], &parts)?.1);
+            continue;
         }
+
         let mut new_decl: Vec<&str> = vec![name_tags.get(leaf).unwrap()];
-        for c in chain.iter().rev() {
-            if !subentities[c].is_empty() {
-                new_decl.push(subentities[c]);
-                new_decl.push(if *c == leaf { &")" } else { &"," });
+        for o in owners {
+            let args = match subentities.get(o) {
+                Some(a) => *a,
+                None if !has_args(leaf) => break,
+                None => return nom_err(s, ErrorKind::Verify),
+            };
+            if !ws(args).is_empty() {
+                if new_decl.len() > 1 {
+                    new_decl.push(",");
+                }
+                new_decl.push(args);
             }
         }
-        leaf_entities.push(Entity::parse_chunks(&new_decl)?.1)
+        new_decl.push(")");
+        match Entity::parse_chunks(&new_decl) {
+            Ok((_, e)) => leaf_entities.push(e),
+            Err(_) if !has_args(leaf) => leaf_entities.push(bare()),
+            Err(e) => return Err(e),
+        }
     }
     // At this point, we assume that there's nothing left to parse, so we
     // return an empty string for the 'remaining' text
@@ -326,9 +678,235 @@ pub(crate) fn parse_complex_mapping(s: &str) -> IResult<Entity> {
 
 ////////////////////////////////////////////////////////////////////////////////
 
+/// Parses an entity instance, repairing common mistakes made by exporters
+/// (see [`FixupKind`]).  This is much slower than [`parse_entity_decl`], so
+/// it should only be used for instances which fail to parse as written.
+///
+/// Repairs are returned alongside the entity, with their offset and line
+/// left at zero.  Integers written where reals are expected don't need
+/// repairing, since they're accepted by the strict parser.
+pub(crate) fn parse_entity_lenient(s: &[u8]) -> IResult<'_, (usize, Entity<'_>, Vec<Fixup<'_>>)> {
+    let s = match std::str::from_utf8(s) {
+        Ok(s) => s,
+        Err(_) => return nom_err("", ErrorKind::Escaped),
+    };
+    let (s, id) = Id::<()>::parse(s)?;
+    let (s, _) = sym('=')(s)?;
+    let s = ws(s);
+    let mut fixes = vec![];
+    let (rest, e) = if s.starts_with('(') {
+        parse_complex(s, Some(&mut fixes))?
+    } else {
+        let n = s.bytes()
+            .take_while(|c| c.is_ascii_alphanumeric() || *c == b'_')
+            .count();
+        let keyword = &s[..n];
+        if type_kind(keyword) != Some("ENTITY") {
+            return nom_alt_err(s);
+        }
+        let (args, _) = sym('(')(&s[n..])?;
+        let (params, rest) = match split_params(args) {
+            Some(p) => p,
+            None => return nom_err(args, ErrorKind::Char),
+        };
+        let mut part = LenientPart::new(keyword, None, params);
+        part.repair(&mut fixes);
+        let head = &s[..s.len() - args.len()];
+        (rest, parse_repaired(head, &[part])?.1)
+    };
+    for f in fixes.iter_mut() {
+        f.id = id.0;
+    }
+    Ok((rest, (id.0, e, fixes)))
+}
+
+/// Splits a parameter list into its top-level parameters, given the text
+/// after its opening parenthesis.  Returns the parameters (without
+/// surrounding whitespace) and the text after the closing parenthesis; a
+/// list without a closing parenthesis runs to the end of the input.
+/// Returns `None` if a string is unterminated.
+fn split_params(s: &str) -> Option<(Vec<&str>, &str)> {
+    let b = s.as_bytes();
+    let mut out = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    let mut i = 0;
+    let end = loop {
+        match b.get(i) {
+            None => break i,
+            Some(b'\'') => {
+                i += string_end(&b[i + 1..])? + 2;
+                continue;
+            },
+            Some(b'/') if b.get(i + 1) == Some(&b'*') => {
+                i = comment_end(b, i + 2).unwrap_or(b.len());
+                continue;
+            },
+            Some(b'(') => depth += 1,
+            Some(b')') if depth == 0 => break i,
+            Some(b')') => depth -= 1,
+            Some(b',') if depth == 0 => {
+                out.push(ws(&s[start..i]).trim_end());
+                start = i + 1;
+            },
+            _ => (),
+        }
+        i += 1;
+    };
+    let last = ws(&s[start..end]).trim_end();
+    if !last.is_empty() || !out.is_empty() {
+        out.push(last);
+    }
+    Some((out, s.get(end + 1..).unwrap_or("")))
+}
+
+/// Returns the integer part of a real without a fractional part, e.g. `3`
+/// for `3.` or `-2.00`
+fn integral_real(p: &str) -> Option<&str> {
+    let (int, frac) = p.split_once('.')?;
+    let digits = int.strip_prefix('-').unwrap_or(int);
+    if !digits.is_empty() && digits.bytes().all(|c| c.is_ascii_digit())
+        && frac.bytes().all(|c| c == b'0')
+    {
+        Some(int)
+    } else {
+        None
+    }
+}
+
+/// Returns the Part 21 spelling of an enumeration value (without dots) of
+/// the given kind, ignoring case
+fn uppercase_literal(kind: &str, s: &str) -> Option<&'static str> {
+    match (kind, s.to_ascii_uppercase().as_str()) {
+        ("ENUMERATION", _) => enumeration_literal(s),
+        (_, "T") => Some(".T."),
+        (_, "F") => Some(".F."),
+        ("LOGICAL", "U" | "UNKNOWN") => Some(".UNKNOWN."),
+        _ => None,
+    }
+}
+
+/// Returns the value which replaces `$` in a non-optional attribute of the
+/// given kind (see [`attribute_kinds`]), if there's a sensible default
+fn null_default(kind: &str) -> Option<&'static str> {
+    match kind {
+        "REAL" => Some("0."),
+        "INTEGER" => Some("0"),
+        "BOOLEAN" => Some(".F."),
+        "LOGICAL" => Some(".UNKNOWN."),
+        k if k.starts_with("LIST OF ") => Some("()"),
+        _ => None,
+    }
+}
+
+/// The parameter list of a simple instance, or of one part of a complex
+/// instance, being repaired by lenient parsing.  Parameters are either
+/// slices of the source text or static strings, so that the repaired list
+/// can be parsed without copying.
+struct LenientPart<'a> {
+    keyword: &'a str,
+    kinds: Vec<&'static str>,
+    names: Vec<&'static str>,
+    optional: Vec<bool>,
+    params: Vec<&'a str>,
+}
+
+impl<'a> LenientPart<'a> {
+    /// Builds the parameter list for the attributes of `leaf` which are
+    /// declared by `owner` (for a part of a complex instance), or for every
+    /// attribute of `leaf` if `owner` is `None`
+    fn new(leaf: &'a str, owner: Option<&'a str>, params: Vec<&'a str>) -> Self {
+        let owners = attribute_owners(leaf);
+        let own: Vec<usize> = (0..attribute_kinds(leaf).len())
+            .filter(|i| owner.is_none_or(|o| owners.get(*i).is_none_or(|x| *x == o)))
+            .collect();
+        let optional = attribute_optional(leaf);
+        Self {
+            keyword: owner.unwrap_or(leaf),
+            kinds: own.iter().map(|i| attribute_kinds(leaf)[*i]).collect(),
+            names: own.iter().map(|i| attribute_names(leaf)[*i]).collect(),
+            optional: own.iter().map(|i| optional.get(*i).copied().unwrap_or(false)).collect(),
+            params,
+        }
+    }
+
+    fn record(&self, index: usize, kind: FixupKind, fixes: &mut Vec<Fixup<'a>>) {
+        let f = Fixup {
+            id: 0,
+            offset: 0,
+            line: 0,
+            keyword: self.keyword,
+            index,
+            attribute: self.names.get(index).copied(),
+            kind,
+        };
+        // Supertypes shared by several leafs are repaired once per leaf
+        if !fixes.contains(&f) {
+            fixes.push(f);
+        }
+    }
+
+    /// Repairs mistakes which can be found without parsing: wrongly-cased
+    /// enumerations, integral reals in integer attributes, `$` in attributes
+    /// which aren't optional, and missing or extra parameters.  None of these
+    /// change a valid parameter list.
+    fn repair(&mut self, fixes: &mut Vec<Fixup<'a>>) {
+        for i in 0..self.params.len().min(self.kinds.len()) {
+            let p = self.params[i];
+            let fixed = match self.kinds[i] {
+                k if p == "$" && !self.optional[i] => null_default(k)
+                    .map(|p| (p, FixupKind::NullValue)),
+                "INTEGER" => integral_real(p)
+                    .map(|p| (p, FixupKind::RealAsInteger)),
+                k @ ("ENUMERATION" | "BOOLEAN" | "LOGICAL") => p.strip_prefix('.')
+                    .and_then(|p| p.strip_suffix('.'))
+                    .filter(|p| p.bytes().any(|c| c.is_ascii_lowercase()))
+                    .and_then(|p| uppercase_literal(k, p))
+                    .map(|p| (p, FixupKind::EnumerationCase)),
+                _ => None,
+            };
+            if let Some((p, kind)) = fixed {
+                self.params[i] = p;
+                self.record(i, kind, fixes);
+            }
+        }
+        for i in self.params.len()..self.kinds.len() {
+            self.params.push(if self.kinds[i] == "DERIVED" { "*" } else { "$" });
+            self.record(i, FixupKind::MissingAttribute, fixes);
+            if !self.optional[i] {
+                if let Some(d) = null_default(self.kinds[i]) {
+                    self.params[i] = d;
+                    self.record(i, FixupKind::NullValue, fixes);
+                }
+            }
+        }
+        for i in self.kinds.len()..self.params.len() {
+            self.record(i, FixupKind::ExtraAttribute, fixes);
+        }
+        self.params.truncate(self.kinds.len());
+    }
+}
+
+/// Parses an instance from its keyword (with the opening parenthesis) and
+/// repaired parameter lists
+fn parse_repaired<'a>(head: &'a str, parts: &[LenientPart<'a>]) -> IResult<'a, Entity<'a>> {
+    let mut chunks = vec![head];
+    for p in parts.iter().flat_map(|p| p.params.iter()) {
+        if chunks.len() > 1 {
+            chunks.push(",");
+        }
+        chunks.push(p);
+    }
+    chunks.push(")");
+    Entity::parse_chunks(&chunks)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::step_file::StepFile;
     #[test]
     fn test_parse_entity_decl() {
         parse_entity_decl(b"#3=SHAPE_DEFINITION_REPRESENTATION(#4,#10);").unwrap();
@@ -336,4 +914,151 @@ mod tests {
         parse_entity_decl(b"#395359=UNCERTAINTY_MEASURE_WITH_UNIT(LENGTH_MEASURE(1.E-007),#395356,'distance_accuracy_value','confusion accuracy');").unwrap();
         parse_entity_decl(b"#1632=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));").unwrap();
     }
+    #[test]
+    fn test_parse_string() {
+        assert_eq!(Cow::<str>::parse("'abc',"), Ok((",", Cow::Borrowed("abc"))));
+        assert_eq!(Cow::<str>::parse("'it''s')"), Ok((")", Cow::Borrowed("it's"))));
+        assert_eq!(Cow::<str>::parse("'a;b(c)'"), Ok(("", Cow::Borrowed("a;b(c)"))));
+        assert_eq!(Cow::<str>::parse("$"), Ok(("", Cow::Borrowed(""))));
+        assert!(Cow::<str>::parse("'abc").is_err());
+    }
+    #[test]
+    fn test_decode_string() {
+        assert!(matches!(decode_string("plain text"), Cow::Borrowed(_)));
+        assert_eq!(decode_string("it''s"), "it's");
+        assert_eq!(decode_string("a\\\\b"), "a\\b");
+        assert_eq!(decode_string("Stra\\S\\_e"), "Stra\u{df}e");
+        assert_eq!(decode_string("Gr\\X\\FCe"), "Gr\u{fc}e");
+        assert_eq!(decode_string("\\X2\\90E853\\X0\\"), "\\X2\\90E853\\X0\\");
+        assert_eq!(decode_string("\\X2\\90E85351\\X0\\A"), "\u{90e8}\u{5351}A");
+        assert_eq!(decode_string("\\X4\\0001F600\\X0\\"), "\u{1F600}");
+        assert_eq!(decode_string("a\\N\\b\\PA\\c"), "abc");
+        assert_eq!(decode_string("bad\\X\\zz"), "bad\\X\\zz");
+    }
+    #[test]
+    fn test_parse_escaped_entity() {
+        let (_, (_, e)) = parse_entity_decl(
+            b"#1=PRODUCT('Part  /* not a comment */ 1','Teil f\\X2\\00FC\\X0\\r\n Stra''s;e','',(#2));").unwrap();
+        match e {
+            Entity::Product(p) => {
+                assert_eq!(p.id.0, "Part  /* not a comment */ 1");
+                assert_eq!(p.name.0, "Teil f\u{fc}r Stra's;e");
+            },
+            e => panic!("Invalid entity {:?}", e),
+        }
+    }
+    #[test]
+    fn test_split_params() {
+        assert_eq!(split_params("'a,b', (1, 2) ,#3 /* c ) */, $);x"),
+                   Some((vec!["'a,b'", "(1, 2)", "#3 /* c ) */", "$"], ";x")));
+        assert_eq!(split_params(" )"), Some((vec![], "")));
+        assert_eq!(split_params("1,"), Some((vec!["1", ""], "")));
+        assert_eq!(split_params("'abc"), None);
+    }
+    #[test]
+    fn test_parse_whitespace() {
+        let s = StepFile::parse(b"ISO-10303-21;
+HEADER;
+ENDSEC;
+DATA ;
+#1 = CARTESIAN_POINT ( '' , ( 0. , /* y */ 1.5 ,
+    -2. ) ) ;
+#2=(
+    LENGTH_UNIT ( )
+    NAMED_UNIT ( * ) /* (comment) */
+    SI_UNIT ( .MILLI. , .METRE. )
+);
+#3 = ADVANCED_FACE('', ( #4 ) , #5 , .F. ) ;
+ENDSEC;
+END-ISO-10303-21;
+");
+        match s.get(1) {
+            Some(Entity::CartesianPoint(p)) => {
+                let c: Vec<f64> = p.coordinates.iter().map(|c| c.0).collect();
+                assert_eq!(c, vec![0.0, 1.5, -2.0]);
+            },
+            e => panic!("Invalid entity {:?}", e),
+        }
+        assert!(matches!(s.get(2), Some(Entity::ComplexEntity(v))
+            if matches!(v[..], [Entity::LengthUnit(_), Entity::SiUnit(_)])));
+        match s.get(3) {
+            Some(Entity::AdvancedFace(f)) => {
+                assert_eq!(f.bounds[0].0, 4);
+                assert!(!f.same_sense);
+            },
+            e => panic!("Invalid entity {:?}", e),
+        }
+    }
+    #[test]
+    fn test_parse_multiple_inheritance() {
+        let s = StepFile::parse(b"DATA;
+            #1=(FACE((#4))FACE_SURFACE(#5,.F.)GEOMETRIC_REPRESENTATION_ITEM()
+                REPRESENTATION_ITEM('f')TOPOLOGICAL_REPRESENTATION_ITEM());
+            #2=(LENGTH_MEASURE_WITH_UNIT()MEASURE_REPRESENTATION_ITEM()
+                MEASURE_WITH_UNIT(LENGTH_MEASURE(2.5),#9)
+                REPRESENTATION_ITEM('thickness'));
+            #3=(FACE((#4))FACE_SURFACE(#5,.F.)GEOMETRIC_REPRESENTATION_ITEM());
+            ENDSEC;");
+        match s.get(1) {
+            Some(Entity::FaceSurface(f)) => {
+                assert_eq!(f.representation_item__name.0, "f");
+                assert_eq!(f.bounds[0].0, 4);
+                assert_eq!(f.face_geometry.0, 5);
+                assert!(!f.same_sense);
+            },
+            e => panic!("Invalid entity {:?}", e),
+        }
+        // LENGTH_MEASURE_WITH_UNIT has no attributes of its own, but it's
+        // still parsed as part of the instance
+        match s.get(2) {
+            Some(Entity::ComplexEntity(v)) => match &v[..] {
+                [Entity::LengthMeasureWithUnit(u), Entity::MeasureRepresentationItem(m)] => {
+                    assert_eq!(u.unit_component.0, 9);
+                    assert_eq!(m.name.0, "thickness");
+                },
+                v => panic!("Invalid parts {:?}", v),
+            },
+            e => panic!("Invalid entity {:?}", e),
+        }
+        // REPRESENTATION_ITEM is missing from the external mapping
+        match s.get(3) {
+            Some(Entity::Untyped(v)) => {
+                let k: Vec<&str> = v.iter().map(|r| r.keyword).collect();
+                assert_eq!(k, vec!["FACE", "FACE_SURFACE", "GEOMETRIC_REPRESENTATION_ITEM"]);
+            },
+            e => panic!("Invalid entity {:?}", e),
+        }
+    }
+    #[test]
+    fn test_parse_untyped() {
+        use Parameter::*;
+        let (_, (i, e)) = parse_entity_fallback(
+            b"#7 = NOT_IN_SCHEMA('a''b', (#1, $, *), .Up., (1, -2.5E3, +3), \
+              LENGTH_MEASURE(2.), NESTED(LIST((#4))));").unwrap();
+        assert_eq!(i, 7);
+        let r = match e {
+            Entity::Untyped(mut v) if v.len() == 1 => v.pop().unwrap(),
+            e => panic!("Invalid entity {:?}", e),
+        };
+        assert_eq!(r.keyword, "NOT_IN_SCHEMA");
+        assert_eq!(r.params, vec![
+            String(Cow::Borrowed("a'b")),
+            List(vec![Ref(1), Null, Derived]),
+            Enum("Up"),
+            List(vec![Integer(1), Real(-2500.0), Integer(3)]),
+            Typed("LENGTH_MEASURE", Box::new(Real(2.0))),
+            Typed("NESTED", Box::new(Typed("LIST", Box::new(List(vec![Ref(4)]))))),
+        ]);
+        assert_eq!(Entity::Untyped(vec![r]).upstream(), vec![1, 4]);
+
+        let (_, (_, e)) = parse_entity_fallback(b"#8=(A()B(1,#2));").unwrap();
+        assert!(matches!(e, Entity::Untyped(v) if v.len() == 2));
+
+        // Invalid Part 21 keeps only the id
+        for b in [&b"#9=A(1,2"[..], b"#9=A(1) B(2);", b"#9=A(oops);", b"#9=();"] {
+            assert!(matches!(parse_entity_fallback(b), Ok((_, (9, Entity::_FailedToParse)))),
+                    "{:?}", std::str::from_utf8(b));
+        }
+        assert!(parse_entity_fallback(b"#=A();").is_err());
+    }
 }
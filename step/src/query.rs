@@ -0,0 +1,425 @@
+//! A small query language for selecting entities.
+//!
+//! A query names an entity type (matching subtypes too, or `*` for any
+//! type), optionally followed by conditions in square brackets, all of
+//! which must hold:
+//! ```text
+//! ADVANCED_FACE[face_geometry -> CYLINDRICAL_SURFACE[radius < 2]]
+//! PRODUCT[name ~ 'Bolt*']
+//! CARTESIAN_POINT[<- VERTEX_POINT, !name = '']
+//! ```
+//! Conditions are
+//! - `path op value`, comparing an attribute with a value
+//! - `path -> query`, checking that an attribute refers to a matching entity
+//! - `<- query`, checking that a matching entity refers to this one
+//! - `path` alone, checking that an attribute is set (not `$`)
+//!
+//! and any condition can be negated with a leading `!`.  A path is an
+//! attribute name (see [`Entity::attributes`]), and may follow references
+//! with dots, e.g. `face_geometry.position.location`.  If a path yields
+//! several values (because it passes through a list), a condition holds if
+//! it holds for any of them.
+//!
+//! Values are written as in Part 21: numbers, `'strings'`, `.ENUMS.`, `#ids`
+//! and `$`.  Operators are `=`, `!=`, `<`, `<=`, `>` and `>=`, plus `~`,
+//! which matches strings against a case-insensitive pattern where `*`
+//! matches any run of characters and `?` matches any single character.
+//! Comparing values of different kinds (e.g. a string with a number, or a
+//! set attribute with `$`) is false for every operator, including `!=`; use
+//! `path` or `!path` to check whether an attribute is set.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while1},
+    combinator::{map, opt},
+    multi::separated_list1,
+};
+
+use crate::{
+    ap214::Entity,
+    id::Id,
+    param::Parameter,
+    parse::{IResult, Parse, parse_enum_tag, sym, ws},
+    step_file::StepFile,
+};
+
+/// A parsed query, which can be run with [`StepFile::query`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    /// Entity type (as an upper-case keyword), or `None` to match any type
+    pub type_name: Option<String>,
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    Not(Box<Condition>),
+    /// The attribute at this path is set
+    Set(Vec<String>),
+    Compare(Vec<String>, Op, Value),
+    /// The attribute at this path refers to an entity matching the query
+    RefersTo(Vec<String>, Query),
+    /// An entity matching the query refers to this entity
+    ReferencedBy(Query),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// Case-insensitive pattern match, with `*` and `?` wildcards
+    Like,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Number(f64),
+    String(String),
+    /// Enumeration tag, without the surrounding dots
+    Enum(String),
+    Ref(usize),
+    Null,
+}
+
+/// Error from parsing a query, with the byte offset at which parsing failed
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueryError {
+    pub offset: usize,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid query at offset {}", self.offset)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+////////////////////////////////////////////////////////////////////////////////
+
+fn name(s: &str) -> IResult<'_, &str> {
+    let s = ws(s);
+    let (rest, n) = take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_')(s)?;
+    if n.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err(nom::Err::Error(nom::error::Error::new(s, nom::error::ErrorKind::Alpha)));
+    }
+    Ok((rest, n))
+}
+
+fn path(s: &str) -> IResult<'_, Vec<String>> {
+    separated_list1(sym('.'), map(name, str::to_owned))(s)
+}
+
+fn op(s: &str) -> IResult<'_, Op> {
+    alt((
+        map(tag("!="), |_| Op::Ne),
+        map(tag("<="), |_| Op::Le),
+        map(tag(">="), |_| Op::Ge),
+        map(tag("="), |_| Op::Eq),
+        map(tag("<"), |_| Op::Lt),
+        map(tag(">"), |_| Op::Gt),
+        map(tag("~"), |_| Op::Like),
+    ))(ws(s))
+}
+
+fn value(s: &str) -> IResult<'_, Value> {
+    alt((
+        map(sym('$'), |_| Value::Null),
+        map(Id::<()>::parse, |i| Value::Ref(i.0)),
+        map(parse_enum_tag, |e| Value::Enum(e.to_owned())),
+        map(<Cow<str>>::parse, |s| Value::String(s.into_owned())),
+        map(f64::parse, Value::Number),
+    ))(s)
+}
+
+fn condition(s: &str) -> IResult<'_, Condition> {
+    let (s, not) = opt(sym('!'))(s)?;
+    let (s, c) = if let Ok((s, _)) = tag::<_, _, ()>("<-")(ws(s)) {
+        map(query, Condition::ReferencedBy)(s)?
+    } else {
+        let (s, p) = path(s)?;
+        if let Ok((s, _)) = tag::<_, _, ()>("->")(ws(s)) {
+            let (s, q) = query(s)?;
+            (s, Condition::RefersTo(p, q))
+        } else if let Ok((s, o)) = op(s) {
+            let (s, v) = value(s)?;
+            (s, Condition::Compare(p, o, v))
+        } else {
+            (s, Condition::Set(p))
+        }
+    };
+    Ok((s, if not.is_some() { Condition::Not(Box::new(c)) } else { c }))
+}
+
+fn query(s: &str) -> IResult<'_, Query> {
+    let (s, type_name) = alt((
+        map(sym('*'), |_| None),
+        map(name, |n| Some(n.to_ascii_uppercase())),
+    ))(s)?;
+    let (s, conditions) = match sym('[')(s) {
+        Ok((s, _)) => {
+            let (s, c) = separated_list1(sym(','), condition)(s)?;
+            let (s, _) = sym(']')(s)?;
+            (s, c)
+        },
+        Err(_) => (s, vec![]),
+    };
+    Ok((s, Query { type_name, conditions }))
+}
+
+impl FromStr for Query {
+    type Err = QueryError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let offset = |rest: &str| QueryError { offset: s.len() - rest.len() };
+        match query(s) {
+            Ok((rest, q)) if ws(rest).is_empty() => Ok(q),
+            Ok((rest, _)) => Err(offset(ws(rest))),
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(offset(e.input)),
+            Err(nom::Err::Incomplete(_)) => Err(offset("")),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+/// Checks whether `s` matches a pattern with `*` and `?` wildcards, ignoring
+/// ASCII case
+fn like(pattern: &str, s: &str) -> bool {
+    let p: Vec<char> = pattern.chars().map(|c| c.to_ascii_lowercase()).collect();
+    let s: Vec<char> = s.chars().map(|c| c.to_ascii_lowercase()).collect();
+    // Greedy matching, backtracking to the most recent `*`
+    let (mut i, mut j) = (0, 0);
+    let mut star = None;
+    while j < s.len() {
+        if i < p.len() && (p[i] == '?' || p[i] == s[j]) {
+            i += 1;
+            j += 1;
+        } else if i < p.len() && p[i] == '*' {
+            star = Some((i, j));
+            i += 1;
+        } else if let Some((si, sj)) = star {
+            i = si + 1;
+            j = sj + 1;
+            star = Some((si, sj + 1));
+        } else {
+            return false;
+        }
+    }
+    p[i..].iter().all(|c| *c == '*')
+}
+
+/// Expands lists and typed parameters into their values
+fn flatten<'s>(v: Vec<Parameter<'s>>) -> Vec<Parameter<'s>> {
+    let mut out = vec![];
+    let mut todo = v;
+    todo.reverse();
+    while let Some(p) = todo.pop() {
+        match p {
+            Parameter::List(v) => todo.extend(v.into_iter().rev()),
+            Parameter::Typed(_, p) => todo.push(*p),
+            p => out.push(p),
+        }
+    }
+    out
+}
+
+/// Returns the values at the end of a path, following references
+fn resolve<'s, 'a>(file: &'s StepFile<'a>, e: &'s Entity<'a>, path: &[String])
+    -> Vec<Parameter<'s>>
+{
+    let mut values = flatten(e.attribute(&path[0]).into_iter().collect());
+    for name in &path[1..] {
+        values = flatten(values.into_iter()
+            .filter_map(|v| match v {
+                Parameter::Ref(j) => file.get(j),
+                _ => None,
+            })
+            .filter_map(|e| e.attribute(name))
+            .collect());
+    }
+    values
+}
+
+fn compare(p: &Parameter, op: Op, v: &Value) -> bool {
+    use std::cmp::Ordering;
+    let ord = match (p, v) {
+        (Parameter::Null, Value::Null) => Some(Ordering::Equal),
+        (_, Value::Null) | (Parameter::Null, _) => None,
+        (Parameter::Integer(i), Value::Number(x)) => (*i as f64).partial_cmp(x),
+        (Parameter::Real(f), Value::Number(x)) => f.partial_cmp(x),
+        (Parameter::String(s), Value::String(t)) => {
+            if op == Op::Like {
+                return like(t, s);
+            }
+            Some(s.as_ref().cmp(t))
+        },
+        (Parameter::Enum(e), Value::Enum(t)) => {
+            Some(if e.eq_ignore_ascii_case(t) { Ordering::Equal } else { e.cmp(&t.as_str()) })
+        },
+        (Parameter::Ref(i), Value::Ref(j)) => Some(i.cmp(j)),
+        _ => None,
+    };
+    match (op, ord) {
+        (_, None) | (Op::Like, _) => false,
+        (Op::Eq, Some(o)) => o == Ordering::Equal,
+        (Op::Ne, Some(o)) => o != Ordering::Equal,
+        (Op::Lt, Some(o)) => o == Ordering::Less,
+        (Op::Le, Some(o)) => o != Ordering::Greater,
+        (Op::Gt, Some(o)) => o == Ordering::Greater,
+        (Op::Ge, Some(o)) => o != Ordering::Less,
+    }
+}
+
+impl Condition {
+    fn matches<'a>(&self, file: &StepFile<'a>, id: usize, e: &Entity<'a>) -> bool {
+        match self {
+            Condition::Not(c) => !c.matches(file, id, e),
+            Condition::Set(path) => resolve(file, e, path).iter()
+                .any(|p| !matches!(p, Parameter::Null | Parameter::Derived)),
+            Condition::Compare(path, op, v) => resolve(file, e, path).iter()
+                .any(|p| compare(p, *op, v)),
+            Condition::RefersTo(path, q) => resolve(file, e, path).iter()
+                .any(|p| matches!(p, Parameter::Ref(j) if q.matches(file, *j))),
+            Condition::ReferencedBy(q) => file.referrers(id).iter()
+                .any(|r| q.matches(file, *r)),
+        }
+    }
+}
+
+impl Query {
+    /// Checks whether the entity with the given id matches this query
+    pub fn matches(&self, file: &StepFile, id: usize) -> bool {
+        if let Some(t) = &self.type_name {
+            if !file.instance_of(id, t) {
+                return false;
+            }
+        }
+        match file.get(id) {
+            Some(e) => self.conditions.iter().all(|c| c.matches(file, id, e)),
+            None => false,
+        }
+    }
+}
+
+impl<'a> StepFile<'a> {
+    /// Returns the ids of every entity which matches the query, in
+    /// increasing order.  Only entities which pass the type check are
+    /// decoded (plus any that the conditions refer to).
+    pub fn query(&self, q: &Query) -> Vec<usize> {
+        let mut out: Vec<usize> = self.sources()
+            .map(|(i, _)| i)
+            .filter(|i| q.matches(self, *i))
+            .collect();
+        out.sort_unstable();
+        out
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_query() {
+        let q: Query = "advanced_face[face_geometry -> CYLINDRICAL_SURFACE[radius < 2]]"
+            .parse().unwrap();
+        assert_eq!(q, Query {
+            type_name: Some("ADVANCED_FACE".to_owned()),
+            conditions: vec![Condition::RefersTo(
+                vec!["face_geometry".to_owned()],
+                Query {
+                    type_name: Some("CYLINDRICAL_SURFACE".to_owned()),
+                    conditions: vec![Condition::Compare(
+                        vec!["radius".to_owned()], Op::Lt, Value::Number(2.0))],
+                })],
+        });
+
+        let q: Query = "*[ !a.b = $, <- X, c ~ 'it''s', d >= .T., e != #12, f ]"
+            .parse().unwrap();
+        assert_eq!(q.type_name, None);
+        assert_eq!(q.conditions, vec![
+            Condition::Not(Box::new(Condition::Compare(
+                vec!["a".to_owned(), "b".to_owned()], Op::Eq, Value::Null))),
+            Condition::ReferencedBy("X".parse().unwrap()),
+            Condition::Compare(vec!["c".to_owned()], Op::Like,
+                               Value::String("it's".to_owned())),
+            Condition::Compare(vec!["d".to_owned()], Op::Ge, Value::Enum("T".to_owned())),
+            Condition::Compare(vec!["e".to_owned()], Op::Ne, Value::Ref(12)),
+            Condition::Set(vec!["f".to_owned()]),
+        ]);
+
+        assert_eq!("PRODUCT[".parse::<Query>(), Err(QueryError { offset: 8 }));
+        assert_eq!("PRODUCT extra".parse::<Query>(), Err(QueryError { offset: 8 }));
+        assert_eq!("PRODUCT[name < ]".parse::<Query>().unwrap_err().offset, 15);
+        assert_eq!("PRODUCT[name ~ 'a' x]".parse::<Query>().unwrap_err().offset, 19);
+        assert!("".parse::<Query>().is_err());
+    }
+
+    #[test]
+    fn test_like() {
+        assert!(like("bolt*", "Bolt M6"));
+        assert!(like("*m?", "bolt m6"));
+        assert!(like("*", ""));
+        assert!(like("a*b*c", "aXbYbZc"));
+        assert!(!like("a*b", "aXbY"));
+        assert!(!like("bolt", "bolts"));
+    }
+
+    #[test]
+    fn test_query() {
+        let s = StepFile::parse(include_bytes!("../../examples/cube_hole.step"));
+        let run = |q: &str| s.query(&q.parse().unwrap());
+
+        let cyl = run("CYLINDRICAL_SURFACE");
+        assert_eq!(cyl.len(), 1);
+        let faces = run("ADVANCED_FACE[face_geometry -> CYLINDRICAL_SURFACE[radius < 2]]");
+        assert!(!faces.is_empty());
+        assert_eq!(faces, run("ADVANCED_FACE[face_geometry.radius > 0.006]"));
+        assert!(run("ADVANCED_FACE[face_geometry.radius > 1]").is_empty());
+        assert_eq!(faces.len() + run("ADVANCED_FACE[!face_geometry -> CYLINDRICAL_SURFACE]").len(),
+                   run("ADVANCED_FACE").len());
+
+        assert_eq!(run("PRODUCT[name ~ 'part*']").len(), 1);
+        assert!(run("PRODUCT[name = 'nope']").is_empty());
+
+        // Subtypes match their supertypes, and references work both ways
+        assert_eq!(run("SURFACE[<- ADVANCED_FACE]").len(),
+                   run("ELEMENTARY_SURFACE").len());
+        assert!(run("*[<- ADVANCED_FACE]").contains(&cyl[0]));
+        assert_eq!(run(&format!("*[face_geometry = #{}]", cyl[0])), faces);
+    }
+
+    #[test]
+    fn test_compare_kinds() {
+        let s = StepFile::parse(b"DATA;
+#1=CARTESIAN_POINT('a',(0.,0.,0.));
+#2=CARTESIAN_POINT('b',(1.,0.,0.));
+#3=CIRCLE('',#4,2.);
+#4=AXIS2_PLACEMENT_3D('',#1,$,$);
+ENDSEC;");
+        let run = |q: &str| s.query(&q.parse().unwrap());
+
+        assert_eq!(run("CARTESIAN_POINT[name != 'a']"), vec![2]);
+        assert_eq!(run("*[radius != 3]"), vec![3]);
+
+        // Values of different kinds never compare, whatever the operator
+        assert!(run("*[name != 1]").is_empty());
+        assert!(run("*[radius != 'a']").is_empty());
+        assert!(run("*[radius != #12]").is_empty());
+        assert!(run("*[location != 2.]").is_empty());
+        assert!(run("*[axis != 1]").is_empty());
+        assert!(run("*[radius != $]").is_empty());
+        assert_eq!(run("*[axis = $]"), vec![4]);
+        assert_eq!(run("*[location != #2]"), vec![4]);
+    }
+}
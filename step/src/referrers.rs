@@ -0,0 +1,96 @@
+use crate::{
+    id::Id,
+    step_file::{FromEntity, StepFile},
+};
+
+/// Inverse of [`Entity::upstream`](crate::ap214::Entity::upstream), mapping
+/// each entity id to the ids of entities which refer to it
+#[derive(Debug)]
+pub(crate) struct Referrers {
+    /// Referenced ids, sorted
+    targets: Vec<usize>,
+    /// Referring ids, sorted within each run of equal `targets`
+    sources: Vec<usize>,
+}
+
+impl Referrers {
+    fn build(s: &StepFile) -> Self {
+        s.decode_all();
+        let mut pairs: Vec<(usize, usize)> = s.entities()
+            .flat_map(|(i, e)| e.upstream().into_iter().map(move |j| (j, i)))
+            .filter(|(j, _)| *j != 0) // NUL references
+            .collect();
+        pairs.sort_unstable();
+        pairs.dedup();
+        let (targets, sources) = pairs.into_iter().unzip();
+        Self { targets, sources }
+    }
+
+    fn get(&self, id: usize) -> &[usize] {
+        let lo = self.targets.partition_point(|t| *t < id);
+        let hi = self.targets.partition_point(|t| *t <= id);
+        &self.sources[lo..hi]
+    }
+}
+
+impl<'a> StepFile<'a> {
+    /// Returns the ids of every entity which refers to the given id, in
+    /// increasing order.
+    ///
+    /// The reverse index is built on first use, which requires decoding
+    /// every entity in the file.  References to ids which aren't in the file
+    /// are still indexed, so this can also be used to find dangling
+    /// references.
+    pub fn referrers(&self, id: usize) -> &[usize] {
+        self.referrers_index()
+            .get_or_init(|| Referrers::build(self))
+            .get(id)
+    }
+
+    /// Returns every entity of type `T` which refers to the given id
+    pub fn referrers_of_type<'s, T: FromEntity<'s> + 's>(&'s self, id: usize)
+        -> Vec<(Id<T>, &'s T)>
+    {
+        self.referrers(id).iter()
+            .filter_map(|i| self.entity(Id::<T>::new(*i))
+                .map(|e| (Id::new(*i), e)))
+            .collect()
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ap214::{EdgeCurve_, OrientedEdge_, VertexPoint_};
+
+    #[test]
+    fn test_referrers() {
+        let s = StepFile::parse(b"DATA;
+            #1=CARTESIAN_POINT('',(0.,0.,0.));
+            #2=VERTEX_POINT('',#1);
+            #3=VERTEX_POINT('',#1);
+            #4=EDGE_CURVE('',#2,#3,#9,.T.);
+            #5=ORIENTED_EDGE('',*,*,#4,.T.);
+            #6=ORIENTED_EDGE('',*,*,#4,.F.);
+            #7=EDGE_CURVE('',#2,#2,$,.T.);
+            ENDSEC;");
+        assert_eq!(s.referrers(1), &[2, 3]);
+        assert_eq!(s.referrers(2), &[4, 7]);
+        assert_eq!(s.referrers(4), &[5, 6]);
+        assert_eq!(s.referrers(5), &[] as &[usize]);
+        assert_eq!(s.referrers(9), &[4]); // dangling
+        assert_eq!(s.referrers(0), &[] as &[usize]);
+
+        let v: Vec<usize> = s.referrers_of_type::<VertexPoint_>(1).iter()
+            .map(|(i, _)| i.0)
+            .collect();
+        assert_eq!(v, vec![2, 3]);
+        assert!(s.referrers_of_type::<EdgeCurve_>(1).is_empty());
+        let e: Vec<bool> = s.referrers_of_type::<OrientedEdge_>(4).iter()
+            .map(|(_, e)| e.orientation)
+            .collect();
+        assert_eq!(e, vec![true, false]);
+    }
+}
@@ -0,0 +1,210 @@
+//! Runtime reflection over entities, so that tools can inspect any entity
+//! without matching on every variant of [`Entity`].
+//!
+//! Type and attribute names come from the generated schema
+//! ([`superclasses_of`], [`attribute_names`] and [`attribute_owners`]), and
+//! attribute values are returned as schema-independent [`Parameter`]s.
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use crate::{
+    ap214::{Entity, attribute_names, attribute_owners, superclasses_of},
+    param::Parameter,
+};
+
+/// Checks whether an entity type is `ancestor` or one of its (transitive)
+/// subtypes.  Both names are compared case-insensitively, so EXPRESS names
+/// like `representation_item` work as well as Part 21 keywords.
+pub fn is_a(keyword: &str, ancestor: &str) -> bool {
+    let keyword = if keyword.bytes().any(|c| c.is_ascii_lowercase()) {
+        Cow::Owned(keyword.to_ascii_uppercase())
+    } else {
+        Cow::Borrowed(keyword)
+    };
+    let mut todo = vec![keyword.as_ref()];
+    while let Some(k) = todo.pop() {
+        if k.eq_ignore_ascii_case(ancestor) {
+            return true;
+        }
+        todo.extend(superclasses_of(k).iter());
+    }
+    false
+}
+
+/// Returns the attributes which an entity declares itself (rather than
+/// inheriting), which are the parameters of its part in a complex entity
+pub(crate) fn own_attribute_names(keyword: &str) -> Vec<&'static str> {
+    let owners = attribute_owners(keyword);
+    attribute_names(keyword).iter()
+        .enumerate()
+        .filter(|(i, _)| owners.get(*i).is_none_or(|o| *o == keyword))
+        .map(|(_, n)| *n)
+        .collect()
+}
+
+impl<'a> Entity<'a> {
+    /// Returns the types of a simple entity (one item) or the components of
+    /// a complex entity, as Part 21 keywords.  Components of a complex entity
+    /// include those with no attributes of their own, such as `LENGTH_UNIT`
+    /// in `(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.))`.
+    pub fn type_names(&self) -> Vec<&str> {
+        match self {
+            Entity::ComplexEntity(v) => v.iter()
+                .flat_map(|e| match e {
+                    Entity::Untyped(r) => r.iter().map(|r| r.keyword).collect(),
+                    e => e.type_name().into_iter().collect::<Vec<_>>(),
+                })
+                .collect(),
+            e => e.type_name().into_iter().collect(),
+        }
+    }
+
+    /// Returns the Part 21 keywords of this entity, like
+    /// [`type_names`](Self::type_names), but also including the keywords of
+    /// untyped entities (which may not be in the schema)
+    pub fn keywords(&self) -> Vec<&str> {
+        match self {
+            Entity::Untyped(v) => v.iter().map(|r| r.keyword).collect(),
+            e => e.type_names(),
+        }
+    }
+
+    /// Checks whether this entity is an instance of the given type, i.e.
+    /// whether it (or any of its components) is that type or a subtype of
+    /// it.  See [`is_a`].
+    pub fn is_a(&self, ancestor: &str) -> bool {
+        self.type_names().into_iter().any(|k| is_a(k, ancestor))
+    }
+
+    /// Returns every attribute of this entity as a (name, value) pair, in
+    /// parameter order.  Inherited attributes are included, and attributes
+    /// of complex entities are listed per component, without repeating
+    /// those inherited from a shared supertype.
+    ///
+    /// Parameters of untyped entities are named from the schema if their
+    /// keyword and position match an attribute, and are otherwise named `""`.
+    pub fn attributes(&self) -> Vec<(&'static str, Parameter<'_>)> {
+        let leafs = match self {
+            Entity::ComplexEntity(v) => v.iter().collect(),
+            Entity::Untyped(v) => return v.iter()
+                .flat_map(|r| {
+                    let names = if v.len() > 1 {
+                        own_attribute_names(r.keyword)
+                    } else {
+                        attribute_names(r.keyword).to_vec()
+                    };
+                    r.params.iter().enumerate()
+                        .map(move |(i, p)| (names.get(i).copied().unwrap_or(""), p.clone()))
+                })
+                .collect(),
+            e => vec![e],
+        };
+        let mut seen = HashSet::new();
+        let mut out = vec![];
+        for leaf in leafs {
+            let r = match leaf.to_record() {
+                Some(r) => r,
+                None => continue,
+            };
+            let owners = attribute_owners(r.keyword);
+            let names = attribute_names(r.keyword);
+            for (i, (name, p)) in names.iter().zip(r.params).enumerate() {
+                let owner = owners.get(i).copied().unwrap_or(r.keyword);
+                if seen.insert((owner, *name)) {
+                    out.push((*name, p));
+                }
+            }
+        }
+        out
+    }
+
+    /// Looks up a single attribute by name (see
+    /// [`attributes`](Self::attributes))
+    pub fn attribute(&self, name: &str) -> Option<Parameter<'_>> {
+        self.attributes().into_iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, p)| p)
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{id::Id, step_file::StepFile};
+
+    #[test]
+    fn test_is_a() {
+        assert!(is_a("ADVANCED_BREP_SHAPE_REPRESENTATION", "REPRESENTATION"));
+        assert!(is_a("REPRESENTATION", "REPRESENTATION"));
+        assert!(is_a("cartesian_point", "representation_item"));
+        assert!(!is_a("REPRESENTATION", "SHAPE_REPRESENTATION"));
+        assert!(!is_a("NOT_AN_ENTITY", "REPRESENTATION"));
+    }
+
+    #[test]
+    fn test_reflect() {
+        let s = StepFile::parse(b"DATA;
+#1=CARTESIAN_POINT('origin',(0.,1.,2.));
+#2=(GEOMETRIC_REPRESENTATION_CONTEXT(3)GLOBAL_UNCERTAINTY_ASSIGNED_CONTEXT((#5))
+GLOBAL_UNIT_ASSIGNED_CONTEXT((#6,#7))REPRESENTATION_CONTEXT('ctx','3D'));
+#3=ADVANCED_FACE('',(),#4,.T.);
+ENDSEC;");
+        let e = &s[Id::<()>::new(1)];
+        assert_eq!(e.type_name(), Some("CARTESIAN_POINT"));
+        assert_eq!(e.type_names(), vec!["CARTESIAN_POINT"]);
+        assert!(e.is_a("representation_item"));
+        assert!(e.is_a("POINT"));
+        assert!(!e.is_a("DIRECTION"));
+        assert_eq!(e.attributes().iter().map(|(n, _)| *n).collect::<Vec<_>>(),
+                   vec!["name", "coordinates"]);
+        assert_eq!(e.attribute("name"), Some(Parameter::String("origin".into())));
+        assert_eq!(e.attribute("coordinates"), Some(Parameter::List(vec![
+            Parameter::Real(0.0), Parameter::Real(1.0), Parameter::Real(2.0)])));
+        assert_eq!(e.attribute("nope"), None);
+
+        let e = &s[Id::<()>::new(2)];
+        assert_eq!(e.type_name(), None);
+        assert_eq!(e.type_names().len(), 3);
+        assert!(e.is_a("representation_context"));
+        assert!(e.is_a("GLOBAL_UNIT_ASSIGNED_CONTEXT"));
+        assert!(!e.is_a("REPRESENTATION_ITEM"));
+        assert_eq!(e.attribute("coordinate_space_dimension"),
+                   Some(Parameter::Integer(3)));
+        assert_eq!(e.attribute("units"),
+                   Some(Parameter::List(vec![Parameter::Ref(6), Parameter::Ref(7)])));
+        let names: Vec<_> = e.attributes().into_iter().map(|(n, _)| n).collect();
+        assert_eq!(names.iter().filter(|n| **n == "context_identifier").count(), 1);
+        assert_eq!(names.len(), 5);
+
+        let e = &s[Id::<()>::new(3)];
+        assert!(e.is_a("FACE"));
+        assert_eq!(e.attribute("face_geometry"), Some(Parameter::Ref(4)));
+        assert_eq!(e.attribute("same_sense"), Some(Parameter::Enum("T")));
+    }
+
+    #[test]
+    fn test_reflect_units() {
+        let s = StepFile::parse(b"DATA;
+#1=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));
+#2=(NAMED_UNIT(*)PLANE_ANGLE_UNIT()SI_UNIT($,.RADIAN.));
+ENDSEC;");
+        let e = &s[Id::<()>::new(1)];
+        assert_eq!(e.type_names(), vec!["LENGTH_UNIT", "SI_UNIT"]);
+        assert!(e.is_a("LENGTH_UNIT"));
+        assert!(e.is_a("si_unit"));
+        assert!(e.is_a("NAMED_UNIT"));
+        assert!(!e.is_a("PLANE_ANGLE_UNIT"));
+        assert_eq!(e.attribute("name"), Some(Parameter::Enum("METRE")));
+        assert!(s.instance_of(1, "LENGTH_UNIT"));
+
+        let e = &s[Id::<()>::new(2)];
+        assert_eq!(e.type_names(), vec!["PLANE_ANGLE_UNIT", "SI_UNIT"]);
+        assert!(e.is_a("PLANE_ANGLE_UNIT"));
+        assert!(!e.is_a("LENGTH_UNIT"));
+        assert!(s.instance_of(2, "PLANE_ANGLE_UNIT"));
+        assert!(!s.instance_of(2, "LENGTH_UNIT"));
+    }
+}
@@ -0,0 +1,281 @@
+use memchr::memchr_iter;
+use nom::error::ErrorKind;
+
+/// A single entity instance which failed to parse
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError<'a> {
+    /// Instance id (`#id`), or `None` if the id itself couldn't be parsed
+    pub id: Option<usize>,
+    /// Byte offset of the start of the instance, relative to the buffer
+    /// passed to [`StepFile::parse`](crate::step_file::StepFile::parse)
+    pub offset: usize,
+    /// Line number (1-indexed) of the start of the instance
+    pub line: usize,
+    /// Entity keywords of the instance.  Simple instances have a single
+    /// keyword; complex instances list every keyword in their external
+    /// mapping, in file order.
+    pub keywords: Vec<&'a str>,
+    /// Error kind reported by `nom`
+    pub kind: ErrorKind,
+}
+
+/// A repair made to a non-conformant instance by
+/// [`StepFile::parse_lenient`](crate::step_file::StepFile::parse_lenient)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fixup<'a> {
+    /// Instance id (`#id`)
+    pub id: usize,
+    /// Byte offset of the start of the instance, relative to the buffer
+    /// passed to [`StepFile::parse_lenient`](crate::step_file::StepFile::parse_lenient)
+    pub offset: usize,
+    /// Line number (1-indexed) of the start of the instance
+    pub line: usize,
+    /// Entity keyword of the repaired parameter list (for a complex
+    /// instance, the part which was repaired)
+    pub keyword: &'a str,
+    /// Index of the parameter within its parameter list
+    pub index: usize,
+    /// Name of the attribute, or `None` for extra parameters
+    pub attribute: Option<&'static str>,
+    /// What was repaired
+    pub kind: FixupKind,
+}
+
+/// Kinds of repair made by lenient parsing
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FixupKind {
+    /// A missing trailing parameter was filled in with `$`
+    MissingAttribute,
+    /// A parameter beyond the end of the attribute list was dropped
+    ExtraAttribute,
+    /// A real with no fractional part (e.g. `3.`) was read as an integer
+    RealAsInteger,
+    /// An enumeration, boolean or logical value in the wrong case (e.g.
+    /// `.f.`) was uppercased
+    EnumerationCase,
+    /// `$` in an attribute which isn't optional was replaced with a default
+    /// value: `0`, `0.`, `.F.`, `.UNKNOWN.` or an empty list
+    NullValue,
+}
+
+/// Summary of a parse, returned by
+/// [`StepFile::parse_with_report`](crate::step_file::StepFile::parse_with_report)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParseReport<'a> {
+    /// Number of instances in the DATA section
+    pub total: usize,
+    /// Instances which failed to parse, sorted by offset
+    pub errors: Vec<ParseError<'a>>,
+    /// Repairs made by lenient parsing, sorted by offset.  This is always
+    /// empty for strict parsing.
+    pub fixups: Vec<Fixup<'a>>,
+}
+
+impl<'a> ParseReport<'a> {
+    /// Number of instances which were parsed successfully
+    pub fn parsed(&self) -> usize {
+        self.total - self.errors.len()
+    }
+
+    /// Number of instances which failed to parse
+    pub fn failed(&self) -> usize {
+        self.errors.len()
+    }
+
+    /// Number of instances which were only parsed after being repaired
+    pub fn fixed(&self) -> usize {
+        let mut offsets: Vec<usize> = self.fixups.iter().map(|f| f.offset).collect();
+        offsets.dedup();
+        offsets.len()
+    }
+
+    /// Checks whether every instance was parsed successfully
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Sorts errors and fix-ups by offset and fills in their line numbers,
+    /// scanning `data` once for each.
+    pub(crate) fn finish(&mut self, data: &[u8]) {
+        self.errors.sort_by_key(|e| e.offset);
+        fill_lines(data, self.errors.iter_mut().map(|e| (e.offset, &mut e.line)));
+
+        // Stable sort, so that fix-ups within an instance stay in order
+        self.fixups.sort_by_key(|f| f.offset);
+        fill_lines(data, self.fixups.iter_mut().map(|f| (f.offset, &mut f.line)));
+    }
+}
+
+/// Given `(offset, line)` pairs sorted by offset, fills in each line number
+fn fill_lines<'b, I: Iterator<Item=(usize, &'b mut usize)>>(data: &[u8], iter: I) {
+    let mut newlines = memchr_iter(b'\n', data).peekable();
+    let mut line = 1;
+    for (offset, out) in iter {
+        while newlines.next_if(|&i| i < offset).is_some() {
+            line += 1;
+        }
+        *out = line;
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ap214::Entity, step_file::StepFile};
+
+    #[test]
+    fn test_parse_report() {
+        let data = b"ISO-10303-21;
+HEADER;
+ENDSEC;
+DATA;
+#1=CARTESIAN_POINT('',(0.,1.,2.));
+/* a comment */
+#2=CARTESIAN_POINT('',
+    (0.,$,2.));
+#3=NOT_A_REAL_ENTITY(1);
+#4=DIRECTION('',(1.,0.,0.));
+ENDSEC;
+END-ISO-10303-21;
+";
+        let (s, report) = StepFile::parse_with_report(data);
+        assert_eq!(report.total, 4);
+        assert_eq!(report.parsed(), 2);
+        assert_eq!(report.failed(), 2);
+
+        let e = &report.errors[0];
+        assert_eq!(e.id, Some(2));
+        assert_eq!(e.line, 7);
+        assert_eq!(&data[e.offset..e.offset + 3], b"#2=");
+        assert_eq!(e.keywords, vec!["CARTESIAN_POINT"]);
+
+        let e = &report.errors[1];
+        assert_eq!(e.id, Some(3));
+        assert_eq!(e.line, 9);
+        assert_eq!(&data[e.offset..e.offset + 3], b"#3=");
+        assert_eq!(e.keywords, vec!["NOT_A_REAL_ENTITY"]);
+        assert_eq!(e.kind, ErrorKind::Alt);
+
+        assert_eq!(s.len(), 4);
+        assert!(matches!(s.get(2), Some(Entity::Untyped(_))));
+    }
+
+    #[test]
+    fn test_parse_lenient() {
+        let data = b"ISO-10303-21;
+HEADER;
+ENDSEC;
+DATA;
+#1=CARTESIAN_POINT('',(0,1,2));
+#2=AXIS2_PLACEMENT_3D('',#1,#3);
+#3=DIRECTION('',(1.,0.,0.),7);
+#4=ADVANCED_FACE('',(#7),#8,.f.);
+#5=CIRCLE('',#2,$);
+#6=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.milli.,.Metre.));
+#9=B_SPLINE_CURVE_WITH_KNOTS('',3.,(#1,#1),.UNSPECIFIED.,.F.,.F.,(4,4),
+    (0.,1.),.unspecified.);
+#10=CIRCLE('',#2,(1.,$));
+#11=ADVANCED_FACE('',(#7),#8,$);
+ENDSEC;
+END-ISO-10303-21;
+";
+        let (s, strict) = StepFile::parse_with_report(data);
+        assert_eq!(strict.failed(), 8);
+        assert!(strict.fixups.is_empty());
+        assert!(matches!(s.get(4), Some(Entity::Untyped(_))));
+
+        let (s, report) = StepFile::parse_lenient(data);
+        assert_eq!(report.total, 9);
+        assert_eq!(report.failed(), 1, "{:?}", report.errors);
+        assert_eq!(report.errors[0].id, Some(10));
+        assert_eq!(report.fixed(), 7);
+
+        let fixes: Vec<(usize, &str, usize, Option<&str>, FixupKind)> = report.fixups.iter()
+            .map(|f| (f.id, f.keyword, f.index, f.attribute, f.kind))
+            .collect();
+        use FixupKind::*;
+        assert_eq!(fixes, vec![
+            (2, "AXIS2_PLACEMENT_3D", 3, Some("ref_direction"), MissingAttribute),
+            (3, "DIRECTION", 2, None, ExtraAttribute),
+            (4, "ADVANCED_FACE", 3, Some("same_sense"), EnumerationCase),
+            (5, "CIRCLE", 2, Some("radius"), NullValue),
+            (6, "SI_UNIT", 0, Some("prefix"), EnumerationCase),
+            (6, "SI_UNIT", 1, Some("name"), EnumerationCase),
+            (9, "B_SPLINE_CURVE_WITH_KNOTS", 1, Some("degree"), RealAsInteger),
+            (9, "B_SPLINE_CURVE_WITH_KNOTS", 8, Some("knot_spec"), EnumerationCase),
+            (11, "ADVANCED_FACE", 3, Some("same_sense"), NullValue),
+        ]);
+        assert_eq!(report.fixups[0].line, 6);
+        assert_eq!(&data[report.fixups[0].offset..][..3], b"#2=");
+
+        match s.get(2) {
+            Some(Entity::Axis2Placement3d(a)) => {
+                assert_eq!(a.axis.map(|i| i.0), Some(3));
+                assert!(a.ref_direction.is_none());
+            },
+            e => panic!("Invalid entity {:?}", e),
+        }
+        assert!(matches!(s.get(3), Some(Entity::Direction(_))));
+        match s.get(4) {
+            Some(Entity::AdvancedFace(f)) => assert!(!f.same_sense),
+            e => panic!("Invalid entity {:?}", e),
+        }
+        match s.get(5) {
+            Some(Entity::Circle(c)) => assert_eq!(c.radius.0.0.0, 0.0),
+            e => panic!("Invalid entity {:?}", e),
+        }
+        assert!(matches!(s.get(6), Some(Entity::ComplexEntity(v))
+            if matches!(v[..], [Entity::LengthUnit(_), Entity::SiUnit(_)])));
+        match s.get(9) {
+            Some(Entity::BSplineCurveWithKnots(c)) => assert_eq!(c.degree, 3),
+            e => panic!("Invalid entity {:?}", e),
+        }
+        assert!(matches!(s.get(10), Some(Entity::Untyped(_))));
+
+        // Valid files are parsed exactly as in strict mode
+        let (_, report) = StepFile::parse_lenient(
+            include_bytes!("../../examples/cube_hole.step"));
+        assert!(report.is_ok());
+        assert!(report.fixups.is_empty());
+    }
+
+    #[test]
+    fn test_parse_lenient_null_values() {
+        let data = b"DATA;
+#1=SWEPT_DISK_SOLID('',#2,$,$,$);
+#3=SHAPE_ASPECT('',$,#4,$);
+ENDSEC;";
+        let (s, report) = StepFile::parse_lenient(data);
+        assert!(report.is_ok(), "{:?}", report.errors);
+
+        let fixes: Vec<(usize, usize, Option<&str>, FixupKind)> = report.fixups.iter()
+            .map(|f| (f.id, f.index, f.attribute, f.kind))
+            .collect();
+        use FixupKind::*;
+        assert_eq!(fixes, vec![
+            (1, 2, Some("radius"), NullValue),
+            (1, 4, Some("start_param"), NullValue),
+            (1, 5, Some("end_param"), MissingAttribute),
+            (1, 5, Some("end_param"), NullValue),
+            (3, 3, Some("product_definitional"), NullValue),
+        ]);
+
+        match s.get(1) {
+            Some(Entity::SweptDiskSolid(d)) => {
+                assert_eq!(d.radius.0.0.0, 0.0);
+                assert!(d.inner_radius.is_none());
+                assert_eq!((d.start_param, d.end_param), (0.0, 0.0));
+            },
+            e => panic!("Invalid entity {:?}", e),
+        }
+        match s.get(3) {
+            Some(Entity::ShapeAspect(a)) => {
+                assert!(a.description.is_none());
+                assert_eq!(a.product_definitional.0, None);
+            },
+            e => panic!("Invalid entity {:?}", e),
+        }
+    }
+}
@@ -1,114 +1,463 @@
-use memchr::{memchr, memchr2, memchr_iter};
+use std::sync::OnceLock;
 use log::warn;
+use nom::error::ErrorKind;
 
 #[cfg(feature = "rayon")]
 use rayon::prelude::*;
 
 use crate::{
     ap214::Entity,
+    blocks::{
+        Sections, block_has_keyword, block_id, block_keywords, split_blocks,
+        split_sections,
+    },
+    external::{Anchor, Reference},
+    header::{DataSection, StepHeader},
     id::Id,
-    parse::{parse_entity_decl, parse_entity_fallback},
+    parse::{Parse, parse_entity_decl, parse_entity_fallback, parse_entity_lenient},
+    referrers::Referrers,
+    report::{Fixup, ParseError, ParseReport},
 };
 
 #[derive(Debug)]
-pub struct StepFile<'a>(pub Vec<Entity<'a>>);
+pub struct StepFile<'a> {
+    pub header: StepHeader<'a>,
+
+    /// DATA sections, in file order.  Entity ids are shared between
+    /// sections; use [`section_of`](Self::section_of) to find which section
+    /// contains a particular entity.
+    pub sections: Vec<DataSection<'a>>,
+
+    /// Values exported by ANCHOR sections, sorted by name
+    pub anchors: Vec<Anchor<'a>>,
+
+    /// External references from REFERENCE sections, sorted by id
+    pub references: Vec<Reference<'a>>,
+
+    /// Entity storage.  In dense mode, this is indexed by id; in sparse
+    /// mode, `entities[i]` has id `ids[i]`.  Entities are decoded from
+    /// `blocks` on first access, unless they were decoded up front.  Nothing
+    /// else may be stored here through `&self`, which `OwnedStepFile::file`
+    /// relies on.
+    entities: Vec<OnceLock<Entity<'a>>>,
+
+    /// Source text for each slot, or an empty slice for unused slots
+    blocks: Vec<&'a [u8]>,
+
+    /// Sorted ids for sparse storage, or empty for dense storage
+    ids: Vec<usize>,
+
+    /// Reverse reference index, built on first use
+    referrers: OnceLock<Referrers>,
+}
+
+/// An entity's id, its source text, and (if already decoded) the entity.
+/// Lazy loaders use `()` for the last element, which keeps entries small.
+pub(crate) type Entry<'a, E = Option<Entity<'a>>> = (usize, &'a [u8], E);
+
+/// The decoded part of an [`Entry`]
+pub(crate) trait Decoded<'a> {
+    fn into_entity(self) -> Option<Entity<'a>>;
+}
+impl<'a> Decoded<'a> for Option<Entity<'a>> {
+    fn into_entity(self) -> Option<Entity<'a>> {
+        self
+    }
+}
+impl<'a> Decoded<'a> for () {
+    fn into_entity(self) -> Option<Entity<'a>> {
+        None
+    }
+}
+
+/// Outcome of parsing a single instance in
+/// [`parse_reporting`](StepFile::parse_reporting)
+enum Parsed<'a> {
+    /// Parsed, possibly after lenient repairs
+    Ok(Entry<'a>, Vec<Fixup<'a>>),
+    /// Failed to parse, with the untyped fallback if the instance is at
+    /// least valid Part 21
+    Failed(ParseError<'a>, Option<Entity<'a>>),
+}
+
 impl<'a> StepFile<'a> {
-    /// Parses a STEP file from a raw array of bytes
-    /// `data` must be preprocessed by [`strip_flatten`] first
+    /// Parses a STEP file from a raw array of bytes, which may be borrowed
+    /// from a memory-mapped file.
+    ///
+    /// Entities which fail to parse with the schema are stored as
+    /// `Entity::Untyped` (keeping their parameters), or as
+    /// `Entity::_FailedToParse` if they aren't valid Part 21, and logged as
+    /// warnings; use [`parse_with_report`](Self::parse_with_report) to
+    /// inspect failures programmatically.
     pub fn parse(data: &'a [u8]) -> Self {
-        let blocks = Self::into_blocks(&data);
-        let data_start = blocks.iter()
-            .position(|b| b == b"DATA;")
-            .unwrap_or(0) + 1;
-        let data_end = blocks.iter()
-            .skip(data_start)
-            .position(|b| b == b"ENDSEC;")
-            .unwrap_or(0) + data_start;
+        let (out, report) = Self::parse_with_report(data);
+        for e in report.errors.iter() {
+            warn!("Failed to parse #{} ({}) at line {}: {:?}",
+                  e.id.map(|i| i.to_string()).unwrap_or_else(|| "?".to_owned()),
+                  e.keywords.join(", "), e.line, e.kind);
+        }
+        out
+    }
+
+    /// Returns a copy of `data`.  This used to remove comments and whitespace
+    /// before [`parse`](Self::parse), which now skips them while parsing, so
+    /// files no longer need to be flattened first.
+    #[deprecated(note = "StepFile::parse accepts raw files; pass the data directly")]
+    pub fn strip_flatten(data: &[u8]) -> Vec<u8> {
+        data.to_vec()
+    }
+
+    /// Parses a STEP file, returning a report which describes every entity
+    /// that failed to parse.  As with [`parse`](Self::parse), those entities
+    /// are stored as `Entity::Untyped` or `Entity::_FailedToParse` (or
+    /// dropped, if even their id is unreadable).
+    ///
+    /// Offsets and line numbers in the report refer to `data`.
+    pub fn parse_with_report(data: &'a [u8]) -> (Self, ParseReport<'a>) {
+        Self::parse_reporting(data, false)
+    }
+
+    /// Parses a STEP file like [`parse_with_report`](Self::parse_with_report),
+    /// but repairs instances which fail to parse because of common exporter
+    /// mistakes: missing trailing attributes, extra attributes, enumerations
+    /// in the wrong case, reals such as `3.` where an integer is expected,
+    /// and `$` in attributes which aren't optional.  Each repair is recorded
+    /// in the report's [`fixups`](ParseReport::fixups).
+    ///
+    /// Instances which parse as written are never changed.
+    pub fn parse_lenient(data: &'a [u8]) -> (Self, ParseReport<'a>) {
+        Self::parse_reporting(data, true)
+    }
+
+    fn parse_reporting(data: &'a [u8], lenient: bool) -> (Self, ParseReport<'a>) {
+        let blocks = split_blocks(data);
+        let sections = split_sections(&blocks);
+        let block_slice = &sections.data;
 
         // Parse every block, accumulating a Vec of Results.  We parse in
         // single-threaded mode in WASM builds, because there's no thread
         // pool.
         let block_iter = {
-            let block_slice = &blocks[data_start..data_end];
             #[cfg(feature = "rayon")]
             { block_slice.par_iter() }
             #[cfg(not(feature = "rayon"))]
             { block_slice.iter() }
         };
 
-        let parsed: Vec<(usize, Entity)> = block_iter
-            .filter_map(|b| parse_entity_decl(*b)
-                .or_else(|e| {
-                    warn!("Failed to parse {}: {:?}",
-                        std::str::from_utf8(b).unwrap_or("[INVALID UTF-8]"),
-                              e);
-                    parse_entity_fallback(*b)
+        let offset = |b: &[u8]| b.as_ptr() as usize - data.as_ptr() as usize;
+        let parsed: Vec<Parsed> = block_iter
+            .map(|b| parse_entity_decl(b)
+                .map(|(_, (i, e))| ((i, *b, Some(e)), vec![]))
+                .or_else(|e| match lenient.then(|| parse_entity_lenient(b)) {
+                    Some(Ok((_, (i, e, mut fixups)))) => {
+                        for f in fixups.iter_mut() {
+                            f.offset = offset(b);
+                        }
+                        Ok(((i, *b, Some(e)), fixups))
+                    },
+                    _ => Err(e),
                 })
-                .ok())
-            .map(|b| b.1)
+                .map(|(e, fixups)| Parsed::Ok(e, fixups))
+                .unwrap_or_else(|e| {
+                    let fallback = parse_entity_fallback(b).ok().map(|r| r.1);
+                    Parsed::Failed(ParseError {
+                        id: fallback.as_ref().map(|f| f.0),
+                        offset: offset(b),
+                        line: 0, // filled in below
+                        keywords: block_keywords(b),
+                        kind: match e {
+                            nom::Err::Error(e) | nom::Err::Failure(e) => e.code,
+                            nom::Err::Incomplete(_) => ErrorKind::Complete,
+                        },
+                    }, fallback.map(|f| f.1))
+                }))
             .collect();
 
-        // Awkward construction because `Entity` is not `Clone`
-        let max_id = parsed.iter().map(|b| b.0).max().unwrap_or(0);
-        let mut out: Vec<Entity> = (0..=max_id)
-            .map(|_| Entity::_EmptySlot)
+        let mut report = ParseReport {
+            total: block_slice.len(),
+            errors: vec![],
+            fixups: vec![],
+        };
+        let mut entries = Vec::with_capacity(parsed.len());
+        for (p, b) in parsed.into_iter().zip(block_slice.iter()) {
+            match p {
+                Parsed::Ok(e, fixups) => {
+                    entries.push(e);
+                    report.fixups.extend(fixups);
+                },
+                Parsed::Failed(e, fallback) => {
+                    if let Some(i) = e.id {
+                        entries.push((i, *b, fallback));
+                    }
+                    report.errors.push(e);
+                }
+            }
+        }
+        report.finish(data);
+
+        (Self::from_entries(&sections, entries), report)
+    }
+
+    /// Indexes a STEP file without decoding any entities.
+    ///
+    /// Only the HEADER section and the `#id` of each entity are parsed up
+    /// front.  Each entity is decoded the first time that it's accessed,
+    /// then cached; entities which fail to decode are stored as
+    /// `Entity::Untyped` or `Entity::_FailedToParse`, as in
+    /// [`parse`](Self::parse).  This is much faster for callers which only
+    /// touch a fraction of the file.
+    ///
+    /// Iterating over [`entities`](Self::entities) decodes every entity;
+    /// use [`entities_with_keyword`](Self::entities_with_keyword) to only
+    /// decode entities of a particular type.
+    pub fn parse_lazy(data: &'a [u8]) -> Self {
+        let blocks = split_blocks(data);
+        let sections = split_sections(&blocks);
+        let block_iter = {
+            let block_slice = &sections.data;
+            #[cfg(feature = "rayon")]
+            { block_slice.par_iter() }
+            #[cfg(not(feature = "rayon"))]
+            { block_slice.iter() }
+        };
+        let entries: Vec<Entry<()>> = block_iter
+            .filter_map(|b| block_id(b).map(|i| (i, *b, ())))
             .collect();
+        Self::from_entries(&sections, entries)
+    }
 
-        for p in parsed.into_iter() {
-            out[p.0] = p.1;
-        }
+    /// Parses everything other than the entities themselves: the HEADER
+    /// section, the opening of each DATA section, and any ANCHOR and
+    /// REFERENCE sections.
+    fn parse_sections(sections: &Sections<'a>) -> Self {
+        let header = StepHeader::from_blocks(&sections.header);
+        let addr = |b: &[u8]| b.as_ptr() as usize;
+        let data_sections = sections.data_sections.iter()
+            .map(|(b, r)| {
+                let s = std::str::from_utf8(b).ok()
+                    .and_then(|s| DataSection::parse(s).ok())
+                    .map(|(_, d)| d);
+                if s.is_none() {
+                    warn!("Failed to parse DATA section header {:?}",
+                          String::from_utf8_lossy(b));
+                }
+                // The span runs from the opening block to the end of the
+                // last block in the section
+                let end = sections.data[r.clone()].last().unwrap_or(b);
+                DataSection {
+                    span: addr(b)..addr(end) + end.len(),
+                    ..s.unwrap_or_default()
+                }
+            })
+            .collect();
+
+        let mut anchors: Vec<Anchor> = sections.anchor.iter()
+            .filter_map(|b| {
+                let a = Anchor::from_block(b);
+                if a.is_none() {
+                    warn!("Failed to parse anchor {:?}", String::from_utf8_lossy(b));
+                }
+                a
+            })
+            .collect();
+        anchors.sort_by(|a, b| a.name.cmp(b.name));
 
-        Self(out)
+        let mut references: Vec<Reference> = sections.reference.iter()
+            .filter_map(|b| {
+                let r = Reference::from_block(b);
+                if r.is_none() {
+                    warn!("Failed to parse reference {:?}", String::from_utf8_lossy(b));
+                }
+                r
+            })
+            .collect();
+        references.sort_by_key(|r| r.id);
+
+        Self {
+            header,
+            sections: data_sections,
+            anchors,
+            references,
+            entities: vec![],
+            blocks: vec![],
+            ids: vec![],
+            referrers: OnceLock::new(),
+        }
     }
 
-    /// Flattens a STEP file, removing comments and whitespace
-    pub fn strip_flatten(data: &[u8]) -> Vec<u8> {
-        let mut out = Vec::with_capacity(data.len());
-        let mut i = 0;
-        while i < data.len() {
-            match data[i] {
-                b'/' => if i + 1 < data.len() && data[i + 1] == b'*' {
-                    for j in memchr_iter(b'/', &data[i + 2..]) {
-                        if data[i + j + 1] == b'*' {
-                            i += j + 2;
-                            break;
-                        }
-                    }
+    /// Builds the entity storage from a list of entries.  If an id appears
+    /// more than once, the last entry wins.
+    ///
+    /// Storage is dense (indexed directly by id) unless the ids are mostly
+    /// unused, e.g. a few thousand entities numbered in the hundreds of
+    /// millions, in which case entities are packed into consecutive slots
+    /// with a sorted id table alongside them.
+    pub(crate) fn from_entries<E: Decoded<'a>>(sections: &Sections<'a>,
+                                               mut entries: Vec<Entry<'a, E>>)
+        -> Self
+    {
+        let out = Self::parse_sections(sections);
+        let max_id = entries.iter().map(|e| e.0).max().unwrap_or(0);
+        if max_id <= 2 * entries.len() + 1024 {
+            let mut entities: Vec<OnceLock<Entity>> = (0..=max_id)
+                .map(|_| OnceLock::new())
+                .collect();
+            let mut blocks = vec![&[][..]; max_id + 1];
+            for (i, b, e) in entries.into_iter() {
+                // Skip writing empty slots, which is slow for large files
+                let e = e.into_entity();
+                if e.is_some() || entities[i].get().is_some() {
+                    entities[i] = e.map(OnceLock::from).unwrap_or_default();
                 }
-                // TODO: don't skip whitespace inside of strings
-                c if c.is_ascii_whitespace() => (),
-                c => out.push(c),
+                blocks[i] = b;
             }
-            i += 1;
+            return Self { entities, blocks, ..out };
         }
-        out
-    }
 
-    /// Splits a STEP file into individual blocks.  The input must be pre-processed
-    /// by [`strip_flatten`] beforehand.
-    fn into_blocks(data: &[u8]) -> Vec<&[u8]> {
-        let mut blocks = Vec::new();
-        let mut i = 0;
-        let mut start = 0;
-        while i < data.len() {
-            let next = memchr2(b'\'', b';', &data[i..]).unwrap();
-            match data[i + next] {
-                // Skip over quoted blocks
-                b'\'' => i += next + memchr(b'\'', &data[i + next..]).unwrap() + 1,
-                b';' => {
-                    blocks.push(&data[start..=(i + next)]);
-
-                    i += next + 1; // Skip the semicolon
-                    start = i;
-                },
-                _ => unreachable!(),
+        // Stable sort, so that duplicates stay in file order
+        entries.sort_by_key(|e| e.0);
+        let mut ids: Vec<usize> = Vec::with_capacity(entries.len());
+        let mut entities = Vec::with_capacity(entries.len());
+        let mut blocks = Vec::with_capacity(entries.len());
+        for (i, b, e) in entries.into_iter() {
+            if ids.last() == Some(&i) {
+                ids.pop();
+                entities.pop();
+                blocks.pop();
             }
+            ids.push(i);
+            entities.push(e.into_entity().map(OnceLock::from).unwrap_or_default());
+            blocks.push(b);
+        }
+        Self { entities, blocks, ids, ..out }
+    }
+
+    /// Returns the storage slot for the given id, if present
+    fn slot(&self, id: usize) -> Option<usize> {
+        if self.ids.is_empty() {
+            if id < self.entities.len() { Some(id) } else { None }
+        } else {
+            self.ids.binary_search(&id).ok()
         }
-        blocks
     }
 
-    pub fn entity<T: FromEntity<'a>>(&'a self, i: Id<T>) -> Option<&'a T> {
-        T::try_from_entity(&self.0[i.0])
+    /// Returns the id stored in a particular slot
+    fn slot_id(&self, slot: usize) -> usize {
+        if self.ids.is_empty() { slot } else { self.ids[slot] }
+    }
+
+    /// Returns the entity in a particular slot, decoding it if necessary
+    fn decode(&self, slot: usize) -> &Entity<'a> {
+        self.entities[slot].get_or_init(|| {
+            let b = self.blocks[slot];
+            if b.is_empty() {
+                Entity::_EmptySlot
+            } else {
+                parse_entity_decl(b)
+                    .or_else(|_| parse_entity_fallback(b))
+                    .map(|(_, (_, e))| e)
+                    .unwrap_or(Entity::_FailedToParse)
+            }
+        })
+    }
+
+    /// Decodes every entity which hasn't yet been decoded (in parallel, if
+    /// the `parallel` feature is enabled).  This is only useful for files
+    /// loaded with [`parse_lazy`](Self::parse_lazy).
+    pub fn decode_all(&self) {
+        let slots = 0..self.blocks.len();
+        #[cfg(feature = "rayon")]
+        slots.into_par_iter().for_each(|i| { self.decode(i); });
+        #[cfg(not(feature = "rayon"))]
+        slots.for_each(|i| { self.decode(i); });
+    }
+
+    pub(crate) fn referrers_index(&self) -> &OnceLock<Referrers> {
+        &self.referrers
+    }
+
+    /// Checks whether entities are stored in compact (sparse) mode
+    pub fn is_sparse(&self) -> bool {
+        !self.ids.is_empty()
+    }
+
+    /// Looks up an entity by id, returning `None` for unused ids
+    pub fn get(&self, id: usize) -> Option<&Entity<'a>> {
+        self.slot(id)
+            .filter(|i| !self.blocks[*i].is_empty())
+            .map(|i| self.decode(i))
+    }
+
+    /// Returns the source text of an entity (from its `#id` to the
+    /// terminating semicolon), if present
+    pub fn source(&self, id: usize) -> Option<&'a [u8]> {
+        self.slot(id)
+            .map(|i| self.blocks[i])
+            .filter(|b| !b.is_empty())
+    }
+
+    /// Iterates over every entity in the file, in order of increasing id
+    pub fn entities(&self) -> impl Iterator<Item=(usize, &Entity<'a>)> {
+        (0..self.blocks.len())
+            .filter(move |i| !self.blocks[*i].is_empty())
+            .map(move |i| (self.slot_id(i), self.decode(i)))
+    }
+
+    /// Iterates over the source text of every entity (from its `#id` to the
+    /// terminating semicolon), in order of increasing id, without decoding
+    pub fn sources(&self) -> impl Iterator<Item=(usize, &'a [u8])> + '_ {
+        (0..self.blocks.len())
+            .filter(move |i| !self.blocks[*i].is_empty())
+            .map(move |i| (self.slot_id(i), self.blocks[i]))
+    }
+
+    /// Iterates over entities whose instance includes the given keyword,
+    /// either as a simple instance or as one part of a complex instance.
+    /// Complex instances may be decoded into a different entity type (e.g.
+    /// a subtype of `keyword`), so callers should check the result.
+    ///
+    /// For lazily-loaded files, only matching entities are decoded.
+    pub fn entities_with_keyword<'s>(&'s self, keyword: &'s str)
+        -> impl Iterator<Item=(usize, &'s Entity<'a>)>
+    {
+        (0..self.blocks.len())
+            .filter(move |i| block_has_keyword(self.blocks[*i], keyword))
+            .map(move |i| (self.slot_id(i), self.decode(i)))
+    }
+
+    /// Returns the number of entities in the file
+    pub fn len(&self) -> usize {
+        self.blocks.iter().filter(|b| !b.is_empty()).count()
+    }
+
+    /// Checks whether the file has no entities
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the index (in [`sections`](Self::sections)) of the DATA
+    /// section which contains the given entity
+    pub fn section_of(&self, id: usize) -> Option<usize> {
+        let b = self.source(id)?.as_ptr() as usize;
+        self.sections.iter().position(|s| s.span.contains(&b))
+    }
+
+    /// Looks up an entity of a particular type, which may be a component
+    /// of a complex entity instance
+    pub fn entity<'s, T: FromEntity<'s>>(&'s self, i: Id<T>) -> Option<&'s T> {
+        self.get(i.0).and_then(|e| T::try_from_entity(e))
+    }
+
+    /// Iterates over every instance of `T` in the file (including components
+    /// of complex entity instances), in order of increasing id.
+    ///
+    /// For lazily-loaded files, only entities which could contain a `T` are
+    /// decoded.
+    pub fn iter<'s, T: FromEntity<'s> + 's>(&'s self)
+        -> impl Iterator<Item=(Id<T>, &'s T)> + use<'a, 's, T>
+    {
+        self.entities_with_keyword(T::KEYWORD)
+            .filter_map(|(i, e)| T::try_from_entity(e).map(|t| (Id::new(i), t)))
     }
 }
 
@@ -116,10 +465,150 @@ impl<'a, T> std::ops::Index<Id<T>> for StepFile<'a> {
     type Output = Entity<'a>;
 
     fn index(&self, id: Id<T>) -> &Self::Output {
-        &self.0[id.0]
+        self.get(id.0).unwrap_or(&Entity::_EmptySlot)
     }
 }
 
 pub trait FromEntity<'a> {
+    /// Part 21 keyword for this entity type
+    const KEYWORD: &'static str;
+
+    /// Extracts this type from an entity, which is either a simple instance
+    /// of the type or a complex instance with the type as one component
     fn try_from_entity(e: &'a Entity<'a>) -> Option<&'a Self>;
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ap214::{
+        AdvancedFace_, CartesianPoint_, Direction_, GeometricRepresentationContext_,
+        GlobalUnitAssignedContext_, LengthUnit_, SiUnit_, SiUnitName,
+    };
+
+    #[test]
+    fn test_sparse_storage() {
+        let s = StepFile::parse(b"DATA;
+            #400000000=CARTESIAN_POINT('',(0.,1.,2.));
+            #7=DIRECTION('',(1.,0.,0.));
+            #123456789=CARTESIAN_POINT('',(3.,4.,5.));
+            ENDSEC;");
+        assert!(s.is_sparse());
+        assert_eq!(s.len(), 3);
+        assert_eq!(s.entities().map(|(i, _)| i).collect::<Vec<_>>(),
+                   vec![7, 123456789, 400000000]);
+
+        let p = s.entity(Id::<CartesianPoint_>::new(123456789)).unwrap();
+        assert_eq!(p.coordinates[2].0, 5.0);
+        assert!(s.entity(Id::<Direction_>::new(7)).is_some());
+        assert!(s.entity(Id::<Direction_>::new(8)).is_none());
+        assert!(matches!(s[Id::<()>::new(400000000)], Entity::CartesianPoint(_)));
+        assert!(matches!(s[Id::<()>::new(5)], Entity::_EmptySlot));
+    }
+
+    #[test]
+    fn test_dense_storage() {
+        let s = StepFile::parse(b"DATA;
+            #1=DIRECTION('',(1.,0.,0.));
+            #3=DIRECTION('',(0.,1.,0.));
+            ENDSEC;");
+        assert!(!s.is_sparse());
+        assert_eq!(s.entities().map(|(i, _)| i).collect::<Vec<_>>(), vec![1, 3]);
+        assert!(s.get(2).is_none());
+        assert!(s.get(1000).is_none());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_strip_flatten() {
+        // Callers which still flatten first get the same result
+        let data = include_bytes!("../../examples/cube_hole.step");
+        let flat = StepFile::strip_flatten(data);
+        let (a, b) = (StepFile::parse(&flat), StepFile::parse(data));
+        assert_eq!(format!("{:?}", a.entities().collect::<Vec<_>>()),
+                   format!("{:?}", b.entities().collect::<Vec<_>>()));
+    }
+
+    #[test]
+    fn test_entity_size() {
+        // Large entities are boxed, so that storage stays small for files
+        // with millions of (mostly small) entities
+        assert!(std::mem::size_of::<Entity>() <= 64);
+
+        let s = StepFile::parse(include_bytes!("../../examples/cube_hole.step"));
+        assert!(matches!(s.get(125), Some(Entity::AdvancedFace(_))));
+        let f = s.entity(Id::<AdvancedFace_>::new(126)).unwrap();
+        assert_eq!(f.bounds.len(), 1);
+        assert!(!f.same_sense);
+    }
+
+    #[test]
+    fn test_lazy() {
+        let s = StepFile::parse_lazy(b"ISO-10303-21;
+HEADER;
+FILE_SCHEMA(('AUTOMOTIVE_DESIGN'));
+ENDSEC;
+DATA;
+#1=DIRECTION('',(1.,0.,0.));
+#2=CARTESIAN_POINT('',(0.,1.,2.));
+#3=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));
+#4=CARTESIAN_POINT('',(oops));
+ENDSEC;
+END-ISO-10303-21;");
+        assert!(s.header.schema.is_some());
+        assert_eq!(s.len(), 4);
+        assert!(s.entities.iter().all(|e| e.get().is_none()));
+
+        let p: Vec<usize> = s.entities_with_keyword("CARTESIAN_POINT")
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(p, vec![2, 4]);
+        assert!(s.entities[1].get().is_none());
+        assert!(s.entities[3].get().is_none());
+        assert!(matches!(s.entities[2].get(), Some(Entity::CartesianPoint(_))));
+        assert!(matches!(s.entities[4].get(), Some(Entity::_FailedToParse)));
+
+        assert!(matches!(&s[Id::<()>::new(3)], Entity::ComplexEntity(v)
+            if matches!(v[..], [Entity::LengthUnit(_), Entity::SiUnit(_)])));
+        assert!(s.entity(Id::<Direction_>::new(1)).is_some());
+        assert_eq!(s.source(1), Some(&b"#1=DIRECTION('',(1.,0.,0.));"[..]));
+    }
+
+    #[test]
+    fn test_iter() {
+        let s = StepFile::parse(b"DATA;
+            #1=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));
+            #2=(GEOMETRIC_REPRESENTATION_CONTEXT(3)
+                GLOBAL_UNCERTAINTY_ASSIGNED_CONTEXT((#3))
+                GLOBAL_UNIT_ASSIGNED_CONTEXT((#1))
+                REPRESENTATION_CONTEXT('',''));
+            #4=GEOMETRIC_REPRESENTATION_CONTEXT('','',2);
+            #5=CARTESIAN_POINT('',(0.,1.,2.));
+            ENDSEC;");
+        assert!(matches!(s[Id::<()>::new(2)], Entity::ComplexEntity(_)));
+
+        // Components of complex entities are found by both iter and entity
+        let v: Vec<(usize, i64)> = s.iter::<GeometricRepresentationContext_>()
+            .map(|(i, e)| (i.0, e.coordinate_space_dimension.0))
+            .collect();
+        assert_eq!(v, vec![(2, 3), (4, 2)]);
+        let u = s.entity(Id::<GlobalUnitAssignedContext_>::new(2)).unwrap();
+        assert_eq!(u.units.len(), 1);
+        assert!(s.entity(Id::<GlobalUnitAssignedContext_>::new(4)).is_none());
+
+        let p: Vec<usize> = s.iter::<CartesianPoint_>().map(|(i, _)| i.0).collect();
+        assert_eq!(p, vec![5]);
+        assert_eq!(s.iter::<Direction_>().count(), 0);
+
+        // Parts with no attributes of their own are typed as well, with the
+        // attributes derived by another part left empty
+        let v: Vec<usize> = s.iter::<LengthUnit_>().map(|(i, _)| i.0).collect();
+        assert_eq!(v, vec![1]);
+        let u = s.entity(Id::<LengthUnit_>::new(1)).unwrap();
+        assert!(u.dimensions.is_none());
+        let u = s.entity(Id::<SiUnit_>::new(1)).unwrap();
+        assert!(matches!(u.name, SiUnitName::Metre));
+    }
+}
@@ -0,0 +1,328 @@
+//! Validation of the references between entities.
+//!
+//! Ids in the generated structs ([`Id<T>`](crate::id::Id)) aren't checked
+//! when a file is parsed, so a reference to a missing entity (or to an entity
+//! of the wrong type) only shows up when it's followed.
+//! [`StepFile::validate`] checks every reference up front, using the types
+//! from [`attribute_targets`], and finds reference cycles.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use crate::{
+    ap214::{Entity, attribute_names, attribute_owners, attribute_targets},
+    blocks::block_keywords,
+    step_file::StepFile,
+    write::entity_records,
+};
+
+/// A single problem found by [`StepFile::validate`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Issue<'a> {
+    /// A reference to an id which isn't defined in the file, either as an
+    /// entity or as an external reference
+    Dangling {
+        /// Entity containing the reference
+        id: usize,
+        /// Attribute containing the reference
+        attribute: &'static str,
+        /// Undefined id
+        target: usize,
+    },
+    /// A reference to an entity which isn't one of the types allowed by the
+    /// attribute (or a subtype of one of them)
+    WrongType {
+        id: usize,
+        attribute: &'static str,
+        target: usize,
+        /// Types allowed by the attribute
+        expected: &'static [&'static str],
+        /// Keywords of the referenced entity
+        found: Vec<&'a str>,
+    },
+    /// Entities which refer to each other in a loop, in reference order
+    /// (starting with the lowest id), e.g. `[1, 2]` for `#1 -> #2 -> #1`
+    Cycle {
+        ids: Vec<usize>,
+    },
+}
+
+impl fmt::Display for Issue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::Dangling { id, attribute, target } =>
+                write!(f, "#{}.{}: reference to undefined #{}", id, attribute, target),
+            Issue::WrongType { id, attribute, target, expected, found } =>
+                write!(f, "#{}.{}: #{} is {}, expected {}", id, attribute, target,
+                       if found.is_empty() { "unknown".to_owned() } else { found.join("+") },
+                       expected.join(" or ")),
+            Issue::Cycle { ids } => {
+                write!(f, "reference cycle ")?;
+                for i in ids {
+                    write!(f, "#{} -> ", i)?;
+                }
+                write!(f, "#{}", ids[0])
+            },
+        }
+    }
+}
+
+/// Result of [`StepFile::validate`]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ValidationReport<'a> {
+    /// Number of entities whose references were checked.  Entities which
+    /// failed to parse are skipped.
+    pub checked: usize,
+    /// Problems found, sorted by entity id, with cycles last
+    pub issues: Vec<Issue<'a>>,
+}
+
+impl<'a> ValidationReport<'a> {
+    /// Checks whether no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Number of dangling references
+    pub fn dangling(&self) -> usize {
+        self.issues.iter().filter(|i| matches!(i, Issue::Dangling { .. })).count()
+    }
+
+    /// Number of references to entities of the wrong type
+    pub fn wrong_type(&self) -> usize {
+        self.issues.iter().filter(|i| matches!(i, Issue::WrongType { .. })).count()
+    }
+
+    /// Number of reference cycles
+    pub fn cycles(&self) -> usize {
+        self.issues.iter().filter(|i| matches!(i, Issue::Cycle { .. })).count()
+    }
+}
+
+impl<'a> StepFile<'a> {
+    /// Checks every reference in the file, reporting references to missing
+    /// ids, references to entities of the wrong type, and reference cycles.
+    /// This decodes every entity.
+    ///
+    /// References in attributes whose type is a SELECT of only entities are
+    /// parsed as untyped ids, so they're only checked for existence.
+    pub fn validate(&self) -> ValidationReport<'a> {
+        let mut out = ValidationReport::default();
+        let mut refs = vec![];
+        for (id, e) in self.entities() {
+            let records = entity_records(e);
+            if records.is_empty() || matches!(e, Entity::Untyped(_)) {
+                continue;
+            }
+            out.checked += 1;
+            let complex = matches!(e, Entity::ComplexEntity(_));
+            for r in records {
+                let names = attribute_names(r.keyword);
+                let targets = attribute_targets(r.keyword);
+                let owners = attribute_owners(r.keyword);
+
+                // Parts of a complex entity only hold their own attributes
+                let indices: Vec<usize> = (0..names.len())
+                    .filter(|i| !complex || owners.get(*i).is_none_or(|o| *o == r.keyword))
+                    .collect();
+                for (i, p) in indices.into_iter().zip(r.params.iter()) {
+                    refs.clear();
+                    p.append_refs(&mut refs);
+                    for target in refs.iter().copied() {
+                        let attribute = names[i];
+                        let b = match self.source(target) {
+                            Some(b) => b,
+                            None if self.reference(target).is_some() => continue,
+                            None => {
+                                out.issues.push(Issue::Dangling { id, attribute, target });
+                                continue;
+                            },
+                        };
+                        let expected = targets.get(i).copied().unwrap_or(&[]);
+                        if !expected.is_empty()
+                            && !expected.iter().any(|t| self.instance_of(target, t))
+                        {
+                            out.issues.push(Issue::WrongType {
+                                id, attribute, target, expected,
+                                found: block_keywords(b),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        out.issues.extend(self.cycles().into_iter().map(|ids| Issue::Cycle { ids }));
+        out
+    }
+
+    /// Finds reference cycles, returning one cycle per strongly connected
+    /// component of the reference graph
+    fn cycles(&self) -> Vec<Vec<usize>> {
+        let ids: Vec<usize> = self.sources().map(|(i, _)| i).collect();
+        let index: HashMap<usize, usize> = ids.iter().enumerate().map(|(n, i)| (*i, n)).collect();
+        let edges: Vec<Vec<usize>> = ids.iter()
+            .map(|i| self.get(*i)
+                .map(|e| e.upstream().into_iter().filter_map(|j| index.get(&j).copied()).collect())
+                .unwrap_or_default())
+            .collect();
+
+        // Tarjan's algorithm, with an explicit stack to handle deep graphs
+        const UNSEEN: usize = usize::MAX;
+        let n = ids.len();
+        let mut order = vec![UNSEEN; n];
+        let mut low = vec![0; n];
+        let mut on_stack = vec![false; n];
+        let mut stack = vec![];
+        let mut next = 0;
+        let mut out = vec![];
+        for root in 0..n {
+            if order[root] != UNSEEN {
+                continue;
+            }
+            let mut calls = vec![(root, 0)];
+            order[root] = next;
+            low[root] = next;
+            next += 1;
+            stack.push(root);
+            on_stack[root] = true;
+            while let Some((v, i)) = calls.last_mut() {
+                let v = *v;
+                if let Some(&w) = edges[v].get(*i) {
+                    *i += 1;
+                    if order[w] == UNSEEN {
+                        order[w] = next;
+                        low[w] = next;
+                        next += 1;
+                        stack.push(w);
+                        on_stack[w] = true;
+                        calls.push((w, 0));
+                    } else if on_stack[w] {
+                        low[v] = low[v].min(order[w]);
+                    }
+                    continue;
+                }
+                calls.pop();
+                if let Some((u, _)) = calls.last() {
+                    low[*u] = low[*u].min(low[v]);
+                }
+                if low[v] == order[v] {
+                    let mut component = vec![];
+                    while let Some(w) = stack.pop() {
+                        on_stack[w] = false;
+                        component.push(w);
+                        if w == v {
+                            break;
+                        }
+                    }
+                    if component.len() > 1 || edges[v].contains(&v) {
+                        out.push(cycle_in(&component, &edges));
+                    }
+                }
+            }
+        }
+        let mut out: Vec<Vec<usize>> = out.into_iter()
+            .map(|c| c.into_iter().map(|n| ids[n]).collect())
+            .collect();
+        out.sort();
+        out
+    }
+}
+
+/// Returns the shortest cycle through the first node (in id order) of a
+/// strongly connected component
+fn cycle_in(component: &[usize], edges: &[Vec<usize>]) -> Vec<usize> {
+    let members: HashSet<usize> = component.iter().copied().collect();
+    let start = *component.iter().min().unwrap();
+    let mut parent = HashMap::new();
+    let mut todo = VecDeque::from(vec![start]);
+    while let Some(v) = todo.pop_front() {
+        for &w in &edges[v] {
+            if w == start {
+                let mut path = vec![v];
+                while let Some(&p) = parent.get(path.last().unwrap()) {
+                    path.push(p);
+                }
+                path.reverse();
+                return path;
+            }
+            if members.contains(&w) && !parent.contains_key(&w) {
+                parent.insert(w, v);
+                todo.push_back(w);
+            }
+        }
+    }
+    unreachable!("Strongly connected component without a cycle");
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_ok() {
+        let s = StepFile::parse(include_bytes!("../../examples/cube_hole.step"));
+        let report = s.validate();
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(report.checked, s.len());
+    }
+
+    #[test]
+    fn test_validate() {
+        let s = StepFile::parse(b"ISO-10303-21;
+HEADER;
+ENDSEC;
+REFERENCE;
+#50=<other.stp#origin>;
+ENDSEC;
+DATA;
+#1=CARTESIAN_POINT('',(0.,0.,0.));
+#2=DIRECTION('',(0.,0.,1.));
+#3=AXIS2_PLACEMENT_3D('',#1,#2,#99);
+#4=AXIS2_PLACEMENT_3D('',#2,#2,$);
+#5=AXIS2_PLACEMENT_3D('',#50,#2,$);
+#6=(GEOMETRIC_REPRESENTATION_CONTEXT(3)GLOBAL_UNIT_ASSIGNED_CONTEXT((#7,#98))
+REPRESENTATION_CONTEXT('',''));
+#7=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));
+#30=SHAPE_REPRESENTATION('',(#31,#3),#6);
+#31=MAPPED_ITEM('',#32,#3);
+#32=REPRESENTATION_MAP(#3,#30);
+#40=SHAPE_REPRESENTATION('',(#4),#1);
+#41=BROKEN_ENTITY(#99);
+ENDSEC;
+END-ISO-10303-21;");
+        let report = s.validate();
+        assert_eq!(report.checked, 11);
+        assert_eq!(report.issues, vec![
+            Issue::Dangling { id: 3, attribute: "ref_direction", target: 99 },
+            Issue::WrongType {
+                id: 4, attribute: "location", target: 2,
+                expected: &["CARTESIAN_POINT"], found: vec!["DIRECTION"],
+            },
+            Issue::Dangling { id: 6, attribute: "units", target: 98 },
+            Issue::WrongType {
+                id: 40, attribute: "context_of_items", target: 1,
+                expected: &["REPRESENTATION_CONTEXT"], found: vec!["CARTESIAN_POINT"],
+            },
+            Issue::Cycle { ids: vec![30, 31, 32] },
+        ]);
+        assert_eq!((report.dangling(), report.wrong_type(), report.cycles()), (2, 2, 1));
+        assert_eq!(report.issues[1].to_string(),
+                   "#4.location: #2 is DIRECTION, expected CARTESIAN_POINT");
+        assert_eq!(report.issues[4].to_string(),
+                   "reference cycle #30 -> #31 -> #32 -> #30");
+    }
+
+    #[test]
+    fn test_self_reference() {
+        let s = StepFile::parse(b"DATA;
+#1=SHAPE_REPRESENTATION('',(#1),#2);
+#2=REPRESENTATION_CONTEXT('','');
+ENDSEC;");
+        let report = s.validate();
+        assert_eq!(report.issues.len(), 2);
+        assert!(matches!(report.issues[0], Issue::WrongType { target: 1, .. }));
+        assert_eq!(report.issues[1], Issue::Cycle { ids: vec![1] });
+    }
+}
@@ -0,0 +1,376 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Write};
+
+use crate::{
+    ap214::{Entity, attribute_owners, superclasses_of},
+    header::{DataSection, StepHeader},
+    param::{Parameter, Record},
+    step_file::StepFile,
+};
+
+/// Converts an entity into one or more Part 21 records.
+///
+/// Simple entities produce a single record.  Complex entities are split into
+/// one record per entity type in the instance (including supertypes without
+/// any attributes), sorted alphabetically as required by the external
+/// mapping.  Untyped entities produce their records as parsed, and
+/// placeholder entities produce no records.
+pub fn entity_records<'b>(e: &'b Entity) -> Vec<Record<'b>> {
+    let leafs = match e {
+        Entity::ComplexEntity(v) => v,
+        Entity::Untyped(v) => return v.clone(),
+        e => return e.to_record().into_iter().collect(),
+    };
+
+    let mut parts: BTreeMap<&str, Vec<Parameter>> = BTreeMap::new();
+    let mut filled = HashSet::new();
+    for leaf in leafs {
+        let r = match leaf.to_record() {
+            Some(r) => r,
+            None => continue,
+        };
+        // Make sure that every supertype of this leaf appears in the output,
+        // even if it doesn't contribute any parameters
+        let mut todo = vec![r.keyword];
+        while let Some(t) = todo.pop() {
+            parts.entry(t).or_default();
+            todo.extend(superclasses_of(t).iter());
+        }
+
+        // Split parameters by the entity which declares them.  Supertypes
+        // shared between multiple leafs are only written once.
+        let owners = attribute_owners(r.keyword);
+        let mut leaf_parts: BTreeMap<&str, Vec<Parameter>> = BTreeMap::new();
+        for (i, p) in r.params.into_iter().enumerate() {
+            let owner = owners.get(i).copied().unwrap_or(r.keyword);
+            leaf_parts.entry(owner).or_default().push(p);
+        }
+        for (owner, params) in leaf_parts.into_iter() {
+            if filled.insert(owner) {
+                parts.insert(owner, params);
+            }
+        }
+    }
+    parts.into_iter()
+        .map(|(keyword, params)| Record { keyword, params })
+        .collect()
+}
+
+pub(crate) fn header_records<'b>(h: &'b StepHeader) -> Vec<Record<'b>> {
+    let string = |s: &'b Cow<str>| Parameter::String(Cow::Borrowed(s));
+    let strs = |v: &'b [Cow<str>]| Parameter::List(v.iter().map(string).collect());
+    let empty = || Parameter::String(Cow::Borrowed(""));
+    let description = match &h.description {
+        Some(d) => vec![strs(&d.description),
+                        string(&d.implementation_level)],
+        None => vec![Parameter::List(vec![empty()]),
+                     Parameter::String(Cow::Borrowed("2;1"))],
+    };
+    let name = match &h.name {
+        Some(n) => vec![
+            string(&n.name),
+            string(&n.time_stamp),
+            strs(&n.author),
+            strs(&n.organization),
+            string(&n.preprocessor_version),
+            string(&n.originating_system),
+            string(&n.authorization),
+        ],
+        None => vec![
+            empty(),
+            empty(),
+            Parameter::List(vec![empty()]),
+            Parameter::List(vec![empty()]),
+            empty(),
+            empty(),
+            empty(),
+        ],
+    };
+    let schema = match &h.schema {
+        Some(s) => vec![strs(&s.schema_identifiers)],
+        None => vec![Parameter::List(
+            vec![Parameter::String(Cow::Borrowed("AUTOMOTIVE_DESIGN"))])],
+    };
+    vec![
+        Record { keyword: "FILE_DESCRIPTION", params: description },
+        Record { keyword: "FILE_NAME", params: name },
+        Record { keyword: "FILE_SCHEMA", params: schema },
+    ]
+}
+
+impl<'a> StepFile<'a> {
+    /// Writes the file in Part 21 format.
+    ///
+    /// Entities are renumbered sequentially, starting from `#1`, followed by
+    /// any external references.  Entities which failed to parse are not
+    /// written, and references to them are replaced with `$`.  ANCHOR,
+    /// REFERENCE, and (multiple) DATA sections are preserved.
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        self.write_entities(self.entities(), |_| true, out)
+    }
+
+    /// Writes a subset of the file's entities in Part 21 format, renumbering
+    /// them as in [`write`](Self::write).  References to entities outside of
+    /// the subset are replaced with `$`, so the subset should usually be
+    /// closed under [`Entity::upstream`]; see [`closure`](Self::closure).
+    ///
+    /// External references and anchors are only written if their ids are
+    /// in the subset.
+    pub fn write_subset<W: Write>(&self, ids: &[usize], out: &mut W) -> io::Result<()> {
+        let keep: HashSet<usize> = ids.iter().copied().collect();
+        let mut sorted: Vec<usize> = keep.iter().copied().collect();
+        sorted.sort_unstable();
+        let entities = sorted.into_iter()
+            .filter_map(|i| self.get(i).map(|e| (i, e)));
+        self.write_entities(entities, |i| keep.contains(&i), out)
+    }
+
+    fn write_entities<'s, W, I, F>(&'s self, entities: I, keep: F, out: &mut W)
+        -> io::Result<()>
+        where W: Write, I: Iterator<Item=(usize, &'s Entity<'a>)>, F: Fn(usize) -> bool
+    {
+        writeln!(out, "ISO-10303-21;")?;
+        writeln!(out, "HEADER;")?;
+        for r in header_records(&self.header) {
+            writeln!(out, "{};", r)?;
+        }
+        writeln!(out, "ENDSEC;")?;
+
+        // Build the records for each entity, then assign them new ids
+        let references: Vec<_> = self.references.iter()
+            .filter(|r| keep(r.id))
+            .collect();
+        let anchors: Vec<_> = self.anchors.iter()
+            .filter(|a| a.id().is_none_or(&keep))
+            .collect();
+        let mut records: Vec<(Option<usize>, usize, Vec<Record>)> = entities
+            .map(|(i, e)| (self.section_of(i), i, entity_records(e)))
+            .filter(|(_, _, r)| !r.is_empty())
+            .collect();
+        records.sort_by_key(|(s, i, _)| (*s, *i));
+        let new_ids: HashMap<usize, usize> = records.iter()
+            .map(|(_, i, _)| *i)
+            .chain(references.iter().map(|r| r.id))
+            .enumerate()
+            .map(|(j, i)| (i, j + 1))
+            .collect();
+        let remap = |i: usize| new_ids.get(&i).copied();
+
+        if !anchors.is_empty() {
+            writeln!(out, "ANCHOR;")?;
+            for a in anchors {
+                match a.id() {
+                    Some(i) => match remap(i) {
+                        Some(j) => writeln!(out, "<{}>=#{};", a.name, j)?,
+                        None => writeln!(out, "<{}>=$;", a.name)?,
+                    },
+                    None => writeln!(out, "<{}>={};", a.name, a.value)?,
+                }
+            }
+            writeln!(out, "ENDSEC;")?;
+        }
+        if !references.is_empty() {
+            writeln!(out, "REFERENCE;")?;
+            for r in references {
+                writeln!(out, "#{}=<{}>;", new_ids[&r.id], r.uri)?;
+            }
+            writeln!(out, "ENDSEC;")?;
+        }
+
+        let mut section = None;
+        for (s, i, rs) in records.into_iter() {
+            if section != Some(s) {
+                if section.is_some() {
+                    writeln!(out, "ENDSEC;")?;
+                }
+                self.write_data_section(s, out)?;
+                section = Some(s);
+            }
+            write!(out, "#{}=", new_ids[&i])?;
+            let complex = rs.len() > 1;
+            if complex {
+                write!(out, "(")?;
+            }
+            for mut r in rs.into_iter() {
+                for p in r.params.iter_mut() {
+                    p.map_refs(remap);
+                }
+                write!(out, "{}", r)?;
+            }
+            if complex {
+                write!(out, ")")?;
+            }
+            writeln!(out, ";")?;
+        }
+        if section.is_none() {
+            writeln!(out, "DATA;")?;
+        }
+        writeln!(out, "ENDSEC;")?;
+        writeln!(out, "END-ISO-10303-21;")
+    }
+
+    /// Writes the opening of a DATA section, which is named if the original
+    /// section was named
+    pub(crate) fn write_data_section<W: Write>(&self, s: Option<usize>, out: &mut W)
+        -> io::Result<()>
+    {
+        match s.and_then(|s| self.sections.get(s)) {
+            Some(DataSection { name: Some(name), schemas, .. }) => {
+                let string = |s: &Cow<'a, str>| Parameter::String(s.clone());
+                writeln!(out, "DATA({},{});", string(name),
+                         Parameter::List(schemas.iter().map(string).collect()))
+            },
+            _ => writeln!(out, "DATA;"),
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse_entity_fallback;
+
+    /// Checks that every entity is written as the same records as its
+    /// source, which is read back as untyped Part 21
+    fn assert_matches_source(s: &StepFile) {
+        for (i, e) in s.entities() {
+            let source = match parse_entity_fallback(s.source(i).unwrap()) {
+                Ok((_, (_, Entity::Untyped(v)))) => v,
+                r => panic!("Could not parse #{} as Part 21: {:?}", i, r),
+            };
+            assert_eq!(entity_records(e), source, "#{}", i);
+        }
+    }
+
+    fn round_trip(data: &[u8]) {
+        let a = StepFile::parse(data);
+        assert_matches_source(&a);
+        let mut out_a = Vec::new();
+        a.write(&mut out_a).unwrap();
+
+        let b = StepFile::parse(&out_a);
+        let mut out_b = Vec::new();
+        b.write(&mut out_b).unwrap();
+
+        assert!(!b.entities().any(|(_, e)| matches!(e, Entity::_FailedToParse)));
+        assert_eq!(std::str::from_utf8(&out_a).unwrap(),
+                   std::str::from_utf8(&out_b).unwrap());
+    }
+
+    #[test]
+    fn test_round_trip_examples() {
+        round_trip(include_bytes!("../../examples/abstract_pca.step"));
+        round_trip(include_bytes!("../../examples/cube_hole.step"));
+        round_trip(include_bytes!("../../examples/cuboid.step"));
+    }
+
+    #[test]
+    fn test_complex_entity_records() {
+        let s = StepFile::parse(b"DATA;
+            #1=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));
+            #2=(GEOMETRIC_REPRESENTATION_CONTEXT(3)
+                GLOBAL_UNCERTAINTY_ASSIGNED_CONTEXT((#3))
+                GLOBAL_UNIT_ASSIGNED_CONTEXT((#1))
+                REPRESENTATION_CONTEXT('',''));
+            ENDSEC;");
+        let r: Vec<String> = entity_records(s.get(1).unwrap()).iter()
+            .map(|r| r.to_string())
+            .collect();
+        assert_eq!(r, vec!["LENGTH_UNIT()", "NAMED_UNIT(*)", "SI_UNIT(.MILLI.,.METRE.)"]);
+
+        let r: Vec<String> = entity_records(s.get(2).unwrap()).iter()
+            .map(|r| r.to_string())
+            .collect();
+        assert_eq!(r, vec![
+            "GEOMETRIC_REPRESENTATION_CONTEXT(3)",
+            "GLOBAL_UNCERTAINTY_ASSIGNED_CONTEXT((#3))",
+            "GLOBAL_UNIT_ASSIGNED_CONTEXT((#1))",
+            "REPRESENTATION_CONTEXT('','')",
+        ]);
+    }
+
+    #[test]
+    fn test_complex_units() {
+        let data = b"DATA;
+            #1=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));
+            #2=(NAMED_UNIT(*)PLANE_ANGLE_UNIT()SI_UNIT($,.RADIAN.));
+            #3=(NAMED_UNIT(*)SI_UNIT($,.STERADIAN.)SOLID_ANGLE_UNIT());
+            #4=(LENGTH_MEASURE_WITH_UNIT()MEASURE_REPRESENTATION_ITEM()
+                MEASURE_WITH_UNIT(LENGTH_MEASURE(2.5),#1)
+                REPRESENTATION_ITEM('thickness'));
+            ENDSEC;";
+        let s = StepFile::parse(data);
+        assert_matches_source(&s);
+
+        let mut out = Vec::new();
+        s.write(&mut out).unwrap();
+        let out = std::str::from_utf8(&out).unwrap();
+        assert!(out.contains("#1=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));"), "{}", out);
+        assert!(out.contains("#2=(NAMED_UNIT(*)PLANE_ANGLE_UNIT()SI_UNIT($,.RADIAN.));"), "{}", out);
+        round_trip(data);
+    }
+
+    #[test]
+    fn test_write_untyped() {
+        let s = StepFile::parse(b"DATA;
+            #10=CARTESIAN_POINT('',(0.,0.,0.));
+            #20=SUPPLIER_NOTE('see \\X\\E9',(#10,$),.Draft.,MASS_MEASURE(2.));
+            #30=(PART_A(1)PART_B(#20,*));
+            ENDSEC;");
+        assert_eq!(s.get(20).unwrap().upstream(), vec![10]);
+        assert_eq!(s.get(30).unwrap().upstream(), vec![20]);
+        assert_eq!(s.get(20).unwrap().keywords(), vec!["SUPPLIER_NOTE"]);
+
+        // Untyped entities are written back out, with references renumbered
+        let mut out = Vec::new();
+        s.write(&mut out).unwrap();
+        let out = std::str::from_utf8(&out).unwrap();
+        assert!(out.contains(
+            "#2=SUPPLIER_NOTE('see \\X2\\00E9\\X0\\',(#1,$),.Draft.,MASS_MEASURE(2.0));"), "{}", out);
+        assert!(out.contains("#3=(PART_A(1)PART_B(#2,*));"), "{}", out);
+    }
+
+    #[test]
+    fn test_write_sections() {
+        let s = StepFile::parse(b"ISO-10303-21;
+HEADER;
+ENDSEC;
+ANCHOR;
+<origin>=#20;
+<scale>=2.5;
+ENDSEC;
+REFERENCE;
+#5=<other.stp#bolt>;
+ENDSEC;
+DATA('points',('AP214'));
+#20=CARTESIAN_POINT('',(0.,0.,0.));
+ENDSEC;
+DATA;
+#3=VERTEX_POINT('',#5);
+ENDSEC;
+END-ISO-10303-21;");
+        let mut out = Vec::new();
+        s.write(&mut out).unwrap();
+        let out = std::str::from_utf8(&out).unwrap();
+        let body = &out[out.find("ANCHOR;").unwrap()..];
+        assert_eq!(body, "ANCHOR;
+<origin>=#1;
+<scale>=2.5;
+ENDSEC;
+REFERENCE;
+#3=<other.stp#bolt>;
+ENDSEC;
+DATA('points',('AP214'));
+#1=CARTESIAN_POINT('',(0.0,0.0,0.0));
+ENDSEC;
+DATA;
+#2=VERTEX_POINT('',#3);
+ENDSEC;
+END-ISO-10303-21;
+");
+        round_trip(out.as_bytes());
+    }
+}
@@ -0,0 +1,705 @@
+//! Reader for STEP-XML files (ISO 10303-28, edition 2), which converts them
+//! to Part 21 so that they load into the same [`StepFile`] as any other file.
+//!
+//! Only the default early-bound configuration is supported:
+//! ```text
+//! <iso_10303_28 xmlns="urn:iso10303-28:ex" version="2.0">
+//!   <iso_10303_28_header><name>part</name>...</iso_10303_28_header>
+//!   <uos id="uos_1">
+//!     <Cartesian_point id="i1" Name="origin"><Coordinates>0 0 0</Coordinates></Cartesian_point>
+//!     <Vertex_point id="i2"><Vertex_geometry><Cartesian_point ref="i1"/></Vertex_geometry></Vertex_point>
+//!     <Measure_representation_item id="i3">
+//!       <Value_component><Length_measure-wrapper>2.5</Length_measure-wrapper></Value_component>
+//!       ...
+//!     </Measure_representation_item>
+//!     <Length_unit-Named_unit-Si_unit id="i4">...</Length_unit-Named_unit-Si_unit>
+//!   </uos>
+//! </iso_10303_28>
+//! ```
+//! Each child of a `uos` element is an entity instance, named after its type
+//! (or after its parts, joined by `-`, for complex instances).  Attributes
+//! are either XML attributes or child elements, named after the EXPRESS
+//! attribute, and references are elements with a `ref` attribute.  Instances
+//! may also be nested inside the attribute which refers to them.
+//!
+//! Entities are decoded with the AP214 schema, so the file is given an
+//! `AUTOMOTIVE_DESIGN` schema unless the `uos` element has a `schema`
+//! attribute.  Ids are taken from the digits at the end of each XML id when
+//! these are unique, and are otherwise numbered in document order.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+
+use crate::{
+    ap214::{attribute_kinds, attribute_names, attribute_owners, type_kind},
+    owned::OwnedStepFile,
+    param::Parameter,
+    reflect::own_attribute_names,
+};
+
+/// Error from reading STEP-XML, either because the XML is malformed or
+/// because it doesn't describe valid entities
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct XmlError(pub String);
+
+impl fmt::Display for XmlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid STEP-XML: {}", self.0)
+    }
+}
+
+impl std::error::Error for XmlError {}
+
+impl From<XmlError> for io::Error {
+    fn from(e: XmlError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+fn err<T>(msg: String) -> Result<T, XmlError> {
+    Err(XmlError(msg))
+}
+
+/// Checks whether the data looks like XML (rather than Part 21 text or an
+/// index cache), i.e. whether it begins with a tag
+pub fn is_xml(data: &[u8]) -> bool {
+    let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+    data.iter().find(|c| !c.is_ascii_whitespace()) == Some(&b'<')
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Reading XML
+
+#[derive(Debug)]
+enum Node<'a> {
+    Element(Element<'a>),
+    Text(Cow<'a, str>),
+}
+
+/// An XML element.  Names are stored without their namespace prefix.
+#[derive(Debug)]
+struct Element<'a> {
+    name: &'a str,
+    attrs: Vec<(&'a str, Cow<'a, str>)>,
+    children: Vec<Node<'a>>,
+}
+
+impl<'a> Element<'a> {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|(k, _)| *k == name).map(|(_, v)| v.as_ref())
+    }
+    fn elements(&self) -> impl Iterator<Item=&Element<'a>> {
+        self.children.iter().filter_map(|c| match c {
+            Node::Element(e) => Some(e),
+            Node::Text(_) => None,
+        })
+    }
+    fn text(&self) -> Cow<'_, str> {
+        let mut texts = self.children.iter().filter_map(|c| match c {
+            Node::Text(t) => Some(t.as_ref()),
+            Node::Element(_) => None,
+        });
+        match (texts.next(), texts.next()) {
+            (None, _) => Cow::Borrowed(""),
+            (Some(t), None) => Cow::Borrowed(t),
+            (Some(a), Some(b)) => {
+                let mut out = a.to_owned() + b;
+                texts.for_each(|t| out.push_str(t));
+                Cow::Owned(out)
+            },
+        }
+    }
+    fn is_nil(&self) -> bool {
+        self.attr("nil") == Some("true")
+    }
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap()
+}
+
+struct Reader<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error<T>(&self, msg: &str) -> Result<T, XmlError> {
+        err(format!("{} at offset {}", msg, self.pos))
+    }
+    fn rest(&self) -> &'a str {
+        &self.s[self.pos..]
+    }
+    fn ws(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+    fn skip_past(&mut self, end: &str) -> Result<&'a str, XmlError> {
+        match self.rest().find(end) {
+            Some(i) => {
+                let out = &self.rest()[..i];
+                self.pos += i + end.len();
+                Ok(out)
+            },
+            None => self.error(&format!("Expected '{}'", end)),
+        }
+    }
+    fn name(&mut self) -> Result<&'a str, XmlError> {
+        let rest = self.rest();
+        let n = rest.find(|c: char| c.is_whitespace() || "/>=".contains(c))
+            .unwrap_or(rest.len());
+        if n == 0 {
+            return self.error("Expected a name");
+        }
+        self.pos += n;
+        Ok(&rest[..n])
+    }
+
+    /// Skips declarations, processing instructions, comments and whitespace
+    fn misc(&mut self) -> Result<(), XmlError> {
+        loop {
+            self.ws();
+            if self.rest().starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if self.rest().starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if self.rest().starts_with("<!DOCTYPE") {
+                // Skip the internal subset, if there is one
+                let end = self.rest().find(['[', '>']);
+                if end.map(|i| &self.rest()[i..i + 1]) == Some("[") {
+                    self.skip_past("]")?;
+                }
+                self.skip_past(">")?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn element(&mut self) -> Result<Element<'a>, XmlError> {
+        if !self.rest().starts_with('<') {
+            return self.error("Expected an element");
+        }
+        self.pos += 1;
+        let raw_name = self.name()?;
+        let mut out = Element { name: local_name(raw_name), attrs: vec![], children: vec![] };
+        loop {
+            self.ws();
+            if self.rest().starts_with("/>") {
+                self.pos += 2;
+                return Ok(out);
+            } else if self.rest().starts_with('>') {
+                self.pos += 1;
+                break;
+            }
+            let k = self.name()?;
+            self.ws();
+            if !self.rest().starts_with('=') {
+                return self.error("Expected '='");
+            }
+            self.pos += 1;
+            self.ws();
+            let quote = match self.rest().chars().next() {
+                Some(q @ ('"' | '\'')) => q,
+                _ => return self.error("Expected a quoted value"),
+            };
+            self.pos += 1;
+            let v = self.skip_past(&quote.to_string())?;
+            out.attrs.push((local_name(k), self.unescape(v)?));
+        }
+
+        loop {
+            let rest = self.rest();
+            if rest.starts_with("</") {
+                self.pos += 2;
+                if self.name()? != raw_name {
+                    return self.error(&format!("Expected </{}>", raw_name));
+                }
+                self.ws();
+                self.skip_past(">")?;
+                return Ok(out);
+            } else if rest.starts_with("<![CDATA[") {
+                self.pos += 9;
+                let t = self.skip_past("]]>")?;
+                out.children.push(Node::Text(Cow::Borrowed(t)));
+            } else if rest.starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if rest.starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if rest.starts_with('<') {
+                out.children.push(Node::Element(self.element()?));
+            } else if rest.is_empty() {
+                return self.error(&format!("Unterminated <{}>", raw_name));
+            } else {
+                let n = rest.find('<').unwrap_or(rest.len());
+                self.pos += n;
+                out.children.push(Node::Text(self.unescape(&rest[..n])?));
+            }
+        }
+    }
+
+    fn unescape(&self, s: &'a str) -> Result<Cow<'a, str>, XmlError> {
+        if !s.contains('&') {
+            return Ok(Cow::Borrowed(s));
+        }
+        let mut out = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(i) = rest.find('&') {
+            out.push_str(&rest[..i]);
+            let end = match rest[i..].find(';') {
+                Some(end) => i + end,
+                None => return self.error("Unterminated character reference"),
+            };
+            let c = match &rest[i + 1..end] {
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "amp" => Some('&'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                r => match r.strip_prefix("#x").or_else(|| r.strip_prefix("#X")) {
+                    Some(h) => u32::from_str_radix(h, 16).ok(),
+                    None => r.strip_prefix('#').and_then(|d| d.parse().ok()),
+                }.and_then(char::from_u32),
+            };
+            match c {
+                Some(c) => out.push(c),
+                None => return self.error(&format!("Unknown reference {}", &rest[i..=end])),
+            }
+            rest = &rest[end + 1..];
+        }
+        out.push_str(rest);
+        Ok(Cow::Owned(out))
+    }
+}
+
+fn parse_xml(s: &str) -> Result<Element<'_>, XmlError> {
+    let mut r = Reader { s: s.strip_prefix('\u{FEFF}').unwrap_or(s), pos: 0 };
+    r.misc()?;
+    let root = r.element()?;
+    r.misc()?;
+    if !r.rest().is_empty() {
+        return r.error("Trailing characters");
+    }
+    Ok(root)
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Converting to Part 21
+
+/// Returns the entity types of an instance element, as Part 21 keywords, or
+/// `None` if it's not an instance of (known) entities
+fn instance_types(name: &str) -> Option<Vec<String>> {
+    let out: Vec<String> = name.split('-').map(|s| s.to_ascii_uppercase()).collect();
+    if out.iter().all(|k| type_kind(k) == Some("ENTITY")) {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+/// Source of an attribute value
+enum Value<'e, 'a> {
+    Attr(&'e str),
+    Element(&'e Element<'a>),
+}
+
+struct Converter<'e> {
+    /// Map from XML ids to Part 21 ids
+    ids: HashMap<&'e str, usize>,
+    /// Next id to give to nested instances without an XML id
+    next_id: usize,
+    /// Part 21 text of each instance, by id
+    out: Vec<(usize, String)>,
+}
+
+impl<'e> Converter<'e> {
+    fn new(sections: &[&'e Element<'_>]) -> Self {
+        fn collect<'e>(e: &'e Element<'_>, out: &mut Vec<&'e str>) {
+            if let (Some(id), Some(_)) = (e.attr("id"), instance_types(e.name)) {
+                out.push(id);
+            }
+            for c in e.elements() {
+                collect(c, out);
+            }
+        }
+        let mut xml_ids = vec![];
+        for s in sections {
+            for c in s.elements() {
+                collect(c, &mut xml_ids);
+            }
+        }
+
+        // Prefer the numbers in the XML ids, which usually match the Part 21
+        // file which they were exported from
+        let numbers: Option<Vec<usize>> = xml_ids.iter()
+            .map(|id| {
+                let digits = id.trim_start_matches(|c: char| !c.is_ascii_digit());
+                digits.parse().ok().filter(|i| *i > 0)
+            })
+            .collect();
+        let numbers = numbers
+            .filter(|v| v.iter().collect::<HashSet<_>>().len() == v.len())
+            .unwrap_or_else(|| (1..=xml_ids.len()).collect());
+        let next_id = numbers.iter().max().map_or(1, |i| i + 1);
+        Self {
+            ids: xml_ids.into_iter().zip(numbers).collect(),
+            next_id,
+            out: vec![],
+        }
+    }
+
+    fn reference(&self, id: &str) -> Result<String, XmlError> {
+        match self.ids.get(id.trim_start_matches('#')) {
+            Some(i) => Ok(format!("#{}", i)),
+            None => err(format!("Unknown reference '{}'", id)),
+        }
+    }
+
+    /// Converts an instance element, returning its id
+    fn instance(&mut self, e: &'e Element<'_>) -> Result<usize, XmlError> {
+        let mut types = match instance_types(e.name) {
+            Some(t) => t,
+            None => return err(format!("Unknown entity type <{}>", e.name)),
+        };
+        let id = match e.attr("id") {
+            Some(i) => self.ids[i],
+            None => {
+                self.next_id += 1;
+                self.next_id - 1
+            },
+        };
+        let text = if types.len() == 1 {
+            let kw = &types[0];
+            self.record(e, kw, attribute_names(kw), &HashSet::new())?
+        } else {
+            // Attributes which any part derives are derived in every part
+            types.sort();
+            let derived: HashSet<(&str, &str)> = types.iter().flat_map(|kw| {
+                let owners = attribute_owners(kw);
+                attribute_names(kw).iter().zip(attribute_kinds(kw)).enumerate()
+                    .filter(|(_, (_, k))| **k == "DERIVED")
+                    .map(move |(i, (n, _))| (owners.get(i).copied().unwrap_or(kw), *n))
+            }).collect();
+            let mut out = "(".to_owned();
+            for kw in &types {
+                out += &self.record(e, kw, &own_attribute_names(kw), &derived)?;
+            }
+            out + ")"
+        };
+        self.out.push((id, text));
+        Ok(id)
+    }
+
+    /// Builds a simple record (or one part of a complex record) from the
+    /// named attributes of `keyword`
+    fn record(&mut self, e: &'e Element<'_>, keyword: &str, names: &[&str],
+              derived: &HashSet<(&str, &str)>) -> Result<String, XmlError>
+    {
+        let all_names = attribute_names(keyword);
+        let kinds = attribute_kinds(keyword);
+        let mut params = vec![];
+        for name in names {
+            let kind = all_names.iter().position(|n| n == name).map_or("", |i| kinds[i]);
+            if kind == "DERIVED" || derived.contains(&(keyword, *name)) {
+                params.push("*".to_owned());
+                continue;
+            }
+            // Attributes inherited from several supertypes are generated as
+            // `owner__name`, but written in XML as just `name`
+            let short = name.rsplit("__").next().unwrap();
+            let matches = |n: &str| n.eq_ignore_ascii_case(name) || n.eq_ignore_ascii_case(short);
+            let value = e.attrs.iter()
+                .find(|(k, _)| matches(k))
+                .map(|(_, v)| Value::Attr(v))
+                .or_else(|| e.elements().find(|c| matches(c.name)).map(Value::Element));
+            params.push(match value {
+                None => "$".to_owned(),
+                Some(Value::Attr(v)) => self.scalar(v, kind)?,
+                Some(Value::Element(c)) => self.contained(c, kind)?,
+            });
+        }
+        Ok(format!("{}({})", keyword, params.join(",")))
+    }
+
+    /// Converts the value inside an attribute (or list) element
+    fn contained(&mut self, e: &'e Element<'_>, kind: &str) -> Result<String, XmlError> {
+        if let Some(inner) = kind.strip_prefix("LIST OF ") {
+            let mut items = vec![];
+            if e.elements().next().is_some() {
+                for c in e.elements() {
+                    items.push(self.item(c, inner)?);
+                }
+            } else {
+                for t in e.text().split_whitespace() {
+                    items.push(self.scalar(t, inner)?);
+                }
+            }
+            Ok(format!("({})", items.join(",")))
+        } else if kind == "ENTITY" || kind == "SELECT" {
+            if let Some(r) = e.attr("ref") {
+                self.reference(r)
+            } else if let Some(c) = e.elements().next() {
+                self.item(c, kind)
+            } else if e.is_nil() {
+                Ok("$".to_owned())
+            } else {
+                err(format!("Expected a value in <{}>", e.name))
+            }
+        } else if e.is_nil() {
+            Ok("$".to_owned())
+        } else {
+            self.scalar(&e.text(), kind)
+        }
+    }
+
+    /// Converts an element which is itself a value: a reference, a nested
+    /// instance, a typed value or a wrapped list item
+    fn item(&mut self, e: &'e Element<'_>, kind: &str) -> Result<String, XmlError> {
+        if let Some(r) = e.attr("ref").or_else(|| e.attr("href")) {
+            return self.reference(r);
+        }
+        if kind.starts_with("LIST OF ") {
+            return self.contained(e, kind);
+        }
+        if kind == "ENTITY" || kind == "SELECT" {
+            if instance_types(e.name).is_some() {
+                return Ok(format!("#{}", self.instance(e)?));
+            } else if kind == "ENTITY" {
+                return err(format!("Expected an entity instance, found <{}>", e.name));
+            }
+            let name = e.name.strip_suffix("-wrapper").unwrap_or(e.name);
+            let keyword = name.to_ascii_uppercase();
+            return match type_kind(&keyword) {
+                Some(k) => Ok(format!("{}({})", keyword, self.contained(e, k)?)),
+                None => err(format!("Unknown type <{}>", e.name)),
+            };
+        }
+        if e.is_nil() {
+            Ok("$".to_owned())
+        } else {
+            self.scalar(&e.text(), kind)
+        }
+    }
+
+    /// Converts a value written as text
+    fn scalar(&self, s: &str, kind: &str) -> Result<String, XmlError> {
+        let t = s.trim();
+        let bad = || err(format!("Invalid {} '{}'", kind.to_ascii_lowercase(), t));
+        Ok(match kind {
+            "STRING" => Parameter::String(Cow::Borrowed(s)).to_string(),
+            "REAL" => match t.parse::<f64>() {
+                Ok(f) if f.is_finite() => Parameter::Real(f).to_string(),
+                _ => return bad(),
+            },
+            "INTEGER" => match t.parse::<i64>() {
+                Ok(i) => i.to_string(),
+                Err(_) => return bad(),
+            },
+            "BOOLEAN" | "LOGICAL" => match t.to_ascii_lowercase().as_str() {
+                "true" | "t" => ".T.".to_owned(),
+                "false" | "f" => ".F.".to_owned(),
+                "unknown" | "u" if kind == "LOGICAL" => ".UNKNOWN.".to_owned(),
+                _ => return bad(),
+            },
+            "ENUMERATION" => if !t.is_empty() && t.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                format!(".{}.", t.to_ascii_uppercase())
+            } else {
+                return bad();
+            },
+            // References may be written as IDREF attributes
+            "ENTITY" => self.reference(t)?,
+            k => match k.strip_prefix("LIST OF ") {
+                Some(inner) => {
+                    let items = t.split_whitespace()
+                        .map(|i| self.scalar(i, inner))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    format!("({})", items.join(","))
+                },
+                None => return err(format!("Cannot read {} values from text", k.to_ascii_lowercase())),
+            },
+        })
+    }
+}
+
+/// Builds the Part 21 header from an `iso_10303_28_header` element
+fn header(h: Option<&Element<'_>>, schema: &str) -> String {
+    let field = |name: &str| -> Vec<String> {
+        h.into_iter()
+            .flat_map(|h| h.elements())
+            .filter(|e| e.name == name)
+            .map(|e| e.text().into_owned())
+            .collect()
+    };
+    let string = |s: String| Parameter::String(Cow::Owned(s));
+    let single = |name: &str| string(field(name).into_iter().next().unwrap_or_default());
+    let list = |name: &str| {
+        let v = field(name);
+        Parameter::List(if v.is_empty() { vec![string(String::new())] } else { v.into_iter().map(string).collect() })
+    };
+    format!("FILE_DESCRIPTION({},'2;1');\nFILE_NAME({},{},{},{},{},{},{});\nFILE_SCHEMA(({}));\n",
+            list("documentation"), single("name"), single("time_stamp"),
+            list("author"), list("organization"), single("preprocessor_version"),
+            single("originating_system"), single("authorization"),
+            string(schema.to_owned()))
+}
+
+/// Converts a STEP-XML file to Part 21 text
+pub fn xml_to_step(data: &[u8]) -> Result<Vec<u8>, XmlError> {
+    let text = std::str::from_utf8(data)
+        .or_else(|_| err("Only UTF-8 files are supported".to_owned()))?;
+    let root = parse_xml(text)?;
+    if !root.name.eq_ignore_ascii_case("iso_10303_28") {
+        return err(format!("Expected <iso_10303_28>, found <{}>", root.name));
+    }
+    let sections: Vec<&Element> = root.elements().filter(|e| e.name == "uos").collect();
+    if sections.is_empty() {
+        return err("No <uos> element".to_owned());
+    }
+    let schema = sections.iter().find_map(|s| s.attr("schema")).unwrap_or("AUTOMOTIVE_DESIGN");
+
+    let mut out = String::new();
+    out += "ISO-10303-21;\nHEADER;\n";
+    out += &header(root.elements().find(|e| e.name == "iso_10303_28_header"), schema);
+    out += "ENDSEC;\n";
+
+    let mut conv = Converter::new(&sections);
+    for s in sections {
+        for e in s.elements() {
+            conv.instance(e)?;
+        }
+        out += "DATA;\n";
+        for (id, text) in conv.out.drain(..) {
+            out += &format!("#{}={};\n", id, text);
+        }
+        out += "ENDSEC;\n";
+    }
+    out += "END-ISO-10303-21;\n";
+    Ok(out.into_bytes())
+}
+
+impl OwnedStepFile {
+    /// Loads a STEP-XML file, taking ownership of the data.  The file is
+    /// converted to Part 21, which [`data`](Self::data) then returns.
+    pub fn from_xml(data: &[u8]) -> Result<Self, XmlError> {
+        Ok(Self::parse(xml_to_step(data)?))
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ap214::Entity;
+
+    const XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!-- exported for testing -->
+<iso_10303_28 xmlns="urn:iso10303-28:ex" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" version="2.0">
+  <iso_10303_28_header>
+    <name>cube &amp; hole</name>
+    <time_stamp>2021-05-12T21:19:39</time_stamp>
+    <author>Ann</author><author>Bo</author>
+    <originating_system>CAD</originating_system>
+  </iso_10303_28_header>
+  <uos id="uos_1">
+    <Cartesian_point id="i10" Name="origin"><Coordinates>0 1.5 -2e-3</Coordinates></Cartesian_point>
+    <Direction id="i11" Name="">
+      <Direction_ratios><ex:double-wrapper>0</ex:double-wrapper><ex:double-wrapper>0</ex:double-wrapper><ex:double-wrapper>1</ex:double-wrapper></Direction_ratios>
+    </Direction>
+    <Axis2_placement_3d id="i12" Name="">
+      <Location><Cartesian_point ref="i10" xsi:nil="true"/></Location>
+      <Axis><Direction ref="i11" xsi:nil="true"/></Axis>
+      <Ref_direction xsi:nil="true"/>
+    </Axis2_placement_3d>
+    <Oriented_edge id="i13" Name="it's"><Edge_element><Edge_curve ref="i14"/></Edge_element><Orientation>false</Orientation></Oriented_edge>
+    <Edge_curve id="i14" Name="" Edge_start="i16" Edge_end="i16" Same_sense="true">
+      <Edge_geometry>
+        <Line Name="">
+          <Pnt><Cartesian_point ref="i10"/></Pnt>
+          <Dir><Vector id="i15" Name="" Magnitude="2"><Orientation><Direction ref="i11"/></Orientation></Vector></Dir>
+        </Line>
+      </Edge_geometry>
+    </Edge_curve>
+    <Vertex_point id="i16" Name=""><Vertex_geometry><Cartesian_point ref="i10"/></Vertex_geometry></Vertex_point>
+    <Length_unit-Named_unit-Si_unit id="i17"><Prefix>milli</Prefix><Name>metre</Name></Length_unit-Named_unit-Si_unit>
+    <Measure_representation_item id="i18" Name="d">
+      <Value_component><Length_measure-wrapper>2.5</Length_measure-wrapper></Value_component>
+      <Unit_component><Length_unit-Named_unit-Si_unit ref="i17"/></Unit_component>
+    </Measure_representation_item>
+  </uos>
+</iso_10303_28>
+"#;
+
+    #[test]
+    fn test_parse_xml() {
+        let e = parse_xml(r#"<a:b x='1' y="&lt;&#65;&#x42;"><c/>t<![CDATA[<u>]]><!-- no --></a:b>"#).unwrap();
+        assert_eq!(e.name, "b");
+        assert_eq!(e.attr("x"), Some("1"));
+        assert_eq!(e.attr("y"), Some("<AB"));
+        assert_eq!(e.elements().count(), 1);
+        assert_eq!(e.text(), "t<u>");
+        assert!(parse_xml("<a><b></a>").is_err());
+        assert!(parse_xml("<a>").is_err());
+        assert!(parse_xml("<a/><b/>").is_err());
+        assert!(is_xml(b"\xEF\xBB\xBF <?xml?>"));
+        assert!(!is_xml(b"ISO-10303-21;"));
+    }
+
+    #[test]
+    fn test_xml_to_step() {
+        let text = String::from_utf8(xml_to_step(XML.as_bytes()).unwrap()).unwrap();
+        assert_eq!(text, "ISO-10303-21;
+HEADER;
+FILE_DESCRIPTION((''),'2;1');
+FILE_NAME('cube & hole','2021-05-12T21:19:39',('Ann','Bo'),(''),'','CAD','');
+FILE_SCHEMA(('AUTOMOTIVE_DESIGN'));
+ENDSEC;
+DATA;
+#10=CARTESIAN_POINT('origin',(0.0,1.5,-0.002));
+#11=DIRECTION('',(0.0,0.0,1.0));
+#12=AXIS2_PLACEMENT_3D('',#10,#11,$);
+#13=ORIENTED_EDGE('it''s',*,*,#14,.F.);
+#15=VECTOR('',#11,2.0);
+#19=LINE('',#10,#15);
+#14=EDGE_CURVE('',#16,#16,#19,.T.);
+#16=VERTEX_POINT('',#10);
+#17=(LENGTH_UNIT()NAMED_UNIT(*)SI_UNIT(.MILLI.,.METRE.));
+#18=MEASURE_REPRESENTATION_ITEM('d',LENGTH_MEASURE(2.5),#17);
+ENDSEC;
+END-ISO-10303-21;
+");
+        let s = OwnedStepFile::from_xml(XML.as_bytes()).unwrap();
+        let s = s.file();
+        assert_eq!(s.len(), 10);
+        assert!(s.entities().all(|(_, e)| !matches!(e, Entity::_FailedToParse)));
+        assert!(matches!(s.get(17), Some(Entity::ComplexEntity(v))
+            if matches!(v[..], [Entity::LengthUnit(_), Entity::SiUnit(_)])));
+        assert_eq!(s.header.name.as_ref().unwrap().author.len(), 2);
+
+        // The usual loader recognizes STEP-XML
+        let s = OwnedStepFile::load_lazy(XML.as_bytes().to_vec()).unwrap();
+        assert!(matches!(s.file().get(18), Some(Entity::MeasureRepresentationItem(_))));
+    }
+
+    #[test]
+    fn test_xml_ids() {
+        // Ids without unique numbers are renumbered in document order
+        let xml = r#"<iso_10303_28><uos>
+            <Cartesian_point id="a" Name=""><Coordinates>0 0 0</Coordinates></Cartesian_point>
+            <Vertex_point id="b" Name="" Vertex_geometry="a"/>
+        </uos></iso_10303_28>"#;
+        let s = OwnedStepFile::from_xml(xml.as_bytes()).unwrap();
+        assert_eq!(s.file().get(2).unwrap().upstream(), vec![1]);
+
+        let bad = |x: &str| xml_to_step(format!("<iso_10303_28><uos>{}</uos></iso_10303_28>", x)
+                                        .as_bytes()).unwrap_err().0;
+        assert_eq!(bad(r#"<Bogus id="i1"/>"#), "Unknown entity type <Bogus>");
+        assert_eq!(bad(r#"<Vertex_point id="i1" Vertex_geometry="i2"/>"#),
+                   "Unknown reference 'i2'");
+        assert_eq!(bad(r#"<Direction id="i1"><Direction_ratios>x</Direction_ratios></Direction>"#),
+                   "Invalid real 'x'");
+        assert!(xml_to_step(b"<step/>").is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use clap::{Arg, App};
 
 use triangulate::triangulate::triangulate;
-use step::step_file::StepFile;
+use step::owned::OwnedStepFile;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
@@ -24,15 +24,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let start = std::time::SystemTime::now();
     let data = std::fs::read(input)?;
-    let flat = StepFile::strip_flatten(&data);
-    let entities = StepFile::parse(&flat);
+    let entities = OwnedStepFile::load_lazy(data)?;
     let end = std::time::SystemTime::now();
     let since_the_epoch = end.duration_since(start)
         .expect("Time went backwards");
     println!("Loaded + parsed in {:?}", since_the_epoch);
 
     let start = std::time::SystemTime::now();
-    let tri = triangulate(&entities);
+    let tri = triangulate(entities.file());
     let end = std::time::SystemTime::now();
     let since_the_epoch = end.duration_since(start)
         .expect("Time went backwards");
@@ -9,7 +9,7 @@ use log::{info, warn, error};
 use rayon::prelude::*;
 
 use step::{
-    ap214, ap214::*, step_file::{FromEntity, StepFile}, id::Id, ap214::Entity,
+    ap214, ap214::*, step_file::StepFile, id::Id, ap214::Entity,
 };
 use crate::{
     Error,
@@ -29,10 +29,7 @@ type TransformStack<'a> =
 fn build_transform_stack<'a>(s: &'a StepFile, flip: bool) -> TransformStack<'a> {
     // Store a map of parent -> (child, transform)
     let mut transform_stack: HashMap<_, Vec<_>> = HashMap::new();
-    for r in s.0.iter()
-        .filter_map(|e|
-            RepresentationRelationshipWithTransformation_::try_from_entity(e))
-    {
+    for (_, r) in s.iter::<RepresentationRelationshipWithTransformation_>() {
         let (a, b) = if flip {
             (r.rep_2, r.rep_1)
         } else {
@@ -64,9 +61,8 @@ fn transform_stack_roots<'a>(transform_stack: &TransformStack<'a>) -> Vec<Repres
 }
 
 pub fn triangulate(s: &StepFile) -> (Mesh, Stats) {
-    let styled_items: Vec<_> = s.0.iter()
-        .filter_map(|e| MechanicalDesignGeometricPresentationRepresentation_::try_from_entity(e))
-        .flat_map(|m| m.items.iter())
+    let styled_items: Vec<_> = s.iter::<MechanicalDesignGeometricPresentationRepresentation_>()
+        .flat_map(|(_, m)| m.items.iter())
         .filter_map(|item| s.entity(item.cast::<StyledItem_>()))
         .collect();
     let brep_colors: HashMap<_, DVec3> = styled_items.iter()
@@ -99,11 +95,15 @@ pub fn triangulate(s: &StepFile) -> (Mesh, Stats) {
     }
 
     // Store a map of ShapeRepresentationRelationships, which some models
-    // use to map from axes to specific instances
+    // use to map from axes to specific instances.  Complex instances which
+    // also carry a transformation are already in the transform stack (in
+    // whichever direction it was built), so following them here as well
+    // would loop forever.
     let mut shape_rep_relationship: HashMap<Id<_>, Vec<Id<_>>> = HashMap::new();
-    for (r1, r2) in s.0.iter()
-        .filter_map(|e| ShapeRepresentationRelationship_::try_from_entity(e))
-        .map(|e| (e.rep_1, e.rep_2))
+    for (r1, r2) in s.iter::<ShapeRepresentationRelationship_>()
+        .filter(|(i, _)|
+            s.entity(i.cast::<RepresentationRelationshipWithTransformation_>()).is_none())
+        .filter_map(|(_, e)| Some((e.rep_1, e.rep_2?)))
     {
         shape_rep_relationship.entry(r1).or_default().push(r2);
     }
@@ -142,8 +142,9 @@ pub fn triangulate(s: &StepFile) -> (Mesh, Stats) {
     // If there are items in breps that aren't attached to a transformation
     // chain, then draw them individually (with an identity matrix)
     if to_mesh.is_empty() {
-        s.0.iter()
-            .enumerate()
+        ["MANIFOLD_SOLID_BREP", "BREP_WITH_VOIDS", "SHELL_BASED_SURFACE_MODEL"]
+            .iter()
+            .flat_map(|k| s.entities_with_keyword(k))
             .filter(|(_i, e)|
                 match e {
                     Entity::ManifoldSolidBrep(_)
@@ -153,7 +154,10 @@ pub fn triangulate(s: &StepFile) -> (Mesh, Stats) {
                 }
             )
             .map(|(i, _e)| Id::new(i))
-            .for_each(|i| to_mesh.entry(i).or_default().push(DMat4::identity()));
+            // A complex instance may match more than one keyword
+            .for_each(|i| {
+                to_mesh.entry(i).or_insert_with(|| vec![DMat4::identity()]);
+            });
     }
 
     let (to_mesh_iter, empty) = {
@@ -779,3 +783,27 @@ fn vertex_point(s: &StepFile, v: Vertex) -> DVec3 {
             .vertex_geometry
             .cast())
 }
+
+////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_triangulate_examples() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/../examples");
+        let mut paths: Vec<_> = std::fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|e| e == "step"))
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty());
+        for p in paths {
+            let data = std::fs::read(&p).unwrap();
+            let step = StepFile::parse(&data);
+            let (mesh, _stats) = triangulate(&step);
+            assert!(!mesh.triangles.is_empty(), "No triangles in {:?}", p);
+        }
+    }
+}
@@ -1,5 +1,5 @@
 // Inject the file loading button at runtime
-document.getElementById("load").innerHTML = '<input type="file" file=null id="file-selector" accept=".step,.stp">';
+document.getElementById("load").innerHTML = '<input type="file" file=null id="file-selector" accept=".step,.stp,.stpz,.gz,.zip">';
 import * as SCENE from './scene.js';
 
 const worker = new Worker("worker.js");
@@ -15,6 +15,13 @@ SCENE.addCameraCallback(function () {
 });
 
 worker.onmessage = function(e) {
+    // Errors are sent back as strings, and meshes as Float32Arrays
+    if (typeof e.data === "string") {
+        setStatus(e.data);
+        fileSelector.disabled = false;
+        exampleSelector.disabled = false;
+        return;
+    }
     setStatus("Building scene...");
     SCENE.loadMesh(e.data);
     fileSelector.disabled = false;
@@ -35,11 +42,11 @@ worker.onmessage = function(e) {
         targetAxis = null;
     }
 }
-const loadMeshFromString = function(s) {
+const loadMeshFromBuffer = function(buf) {
     const d = new Date();
     startTime = d.getTime();
     setStatus("Parsing & triangulating...");
-    worker.postMessage(s);
+    worker.postMessage(new Uint8Array(buf));
 }
 
 const fileSelector = document.getElementById('file-selector');
@@ -57,10 +64,10 @@ fileSelector.addEventListener('change', (event) => {
     const file = event.target.files[0];
     const reader = new FileReader();
     reader.addEventListener('load', (event) => {
-        loadMeshFromString(event.target.result);
+        loadMeshFromBuffer(event.target.result);
     });
     setStatus("Uploading...");
-    reader.readAsText(file);
+    reader.readAsArrayBuffer(file);
 });
 
 setStatus("");
@@ -115,8 +122,8 @@ exampleSelector.onchange = function(e) {
         }
 
         fetch(ex[1])
-            .then(response => response.text())
-            .then(text => loadMeshFromString(text))
+            .then(response => response.arrayBuffer())
+            .then(buf => loadMeshFromBuffer(buf))
     }
 }
 fetch('examples.json')
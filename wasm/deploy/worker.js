@@ -6,8 +6,12 @@ async function run() {
     init_log();
 
     onmessage = function(e) {
-        var triangles = step_to_triangle_buf(e.data);
-        postMessage(triangles);
+        try {
+            var triangles = step_to_triangle_buf(e.data);
+            postMessage(triangles);
+        } catch (err) {
+            postMessage(String(err));
+        }
     }
 }
 run();
@@ -15,14 +15,16 @@ pub fn init_log() {
     console_log::init_with_level(Level::Info).expect("Failed to initialize log");
 }
 
+/// Files which can't be loaded (e.g. corrupt compressed data) are reported
+/// to JavaScript as an exception, rather than panicking.
 #[wasm_bindgen]
-pub fn step_to_triangle_buf(data: String) -> Vec<f32> {
-    use step::step_file::StepFile;
+pub fn step_to_triangle_buf(data: &[u8]) -> Result<Vec<f32>, JsValue> {
+    use step::owned::OwnedStepFile;
     use triangulate::triangulate::triangulate; // lol
 
-    let flat = StepFile::strip_flatten(data.as_bytes());
-    let step = StepFile::parse(&flat);
-    let (mut mesh, _stats) = triangulate(&step);
+    let step = OwnedStepFile::load_lazy(data.to_vec())
+        .map_err(|e| JsValue::from_str(&format!("Could not load file: {}", e)))?;
+    let (mut mesh, _stats) = triangulate(step.file());
 
     let (mut xmin, mut xmax) = (std::f64::INFINITY, -std::f64::INFINITY);
     let (mut ymin, mut ymax) = (std::f64::INFINITY, -std::f64::INFINITY);
@@ -45,10 +47,10 @@ pub fn step_to_triangle_buf(data: String) -> Vec<f32> {
         pos.z = (pos.z - zc) / scale * 200.0;
     }
 
-    mesh.triangles.iter()
+    Ok(mesh.triangles.iter()
         .flat_map(|v| v.verts.iter())
         .map(|p| &mesh.verts[*p as usize])
         .flat_map(|v| v.pos.iter().chain(&v.norm).chain(&v.color))
         .map(|f| *f as f32)
-        .collect()
+        .collect())
 }